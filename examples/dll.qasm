@@ -1,3 +1,5 @@
+.extern add(i32, i32) -> i32
+
 main:
   PUSH_STR "/home/sushi/projects/proton/libtest.so"
   DLL_LOAD
@@ -7,8 +9,7 @@ main:
   PUSH 1
   PUSH_STR "add"
   LOAD dllHandler
-  DLL_CALL 2
-  DEREF
+  DLL_CALL add
   DEBUG
   PRINT
 
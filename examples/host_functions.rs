@@ -0,0 +1,45 @@
+//! Embeds QuarkVM in a Rust program and exposes two host functions to QASM.
+//!
+//! The program below is the bytecode for:
+//!
+//! ```qasm
+//!   PUSH 2
+//!   PUSH 40
+//!   HOST_CALL "add"
+//!   PRINT
+//!   PUSH_STR "proton"
+//!   HOST_CALL "greet"
+//!   PRINT
+//! ```
+//!
+//! Run with `cargo run --example host_functions`.
+
+use proton::lib::bytecode::Program;
+use proton::lib::machine_type::{
+    DEFINE_HOST_CALL, DEFINE_PRINT, DEFINE_PUSH, DEFINE_PUSH_STR, QuarkVM,
+};
+
+fn main() {
+    let mut vm = QuarkVM::default();
+    vm.register_host_function("add", |a: u16, b: u16| a.wrapping_add(b));
+    vm.register_host_function("greet", |name: String| {
+        println!("hello, {}!", name);
+        name.len() as u16
+    });
+
+    vm.load_program(Program::new(vec![
+        DEFINE_PUSH(2),
+        DEFINE_PUSH(40),
+        DEFINE_HOST_CALL("add"),
+        DEFINE_PRINT(),
+        DEFINE_PUSH_STR("proton"),
+        DEFINE_HOST_CALL("greet"),
+        DEFINE_PRINT(),
+    ]))
+    .expect("the program has no data items");
+
+    if let Err(fault) = vm.run() {
+        eprintln!("{}", fault);
+        std::process::exit(1);
+    }
+}
@@ -1,44 +1,205 @@
-use crate::lexer::lexer::Lexer;
-use crate::parser::parser::Parser;
+use crate::diagnostics::Diagnostic;
+use crate::lexer::lexer::{Lexer, Token, TokenType};
+use crate::parser::parser::{ASTNode, Parser, Spanned};
 use crate::compiler;
-use proton::lib::bytecode::ByteCodeCompiler;
+use crate::macros::{self, MacroExpander};
+use crate::optimizer;
+use proton::lib::bytecode::{ByteCodeCompiler, BytecodeError, DebugInfo, Program};
+use proton::lib::syscalls::Arch;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs;
 use std::io::{self};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum AssemblerError {
+    /// Problems in the source, each pointing at where it was found.
+    Diagnostics(Vec<Diagnostic>),
+    Write(BytecodeError),
+}
+
+#[derive(Debug)]
+pub enum IncludeError {
+    MissingPath,
+    Unreadable { path: String, error: io::Error },
+    /// The chain of files that leads back to one already being included.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPath => write!(f, "expected a file name in quotes after `.include`"),
+            Self::Unreadable { path, error } => write!(f, "cannot include `{}`: {}", path, error),
+            Self::Cycle(chain) => write!(f, "include cycle: {}", chain.join(" -> ")),
+        }
+    }
+}
+
+/// A file taking part in the program: the one given on the command line or one it includes.
+pub struct SourceFile {
+    /// The path as the user would write it, used in messages.
+    pub name: String,
+    pub path: PathBuf,
+    pub code: String,
+}
 
 pub struct Assembler<'a> {
     output_name: &'a str,
-    lexer: Lexer<'a>,
-    parser: Parser<'a>
+    /// Every file read so far. A token's `Span::file` indexes into this list.
+    sources: Vec<SourceFile>,
+    /// Canonical paths of the files already included, so each is only assembled once.
+    included: HashSet<PathBuf>,
+    parser: Parser,
+    /// Run the peephole optimizer over the compiled instructions (`-O`).
+    pub optimize: bool,
+    /// Whose syscall numbers `SYSCALL name` resolves to (`--target`).
+    pub target: Arch
 }
 
 impl<'a> Assembler<'a> {
-    pub fn new(src: &str, output_name: &'a str) -> io::Result<Self> {
+    pub fn new(src: &'a str, output_name: &'a str) -> io::Result<Self> {
         let source_code = fs::read_to_string(src)?;
-
-        let source_code_ref: &'static str = Box::leak(source_code.clone().into_boxed_str());
+        let path = fs::canonicalize(src)?;
 
         Ok(Self {
             output_name,
-            lexer: Lexer::new(source_code_ref),
-            parser: Parser::new(source_code_ref)
+            sources: vec![SourceFile { name: src.to_string(), path, code: source_code }],
+            included: HashSet::new(),
+            parser: Parser::new(),
+            optimize: false,
+            target: Arch::host().unwrap_or(Arch::X86_64)
         })
     }
 
-    pub fn compile(&mut self) {
-        let _size = self.lexer.lex();
-        let parsed = self.parser.parse(self.lexer.tokens.clone());
-        if let Ok(parse_result) = parsed  {
-            let mut compiled = compiler::Compiler::new(parse_result);
-            let compiled_instructions = compiled.compile();
-            if let Ok(compiled_instructions) = compiled_instructions {
-                println!("COMPILED: {:?}", compiled_instructions);
-                let mut b = ByteCodeCompiler::new(self.output_name);
-                b.store_file(&compiled_instructions[0..]);
-            } else {
-                panic!("Error occured while compiling: {:?}", compiled_instructions);
+    /// Formats a diagnostic against the source file it points into.
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let source = &self.sources[diagnostic.span.file];
+        diagnostic.render(&source.name, &source.code)
+    }
+
+    /// Lexes the source with index `file` and, in place of every `.include "path"`, the
+    /// tokens of the included file. `stack` holds the files currently being expanded.
+    fn lex_file(&mut self, file: usize, stack: &mut Vec<usize>, diagnostics: &mut Vec<Diagnostic>) -> Vec<Token> {
+        self.included.insert(self.sources[file].path.clone());
+        stack.push(file);
+        let mut lexer = Lexer::new(&self.sources[file].code, file);
+        let tokens = match lexer.lex() {
+            Ok(_) => std::mem::take(&mut lexer.tokens),
+            Err(errors) => {
+                diagnostics.extend(errors);
+                Vec::new()
+            }
+        };
+
+        let mut expanded = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            if !matches!(&token.tt, TokenType::Directive(name) if name == "include") {
+                expanded.push(token);
+                continue;
+            }
+            let (path, span) = match tokens.next_if(|next| next.span.same_line(&token.span)) {
+                Some(Token { tt: TokenType::String(path), span }) => (path, span),
+                Some(other) => {
+                    diagnostics.push(Diagnostic::new(other.span, IncludeError::MissingPath));
+                    continue;
+                }
+                None => {
+                    diagnostics.push(Diagnostic::new(token.span, IncludeError::MissingPath));
+                    continue;
+                }
+            };
+            match self.open_include(file, &path, stack) {
+                Ok(Some(included)) => expanded.extend(self.lex_file(included, stack, diagnostics)),
+                Ok(None) => {}
+                Err(error) => diagnostics.push(Diagnostic::new(span, error)),
             }
-        } else {
-            panic!("Error Occured while Parsing: {:?}", parsed);
-        } 
+        }
+        stack.pop();
+        expanded
+    }
+
+    /// Resolves `path` relative to the file that includes it and reads it. Returns `None`
+    /// if the file was already included.
+    fn open_include(&mut self, from: usize, path: &str, stack: &[usize]) -> Result<Option<usize>, IncludeError> {
+        let unreadable = |error| IncludeError::Unreadable { path: path.to_string(), error };
+        let base = self.sources[from].path.parent().unwrap_or(Path::new("."));
+        let canonical = fs::canonicalize(base.join(path)).map_err(unreadable)?;
+
+        if let Some(position) = stack.iter().position(|&file| self.sources[file].path == canonical) {
+            let mut chain: Vec<String> = stack[position..].iter().map(|&file| self.sources[file].name.clone()).collect();
+            chain.push(self.sources[stack[position]].name.clone());
+            return Err(IncludeError::Cycle(chain));
+        }
+        if self.included.contains(&canonical) {
+            return Ok(None);
+        }
+
+        let code = fs::read_to_string(&canonical).map_err(unreadable)?;
+        let name = Path::new(&self.sources[from].name)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(path)
+            .display()
+            .to_string();
+        self.sources.push(SourceFile { name, path: canonical, code });
+        Ok(Some(self.sources.len() - 1))
+    }
+
+    /// Reads, parses and macro-expands the program, ready to be compiled.
+    pub fn expand(&mut self) -> Result<Vec<Spanned<ASTNode>>, AssemblerError> {
+        let mut diagnostics = Vec::new();
+        let tokens = self.lex_file(0, &mut Vec::new(), &mut diagnostics);
+        if !diagnostics.is_empty() {
+            return Err(AssemblerError::Diagnostics(diagnostics));
+        }
+        let parse_result = self
+            .parser
+            .parse(tokens)
+            .map_err(AssemblerError::Diagnostics)?;
+        MacroExpander::new()
+            .expand(parse_result)
+            .map_err(AssemblerError::Diagnostics)
+    }
+
+    /// The program as QASM after includes and macros have been expanded.
+    pub fn expanded_source(&mut self) -> Result<String, AssemblerError> {
+        Ok(macros::to_source(&self.expand()?))
+    }
+
+    /// Assembles the source and writes the bytecode file. Each stage reports all of its
+    /// errors at once; later stages only run if the earlier ones succeeded.
+    pub fn compile(&mut self) -> Result<(), AssemblerError> {
+        self.assemble(false)
+    }
+
+    /// Assembles the source into a relocatable object file for `linker`.
+    pub fn compile_object(&mut self) -> Result<(), AssemblerError> {
+        self.assemble(true)
+    }
+
+    fn assemble(&mut self, object: bool) -> Result<(), AssemblerError> {
+        let parse_result = self.expand()?;
+        let mut compiled = compiler::Compiler::new(parse_result);
+        compiled.object = object;
+        compiled.target = self.target;
+        let compiled_instructions = compiled.compile().map_err(AssemblerError::Diagnostics)?;
+        let mut program = Program {
+            instructions: compiled_instructions,
+            data: compiled.data.clone(),
+            symbols: compiled.symbols(),
+            debug_info: DebugInfo {
+                files: self.sources.iter().map(|source| source.name.clone()).collect(),
+                entries: compiled.debug_entries.clone(),
+            },
+            link: object.then(|| compiled.link_info()),
+        };
+        if self.optimize {
+            optimizer::optimize(&mut program);
+        }
+        let mut b = ByteCodeCompiler::new(self.output_name);
+        b.store_file(&program).map_err(AssemblerError::Write)
     }
 }
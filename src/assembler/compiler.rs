@@ -1,35 +1,101 @@
-use std::collections::HashMap;
-use proton::lib::machine_type::{Instruction, InstructionType, Word};
-use super::parser::parser::ASTNode;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use proton::lib::bytecode::{DataInit, DataItem, DebugEntry, Export, LinkInfo, Relocation, Symbol, SymbolKind};
+use proton::lib::ffi::Signature;
+use proton::lib::machine_type::{CONSTANT_POOL_SIZE, DEFINE_ENTRY_PROLOGUE, ENTRY_PROLOGUE_LEN, Instruction, InstructionType, MAX_SYSCALL_ARGS, Word};
+use proton::lib::syscalls::Arch;
+use crate::diagnostics::{Diagnostic, Span};
+use super::lexer::lexer::NumberType;
+use super::parser::parser::{ASTNode, DataKind, Spanned};
 
 #[derive(Debug)]
 pub enum CompilerError {
-    UnexpectedArgument
+    UnexpectedArgument,
+    UnknownFrameSlot(String),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    EmptyData(String),
+    UnknownSyscall { name: String, target: Arch },
+    TooManySyscallArgs(u16),
+    UnknownExtern(String),
+    NotVariadic,
+    TooManyOperands(usize),
+    TooManySlots,
+    DataTooLarge { name: String, cells: usize },
+    UnknownVariable(String),
+    NotAVariable(String)
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedArgument => write!(f, "this operand is not allowed here"),
+            Self::UnknownFrameSlot(name) => write!(f, "`{}` is not an argument or local of the current function", name),
+            Self::UnknownLabel(name) => write!(f, "unknown label `{}`", name),
+            Self::DuplicateLabel(name) => write!(f, "`{}` is defined more than once", name),
+            Self::EmptyData(name) => write!(f, "data item `{}` has no cells", name),
+            Self::UnknownSyscall { name, target } => write!(f, "unknown syscall `{}` for {}", name, target),
+            Self::TooManySyscallArgs(count) => write!(f, "a syscall takes at most {} arguments, not {}", MAX_SYSCALL_ARGS, count),
+            Self::UnknownExtern(name) => write!(f, "`{}` has no signature; declare it with `.extern {}(...) -> type`", name, name),
+            Self::NotVariadic => write!(f, "only a variadic function takes a count of extra arguments"),
+            Self::TooManyOperands(count) => write!(f, "this instruction needs {} operand words, at most {} fit", count, u16::MAX),
+            Self::TooManySlots => write!(f, "the program needs more than the {} constant pool slots", CONSTANT_POOL_SIZE),
+            Self::DataTooLarge { name, cells } => write!(f, "data item `{}` has {} cells, at most {} fit in one allocation", name, cells, u16::MAX),
+            Self::UnknownVariable(name) => write!(f, "unknown variable `{}`; create it with `.data` or a `STORE`", name),
+            Self::NotAVariable(name) => write!(f, "`{}` is an `.extern`, not a variable", name),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SymbolValue {
     Label(u16),
-    Variable(u16)
+    Variable(u16),
+    Argument(u16),
+    Local(u16),
+    /// A `.const` value, compiled in place of its name.
+    Constant(ASTNode),
+    /// An `.extern` signature, used by `DLL_CALL` with its name.
+    Extern(Signature)
 }
 
 #[derive(Debug)]
 pub struct Compiler {
     pub symbol_table: Vec<HashMap<String, SymbolValue>>,
-    pub ASTnodes: Vec<ASTNode>,
+    pub ASTnodes: Vec<Spanned<ASTNode>>,
     pub ic: usize,
     pub instruction_index: usize,
-    pub const_pool_index: usize
+    pub const_pool_index: usize,
+    pub debug_entries: Vec<DebugEntry>,
+    pub data: Vec<DataItem>,
+    /// Whether to produce a relocatable object: no entry prologue is prepended, and labels
+    /// this file doesn't define become imports for the linker to resolve.
+    pub object: bool,
+    /// Names declared with `.global`.
+    pub globals: HashSet<String>,
+    /// Labels named by a `CALL`. Together with `main` and `.global` names they start a new
+    /// function, which closes the previous function's `.args`/`.locals` scope.
+    pub called: HashSet<String>,
+    pub relocations: Vec<Relocation>,
+    /// Whose syscall numbers `SYSCALL name` compiles to.
+    pub target: Arch
 }
 
 impl Compiler {
-    pub fn new(ASTnodes: Vec<ASTNode>) -> Self {
+    pub fn new(ASTnodes: Vec<Spanned<ASTNode>>) -> Self {
         Self {
             symbol_table: vec![],
             ASTnodes,
             ic: 0,
             instruction_index: 1,
-            const_pool_index: 0
+            const_pool_index: 0,
+            debug_entries: vec![],
+            data: vec![],
+            object: false,
+            globals: HashSet::new(),
+            called: HashSet::new(),
+            relocations: vec![],
+            target: Arch::host().unwrap_or(Arch::X86_64)
         }
     }
 
@@ -39,73 +105,177 @@ impl Compiler {
         }
     }
 
-    pub fn generate_label_table(&mut self) {
+    pub fn generate_label_table(&mut self, diagnostics: &mut Vec<Diagnostic>) {
+        // `compile` prepends `CALL main; RET` only when there is a `main`, shifting everything by two.
+        let has_main = self.ASTnodes.iter().any(|node| matches!(&node.node, ASTNode::Label(name) if name == "main"));
+        self.instruction_index = if has_main && !self.object { ENTRY_PROLOGUE_LEN } else { 0 };
+        if self.symbol_table.is_empty() {
+            self.symbol_table.push(HashMap::new());
+        }
+        let mut stored = Vec::new();
         while self.ic < self.ASTnodes.len() {
-            match &self.ASTnodes[self.ic] {
-                ASTNode::Instruction(_, _) => {
+            let Spanned { node, span } = &self.ASTnodes[self.ic];
+            match node {
+                ASTNode::Instruction(it, args) => {
+                    match (it, args.first()) {
+                        (InstructionType::INST_CALL, Some(Spanned { node: ASTNode::Variable(name) | ASTNode::Label(name), .. })) => {
+                            self.called.insert(name.clone());
+                        },
+                        (InstructionType::INST_STORE, Some(Spanned { node: ASTNode::Variable(name), span })) => {
+                            stored.push((name.clone(), *span));
+                        },
+                        _ => {}
+                    }
+                    self.instruction_index += 1;
+                },
+                ASTNode::Frame { .. } => {
                     self.instruction_index += 1;
                 },
                 ASTNode::Label(label_name) => {
-                    dbg!(label_name, &self.instruction_index);
-                    if let Some(ctx) = self.symbol_table.last_mut() {
+                    let ctx = self.symbol_table.last_mut().expect("NO CONTEXT");
+                    if ctx.contains_key(label_name) {
+                        diagnostics.push(Diagnostic::new(*span, CompilerError::DuplicateLabel(label_name.to_string())));
+                    } else {
                         ctx.insert(label_name.to_string(), SymbolValue::Label(self.instruction_index as u16));
+                    }
+                },
+                ASTNode::Const { name, value } => {
+                    let ctx = self.symbol_table.last_mut().expect("NO CONTEXT");
+                    if ctx.contains_key(name) {
+                        diagnostics.push(Diagnostic::new(*span, CompilerError::DuplicateLabel(name.to_string())));
+                    } else {
+                        ctx.insert(name.to_string(), SymbolValue::Constant(value.node.clone()));
+                    }
+                },
+                ASTNode::Global(names) => {
+                    self.globals.extend(names.iter().cloned());
+                },
+                ASTNode::Extern { name, signature } => {
+                    let ctx = self.symbol_table.last_mut().expect("NO CONTEXT");
+                    if ctx.contains_key(name) {
+                        diagnostics.push(Diagnostic::new(*span, CompilerError::DuplicateLabel(name.to_string())));
                     } else {
-                        let mut d = HashMap::new();
-                        d.insert(label_name.to_string(), SymbolValue::Label(self.instruction_index as u16));
-                        self.symbol_table.push(d);
+                        ctx.insert(name.to_string(), SymbolValue::Extern(signature.clone()));
+                    }
+                },
+                ASTNode::Data { name, .. } => {
+                    let ctx = self.symbol_table.last_mut().expect("NO CONTEXT");
+                    if ctx.contains_key(name) {
+                        diagnostics.push(Diagnostic::new(*span, CompilerError::DuplicateLabel(name.to_string())));
+                    } else if self.const_pool_index >= CONSTANT_POOL_SIZE {
+                        diagnostics.push(Diagnostic::new(*span, CompilerError::TooManySlots));
+                    } else {
+                        // The machine stores the item's address in this slot when it loads the program.
+                        ctx.insert(name.to_string(), SymbolValue::Variable(self.const_pool_index as u16));
+                        self.const_pool_index += 1;
                     }
                 },
                 _ => {}
             }
             self.advance();
         }
+        // Apart from `.data` items, a global variable exists once something `STORE`s to it,
+        // wherever that is, so a `LOAD` above the `STORE` finds it too.
+        for (name, span) in stored {
+            let ctx = self.symbol_table.first_mut().expect("NO CONTEXT");
+            match ctx.get(&name) {
+                // Already a variable, a label or constant the operand resolves to, or an
+                // `.extern`, which `variable_address` reports.
+                Some(_) => {},
+                // An import, patched by the linker.
+                None if self.object && self.globals.contains(&name) => {},
+                None if self.const_pool_index >= CONSTANT_POOL_SIZE => diagnostics.push(Diagnostic::new(span, CompilerError::TooManySlots)),
+                None => {
+                    ctx.insert(name, SymbolValue::Variable(self.const_pool_index as u16));
+                    self.const_pool_index += 1;
+                }
+            }
+        }
         self.ic = 0;
         self.instruction_index = 0;
-        dbg!(&self.symbol_table);
     }
 
-    pub fn get_or_allocate_variable_address(&mut self, name: &str) -> Result<u16, CompilerError> {
+    /// The constant pool slot of the global variable or data item `name`.
+    pub fn variable_address(&self, name: &str) -> Result<u16, CompilerError> {
         for scope in self.symbol_table.iter().rev() {
-            if let Some(SymbolValue::Variable(addr)) = scope.get(name) {
-                return Ok(*addr);
-            } else if let Some(SymbolValue::Label(addr)) = scope.get(name) {
-                return Ok(*addr);
+            match scope.get(name) {
+                Some(SymbolValue::Variable(addr)) | Some(SymbolValue::Label(addr)) => return Ok(*addr),
+                Some(SymbolValue::Extern(_)) => return Err(CompilerError::NotAVariable(name.to_string())),
+                _ => {}
             }
         }
+        Err(CompilerError::UnknownVariable(name.to_string()))
+    }
 
-        let addr = self.const_pool_index as u16;
-        if let Some(scope) = self.symbol_table.last_mut() {
-            scope.insert(name.to_string(), SymbolValue::Variable(addr));
-        } else {
-            let mut new_scope = HashMap::new();
-            new_scope.insert(name.to_string(), SymbolValue::Variable(addr));
-            self.symbol_table.push(new_scope);
-        }
+    /// Every label in the program with the instruction index it resolves to.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = self
+            .symbol_table
+            .iter()
+            .flat_map(|scope| scope.iter())
+            .filter_map(|(name, value)| match value {
+                SymbolValue::Label(index) => Some(Symbol { name: name.clone(), index: *index }),
+                _ => None,
+            })
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.index);
+        symbols
+    }
 
-        self.const_pool_index += 1;
-        Ok(addr)
+    /// Resolves the operand of `CALL` and the jumps to the instruction index of a label.
+    /// Plain numbers are taken as instruction indices.
+    pub fn parse_code_address(&self, arg: &ASTNode) -> Result<Word, CompilerError> {
+        let name = match arg {
+            ASTNode::Variable(name) | ASTNode::Label(name) => name,
+            ASTNode::Number(super::lexer::lexer::NumberType::u16(index)) => return Ok(Word::from(*index)),
+            _ => return Err(CompilerError::UnexpectedArgument)
+        };
+        self.symbol_table
+            .iter()
+            .find_map(|scope| match scope.get(name) {
+                Some(SymbolValue::Label(index)) => Some(Word::from(*index)),
+                _ => None
+            })
+            .ok_or_else(|| CompilerError::UnknownLabel(name.to_string()))
+    }
+
+    /// The value of the `.const` called `name`, if there is one.
+    fn constant(&self, name: &str) -> Option<&ASTNode> {
+        self.symbol_table.iter().find_map(|scope| match scope.get(name) {
+            Some(SymbolValue::Constant(value)) => Some(value),
+            _ => None
+        })
+    }
+
+    /// The signature declared with `.extern name`, if there is one.
+    fn external(&self, name: &str) -> Option<&Signature> {
+        self.symbol_table.iter().find_map(|scope| match scope.get(name) {
+            Some(SymbolValue::Extern(signature)) => Some(signature),
+            _ => None
+        })
     }
 
     pub fn parse_arg(&mut self, arg: &ASTNode) -> Result<Vec<Word>, CompilerError> {
         match arg {
-            ASTNode::Variable(x) => {
-                let address = self.get_or_allocate_variable_address(&x[0..])?;
-                Ok(vec![Word::U16(address)])
-            },
-            ASTNode::Label(x) => {
-                let address = self.get_or_allocate_variable_address(&x[0..])?;
-                Ok(vec![Word::U16(address)])
+            ASTNode::Variable(x) if self.constant(x).is_some() => {
+                let value = self.constant(x).cloned().expect("checked above");
+                self.parse_arg(&value)
             },
+            ASTNode::Variable(x) | ASTNode::Label(x) => Ok(vec![Word::U16(self.variable_address(x)?)]),
             ASTNode::StringLiteral(x) => {
-                let mut args = vec![Word::from(x.len() as u16)];
-                dbg!(&args);
+                let mut args = vec![Word::from(x.chars().count() as u16)];
                 args.extend(x.chars().map(Word::from));
                 Ok(args)
             },
             ASTNode::Number(x) => {
                 match x {
                     super::lexer::lexer::NumberType::u16(u) => Ok(vec![Word::from(*u)]),
-                    super::lexer::lexer::NumberType::f16(f) => Ok(vec![Word::from(*f)])
+                    super::lexer::lexer::NumberType::f16(f) => Ok(vec![Word::from(*f)]),
+                    super::lexer::lexer::NumberType::i16(i) => Ok(vec![Word::from(*i)]),
+                    super::lexer::lexer::NumberType::u32(u) => Ok(vec![Word::from(*u)]),
+                    super::lexer::lexer::NumberType::i32(i) => Ok(vec![Word::from(*i)]),
+                    super::lexer::lexer::NumberType::bool(b) => Ok(vec![Word::from(*b)]),
+                    super::lexer::lexer::NumberType::char(c) => Ok(vec![Word::from(*c)])
                 }
             }
             _ => {
@@ -114,16 +284,182 @@ impl Compiler {
         }
     }
 
-    pub fn compile_instruction(&mut self, it: InstructionType, args: Vec<ASTNode>) -> Result<Instruction, CompilerError> {
-        let mut args_flattened: Vec<Word> = vec![];
-        for arg in &args[0..] { 
-            for a in self.parse_arg(arg)? {
-                args_flattened.push(a);
+    /// Compiles `SYSCALL name` to the argument count and the target's syscall number.
+    pub fn parse_syscall(&self, name: &str) -> Result<Vec<Word>, CompilerError> {
+        let syscall = self.target.syscall(name).ok_or_else(|| CompilerError::UnknownSyscall {
+            name: name.to_string(),
+            target: self.target
+        })?;
+        Ok(vec![Word::U16(syscall.args as u16), Word::U16(syscall.number)])
+    }
+
+    /// Compiles `DLL_CALL` with a signature, written out or named by `.extern`, and an
+    /// optional count of the arguments passed to its `...`. `None` for `DLL_CALL n`, which
+    /// leaves the argument types to the machine to guess.
+    pub fn compile_dll_call(&self, args: &[Spanned<ASTNode>]) -> Result<Option<Vec<Word>>, Diagnostic> {
+        let Some((Spanned { node, span }, rest)) = args.split_first() else {
+            return Ok(None);
+        };
+        let signature = match node {
+            ASTNode::Signature(signature) => Some(signature),
+            ASTNode::Variable(name) if self.constant(name).is_none() => {
+                Some(self.external(name).ok_or_else(|| Diagnostic::new(*span, CompilerError::UnknownExtern(name.clone())))?)
+            },
+            _ => None
+        };
+        let extra = match (signature, rest) {
+            (_, []) => 0,
+            (Some(signature), [Spanned { node: ASTNode::Number(NumberType::u16(extra)), .. }]) if signature.variadic => *extra,
+            (Some(_), [Spanned { node: ASTNode::Number(_), span }]) => return Err(Diagnostic::new(*span, CompilerError::NotVariadic)),
+            (_, [Spanned { span, .. }, ..]) => return Err(Diagnostic::new(*span, CompilerError::UnexpectedArgument))
+        };
+        Ok(signature.map(|signature| signature.encode(signature.args.len() as u16 + extra)))
+    }
+
+    /// Lays out a `.data` item as the cells the machine will place on the heap.
+    pub fn compile_data(&mut self, name: &str, slot: u16, kind: DataKind, values: &[Spanned<ASTNode>]) -> Result<DataItem, Diagnostic> {
+        let mut words = Vec::new();
+        for Spanned { node, span } in values {
+            let node = match node {
+                ASTNode::Variable(x) => self.constant(x).cloned().unwrap_or_else(|| node.clone()),
+                _ => node.clone()
+            };
+            let unexpected = || Diagnostic::new(*span, CompilerError::UnexpectedArgument);
+            match (kind, node) {
+                (DataKind::String, ASTNode::StringLiteral(text)) => {
+                    words.extend(text.chars().map(Word::from));
+                    words.push(Word::Char('\0'));
+                },
+                (DataKind::Array, node @ ASTNode::Number(_)) => words.extend(self.parse_arg(&node).map_err(|_| unexpected())?),
+                (DataKind::Zero, ASTNode::Number(NumberType::u16(count))) => {
+                    if count == 0 {
+                        return Err(Diagnostic::new(*span, CompilerError::EmptyData(name.to_string())));
+                    }
+                    return Ok(DataItem { name: name.to_string(), slot, init: DataInit::Zeroed(count) });
+                },
+                _ => return Err(unexpected())
             }
         }
-        let args: Vec<Word> = args.iter().flat_map(|arg| self.parse_arg(arg)).flatten().collect();
-        let args: Option<Vec<Word>> = if !args.is_empty() {
-            Some(args)
+        if words.len() > u16::MAX as usize {
+            let span = values.first().map_or_else(Span::default, |value| value.span);
+            return Err(Diagnostic::new(span, CompilerError::DataTooLarge { name: name.to_string(), cells: words.len() }));
+        }
+        Ok(DataItem { name: name.to_string(), slot, init: DataInit::Words(words) })
+    }
+
+    /// Whether the label `name` is the entry of a function rather than a jump target
+    /// inside one.
+    fn starts_function(&self, name: &str) -> bool {
+        name == "main" || self.called.contains(name) || self.globals.contains(name)
+    }
+
+    /// Opens the scope of a function declared with `.args` / `.locals`, replacing the
+    /// previous function's, and returns the `ENTER` that sets up its frame.
+    pub fn compile_frame(&mut self, args: &[String], locals: &[String]) -> Instruction {
+        if self.symbol_table.is_empty() {
+            self.symbol_table.push(HashMap::new());
+        }
+        self.symbol_table.truncate(1);
+        let mut scope = HashMap::new();
+        for (i, name) in args.iter().enumerate() {
+            scope.insert(name.to_string(), SymbolValue::Argument(i as u16));
+        }
+        for (i, name) in locals.iter().enumerate() {
+            scope.insert(name.to_string(), SymbolValue::Local(i as u16));
+        }
+        self.symbol_table.push(scope);
+        Instruction {
+            tt: InstructionType::INST_ENTER,
+            values: Some(vec![Word::from(args.len() as u16), Word::from(locals.len() as u16)])
+        }
+    }
+
+    /// Resolves the operand of `LOAD_ARG`, `STORE_LOCAL` and friends against the current
+    /// function's scope. Plain numbers are slot indices.
+    pub fn parse_frame_slot(&self, it: InstructionType, arg: &ASTNode) -> Result<Word, CompilerError> {
+        let name = match arg {
+            ASTNode::Variable(name) => name,
+            ASTNode::Number(super::lexer::lexer::NumberType::u16(index)) => return Ok(Word::from(*index)),
+            _ => return Err(CompilerError::UnexpectedArgument)
+        };
+        let wants_argument = matches!(it, InstructionType::INST_LOAD_ARG | InstructionType::INST_STORE_ARG);
+        let scope = if self.symbol_table.len() > 1 { self.symbol_table.last() } else { None };
+        match (scope.and_then(|scope| scope.get(name)), wants_argument) {
+            (Some(SymbolValue::Argument(index)), true) | (Some(SymbolValue::Local(index)), false) => Ok(Word::from(*index)),
+            _ => Err(CompilerError::UnknownFrameSlot(name.to_string()))
+        }
+    }
+
+    /// Notes that operand `operand` of the instruction being compiled must be patched by
+    /// the linker. Only object files carry relocations.
+    fn relocate(&mut self, operand: usize, kind: SymbolKind, symbol: Option<String>) {
+        if self.object {
+            self.relocations.push(Relocation {
+                instruction: self.instruction_index as u16,
+                operand: operand as u8,
+                kind,
+                symbol
+            });
+        }
+    }
+
+    /// Whether `name` is declared `.global` without being defined here, as a label or a
+    /// data item, making it an import.
+    fn is_import(&self, name: &str) -> bool {
+        self.object
+            && self.globals.contains(name)
+            && !matches!(
+                self.symbol_table.first().and_then(|scope| scope.get(name)),
+                Some(SymbolValue::Label(_)) | Some(SymbolValue::Variable(_))
+            )
+    }
+
+    pub fn compile_instruction(&mut self, it: InstructionType, args: Vec<Spanned<ASTNode>>) -> Result<Instruction, Diagnostic> {
+        if matches!(it, InstructionType::INST_DLL_CALL) && let Some(values) = self.compile_dll_call(&args)? {
+            return Ok(Instruction { tt: it, values: Some(values) });
+        }
+        let mut args_flattened: Vec<Word> = vec![];
+        for Spanned { node: arg, span } in &args[0..] {
+            let operand = args_flattened.len();
+            let compiled = match (it, arg) {
+                (InstructionType::INST_LOAD_ARG, _)
+                | (InstructionType::INST_STORE_ARG, _)
+                | (InstructionType::INST_LOAD_LOCAL, _)
+                | (InstructionType::INST_STORE_LOCAL, _) => self.parse_frame_slot(it, arg).map(|word| vec![word]),
+                _ if it.takes_code_address() => match self.parse_code_address(arg) {
+                    Ok(word) => {
+                        self.relocate(operand, SymbolKind::Code, None);
+                        Ok(vec![word])
+                    },
+                    Err(CompilerError::UnknownLabel(name)) if self.object => {
+                        self.relocate(operand, SymbolKind::Code, Some(name));
+                        Ok(vec![Word::U16(0)])
+                    },
+                    Err(error) => Err(error)
+                },
+                (InstructionType::INST_SYSCALL, ASTNode::Variable(name)) if self.constant(name).is_none() => self.parse_syscall(name),
+                (InstructionType::INST_SYSCALL, _) if operand == 0 => self.parse_arg(arg).and_then(|words| match words.first() {
+                    Some(Word::U16(count)) if *count as usize > MAX_SYSCALL_ARGS => Err(CompilerError::TooManySyscallArgs(*count)),
+                    _ => Ok(words)
+                }),
+                (InstructionType::INST_LOAD | InstructionType::INST_STORE, ASTNode::Variable(name)) if self.is_import(name) => {
+                    self.relocate(operand, SymbolKind::Slot, Some(name.clone()));
+                    Ok(vec![Word::U16(0)])
+                },
+                (InstructionType::INST_LOAD | InstructionType::INST_STORE, _) => {
+                    self.relocate(operand, SymbolKind::Slot, None);
+                    self.parse_arg(arg)
+                },
+                _ => self.parse_arg(arg)
+            };
+            args_flattened.extend(compiled.map_err(|error| Diagnostic::new(*span, error))?);
+        }
+        if args_flattened.len() > u16::MAX as usize {
+            let span = args.first().map_or_else(Span::default, |arg| arg.span);
+            return Err(Diagnostic::new(span, CompilerError::TooManyOperands(args_flattened.len())));
+        }
+        let args: Option<Vec<Word>> = if !args_flattened.is_empty() {
+            Some(args_flattened)
         } else {
             None
         };
@@ -133,30 +469,204 @@ impl Compiler {
         })
     }
 
-    pub fn compile(&mut self) -> Result<Vec<Instruction>, CompilerError> {
+    /// The symbols this object defines for others: `.global` labels and data items, and
+    /// `main` so the linker can find the entry point.
+    pub fn link_info(&self) -> LinkInfo {
+        let mut exports: Vec<Export> = self
+            .symbol_table
+            .first()
+            .into_iter()
+            .flat_map(|scope| scope.iter())
+            .filter_map(|(name, value)| {
+                let (kind, value) = match value {
+                    SymbolValue::Label(index) if self.globals.contains(name) || name == "main" => (SymbolKind::Code, *index),
+                    SymbolValue::Variable(slot) if self.globals.contains(name) && self.data.iter().any(|item| &item.name == name) => (SymbolKind::Slot, *slot),
+                    _ => return None
+                };
+                Some(Export { name: name.clone(), kind, value })
+            })
+            .collect();
+        exports.sort_by(|a, b| a.name.cmp(&b.name));
+        LinkInfo {
+            slots: self.const_pool_index as u16,
+            exports,
+            relocations: self.relocations.clone()
+        }
+    }
+
+    /// Records where the instruction about to be emitted came from, for the Debug section.
+    fn record_position(&mut self, instruction: usize, span: Span) {
+        self.debug_entries.push(DebugEntry {
+            instruction: instruction as u16,
+            file: span.file as u16,
+            line: span.line as u32,
+            column: span.column as u32
+        });
+    }
+
+    /// Compiles every node, collecting all errors instead of stopping at the first one.
+    pub fn compile(&mut self) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
         let mut instructions: Vec<Instruction> = vec![];
-        self.generate_label_table();
+        let mut diagnostics: Vec<Diagnostic> = vec![];
+        self.generate_label_table(&mut diagnostics);
         while self.ic < self.ASTnodes.len() {
-            let value = self.ASTnodes[self.ic].clone();
-            match value {
+            let Spanned { node, span } = self.ASTnodes[self.ic].clone();
+            match node {
                 ASTNode::Instruction(it, args) => {
-                    instructions.push(self.compile_instruction(it, args)?);
-                    self.advance();
+                    self.instruction_index = instructions.len();
+                    match self.compile_instruction(it, args) {
+                        Ok(instruction) => {
+                            self.record_position(instructions.len(), span);
+                            instructions.push(instruction);
+                        },
+                        Err(diagnostic) => diagnostics.push(diagnostic)
+                    }
                 },
-                ASTNode::Label(_) => {
-                    self.advance();
+                ASTNode::Frame { args, locals } => {
+                    self.record_position(instructions.len(), span);
+                    instructions.push(self.compile_frame(&args, &locals));
                 },
-                _=> {
-
-                }
+                ASTNode::Label(name) if self.starts_function(&name) => {
+                    // Until its own `.args`/`.locals`, if any, a function sees no frame slots.
+                    self.symbol_table.truncate(1);
+                },
+                ASTNode::Data { name, kind, values } => {
+                    // A name that is not a data slot was already reported as a duplicate.
+                    if let Some(SymbolValue::Variable(slot)) = self.symbol_table.first().and_then(|scope| scope.get(&name)) {
+                        match self.compile_data(&name, *slot, kind, &values) {
+                            Ok(item) => self.data.push(item),
+                            Err(diagnostic) => diagnostics.push(diagnostic)
+                        }
+                    }
+                },
+                _ => {}
             }
+            self.advance();
         }
-        if let Some(SymbolValue::Label(index)) = self.symbol_table.last().expect("NO CONTEXT").get("main") {
-            instructions.insert(0, Instruction {
-                tt: InstructionType::INST_CALL,
-                values: Some(vec![Word::from(*index)])
-            });
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        if self.object {
+            return Ok(instructions);
+        }
+        if let Some(SymbolValue::Label(index)) = self.symbol_table.first().and_then(|scope| scope.get("main")) {
+            instructions.splice(0..0, DEFINE_ENTRY_PROLOGUE(*index));
+            for entry in self.debug_entries.iter_mut() {
+                entry.instruction += ENTRY_PROLOGUE_LEN as u16;
+            }
         }
         Ok(instructions)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proton::lib::bytecode::Program;
+    use proton::lib::machine_type::{DEFINE_PUSH, DEFINE_SYSCALL, QuarkVM, StackValues, VmError};
+    use crate::diagnostics::Diagnostic;
+    use crate::lexer::lexer::Lexer;
+    use crate::macros::MacroExpander;
+    use crate::parser::parser::Parser;
+    use super::Compiler;
+
+    fn compile(source: &str) -> Result<Program, Vec<Diagnostic>> {
+        let mut lexer = Lexer::new(source, 0);
+        lexer.lex().expect("lexes");
+        let nodes = Parser::new().parse(std::mem::take(&mut lexer.tokens)).expect("parses");
+        let nodes = MacroExpander::new().expand(nodes).expect("expands");
+        let mut compiler = Compiler::new(nodes);
+        let instructions = compiler.compile()?;
+        Ok(Program { data: compiler.data.clone(), ..Program::new(instructions) })
+    }
+
+    fn assemble(source: &str) -> Program {
+        compile(source).expect("compiles")
+    }
+
+    fn run(source: &str) -> QuarkVM {
+        let mut vm = QuarkVM::default();
+        vm.load_program(assemble(source)).expect("loads");
+        vm.run().expect("runs without a fault");
+        vm
+    }
+
+    #[test]
+    fn data_strings_and_pushed_strings_share_a_layout() {
+        let vm = run(r#"
+.data
+msg: .string "hi!"
+.text
+main:
+    LOAD msg
+    CALL strlen
+    PUSH_STR "hi!"
+    CALL strlen
+    RET
+strlen:
+    .args s
+    .locals n
+next:
+    LOAD_ARG s
+    LOAD_LOCAL n
+    ADD
+    DEREF
+    PUSH '\0'
+    EQ
+    JMPNZ done
+    POP
+    LOAD_LOCAL n
+    PUSH 1
+    ADD
+    STORE_LOCAL n
+    JMP next
+done:
+    POP
+    LOAD_LOCAL n
+    RET
+"#);
+        assert!(matches!(vm.peek_stack(0), Ok(StackValues::U16(3))));
+        assert!(matches!(vm.peek_stack(1), Ok(StackValues::U16(3))));
+    }
+
+    #[test]
+    fn strings_keep_characters_above_u_ffff() {
+        let vm = run(r#"
+main:
+    PUSH_STR "a\u{1F600}"
+    DUP
+    PUSH 1
+    ADD
+    DEREF
+    RET
+"#);
+        assert!(matches!(vm.peek_stack(0), Ok(StackValues::Char('\u{1F600}'))));
+        let Ok(StackValues::Pointer(ptr)) = vm.peek_stack(1) else { panic!("expected the string") };
+        assert_eq!(vm.get_str_from_ptr(ptr).unwrap(), "a\u{1F600}");
+    }
+
+    #[test]
+    fn main_runs_once_when_it_is_not_the_first_label() {
+        let vm = run(r#"
+one:
+    PUSH 1
+    RET
+main:
+    CALL one
+    RET
+"#);
+        assert!(matches!(vm.peek_stack(0), Ok(StackValues::U16(1))));
+        assert!(vm.peek_stack(1).is_err());
+    }
+
+    #[test]
+    fn syscalls_take_at_most_six_arguments() {
+        let diagnostics = compile("main:\n    SYSCALL 7\n    RET\n").unwrap_err();
+        assert_eq!(diagnostics[0].message, "a syscall takes at most 6 arguments, not 7");
+
+        // Bytecode written by hand gets the same limit at run time.
+        let mut vm = QuarkVM::default();
+        vm.load_program(Program::new(vec![DEFINE_PUSH(0), DEFINE_SYSCALL(7)])).unwrap();
+        let fault = vm.run().unwrap_err();
+        assert!(matches!(fault.error, VmError::TooManySyscallArgs(7)));
+    }
+}
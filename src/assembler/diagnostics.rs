@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// A location in QASM source: the index of the file in the assembler's source list,
+/// 1-based line and column (in characters) and how many characters to underline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn new(file: usize, line: usize, column: usize, length: usize) -> Self {
+        Self { file, line, column, length }
+    }
+
+    /// Whether both spans start on the same line of the same file.
+    pub fn same_line(&self, other: &Span) -> bool {
+        self.file == other.file && self.line == other.line
+    }
+}
+
+/// An error found while assembling, pointing at the source it is about.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    /// Formats the diagnostic with the offending source line and a caret under the span:
+    ///
+    /// ```text
+    /// error: unknown label `nowhere`
+    ///  --> loop.qasm:2:7
+    ///   |
+    /// 2 |   JMP nowhere
+    ///   |       ^^^^^^^
+    /// ```
+    pub fn render(&self, file_name: &str, source_code: &str) -> String {
+        let Span { line, column, length, .. } = self.span;
+        let mut out = format!("error: {}\n --> {}:{}:{}\n", self.message, file_name, line, column);
+        let Some(text) = source_code.lines().nth(line.saturating_sub(1)) else {
+            return out;
+        };
+        let gutter = " ".repeat(line.to_string().len());
+        // Tabs are kept so the caret lines up with the source line as the terminal shows it.
+        let indent: String = text
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line, text));
+        out.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(length.max(1))));
+        out
+    }
+}
@@ -1,258 +1,470 @@
+use crate::diagnostics::{Diagnostic, Span};
 use proton::lib::machine_type::{ InstructionType };
 use half::f16;
+use std::fmt;
 
-const IGNORE: [char; 3] = ['\n', '\t', ' '];
+const IGNORE: [char; 4] = ['\n', '\t', '\r', ' '];
 
 pub struct Lexer<'a> {
     pub source_code: &'a str,
     current_index: usize,
     pub tokens: Vec<Token>,
+    file: usize,
     line_number: usize,
     column: usize
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum LexerError {
     EOFWithNoTokens,
     InvalidInstructionType,
     InvalidNumber,
-    InvalidLabel
+    InvalidLabel,
+    InvalidDirective,
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedChar,
+    InvalidEscape
+}
+
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EOFWithNoTokens => write!(f, "the file contains no instructions"),
+            Self::InvalidInstructionType => write!(f, "unknown instruction"),
+            Self::InvalidNumber => write!(f, "invalid number literal"),
+            Self::InvalidLabel => write!(f, "invalid label"),
+            Self::InvalidDirective => write!(f, "expected a directive name after `.`"),
+            Self::UnexpectedCharacter(c) => write!(f, "unexpected character `{}`", c.escape_debug()),
+            Self::UnterminatedString => write!(f, "unterminated string literal"),
+            Self::UnterminatedChar => write!(f, "character literals hold exactly one character and end with `'`"),
+            Self::InvalidEscape => write!(f, "invalid escape sequence"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum TokenType {
     InstructionType(InstructionType),
-    Label(usize, usize),
+    Label(String),
+    Directive(String),
     String(String),
     Number(NumberType),
     Colon,
     Comma,
+    Equals,
+    LParen,
+    RParen,
+    /// `->` before the return type of a signature.
+    Arrow,
+    /// `...` marking a variadic signature.
+    Ellipsis,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum NumberType {
     u16(u16),
-    f16(f16)
+    f16(f16),
+    i16(i16),
+    u32(u32),
+    i32(i32),
+    bool(bool),
+    char(char)
+}
+
+impl fmt::Display for NumberType {
+    /// Writes the literal so that it lexes back to the same value and type.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::u16(v) => write!(f, "{}", v),
+            Self::f16(v) => write!(f, "{:?}", v.to_f32()),
+            Self::i16(v) => write!(f, "{}i16", v),
+            Self::u32(v) => write!(f, "{}u32", v),
+            Self::i32(v) => write!(f, "{}i32", v),
+            Self::bool(b) => write!(f, "{}", b),
+            Self::char(c) => write!(f, "'{}'", escape(*c, '\'')),
+        }
+    }
+}
+
+/// Writes `c` as it must appear inside a literal closed by `quote`, the inverse of the
+/// escapes `Lexer::build_escape` understands.
+pub fn escape(c: char, quote: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\0' => "\\0".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if c == quote => format!("\\{}", c),
+        c if (c as u32) < 0x80 && c.is_control() => format!("\\x{:02x}", c as u32),
+        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub tt: TokenType,
-    pub line_number: usize,
-    pub column: usize
+    pub span: Span
+}
+
+/// The instruction a mnemonic stands for, if it is one.
+fn keyword(word: &str) -> Option<InstructionType> {
+    match word {
+            "NOOP"      => Some(InstructionType::INST_NOOP),
+            "PUSH"      => Some(InstructionType::INST_PUSH),
+            "POP"       => Some(InstructionType::INST_POP),
+            "ADD"       => Some(InstructionType::INST_ADD),
+            "AND"       => Some(InstructionType::INST_AND),
+            "OR"        => Some(InstructionType::INST_OR),
+            "XOR"       => Some(InstructionType::INST_XOR),
+            "NOT"       => Some(InstructionType::INST_NOT),
+            "SHL"       => Some(InstructionType::INST_SHL),
+            "SHR"       => Some(InstructionType::INST_SHR),
+            "MUL"       => Some(InstructionType::INST_MUL),
+            "DIV"       => Some(InstructionType::INST_DIV),
+            "SUB"       => Some(InstructionType::INST_SUB),
+            "JMPZ"      => Some(InstructionType::INST_JMPZ),
+            "JMPEQ"     => Some(InstructionType::INST_JMPEQ),
+            "JMPNEQ"    => Some(InstructionType::INST_JMPNEQ),
+            "JMPNZ"     => Some(InstructionType::INST_JMPNZ),
+            "PUSH_STR"  => Some(InstructionType::INST_PUSH_STR),
+            "ALLOC"     => Some(InstructionType::INST_ALLOC),
+            "ALLOC_RAW" => Some(InstructionType::INST_ALLOC_RAW),
+            "SYSCALL"   => Some(InstructionType::INST_SYSCALL),
+            "DUP"       => Some(InstructionType::INST_DUP),
+            "INSWAP"    => Some(InstructionType::INST_INSWAP),
+            "PRINT"     => Some(InstructionType::INST_PRINT),
+            "LOAD"      => Some(InstructionType::INST_LOAD),
+            "STORE"     => Some(InstructionType::INST_STORE),
+            "DEREF"     => Some(InstructionType::INST_DEREF),
+            "REF"       => Some(InstructionType::INST_REF),
+            "DEBUG"     => Some(InstructionType::INST_DEBUG),
+            "CALL"     => Some(InstructionType::INST_CALL),
+            "RET"     => Some(InstructionType::INST_RET),
+            "PUT"     => Some(InstructionType::INST_PUT),
+            "STD_SYSCALL"     => Some(InstructionType::INST_STD_SYSCALL),
+            "DLL_LOAD"     => Some(InstructionType::INST_DLL_LOAD),
+            "DLL_CALL"     => Some(InstructionType::INST_DLL_CALL),
+            "HOST_CALL"     => Some(InstructionType::INST_HOST_CALL),
+            "ENTER"     => Some(InstructionType::INST_ENTER),
+            "LOAD_ARG"     => Some(InstructionType::INST_LOAD_ARG),
+            "STORE_ARG"     => Some(InstructionType::INST_STORE_ARG),
+            "LOAD_LOCAL"     => Some(InstructionType::INST_LOAD_LOCAL),
+            "STORE_LOCAL"     => Some(InstructionType::INST_STORE_LOCAL),
+            "FREE"     => Some(InstructionType::INST_FREE),
+            "FREE_RAW"     => Some(InstructionType::INST_FREE_RAW),
+            "LT"     => Some(InstructionType::INST_LT),
+            "GT"     => Some(InstructionType::INST_GT),
+            "LE"     => Some(InstructionType::INST_LE),
+            "GE"     => Some(InstructionType::INST_GE),
+            "EQ"     => Some(InstructionType::INST_EQ),
+            "JMP"     => Some(InstructionType::INST_JMP),
+            "JMPLT"     => Some(InstructionType::INST_JMPLT),
+            "JMPGT"     => Some(InstructionType::INST_JMPGT),
+            _ => None,
+    }
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source_code: &'a str) -> Self {
+    /// A lexer for `source_code`, the source file with index `file`.
+    pub fn new(source_code: &'a str, file: usize) -> Self {
         Self {
             source_code,
             current_index: 0,
-            column: 0,
+            column: 1,
             tokens: Vec::new(),
-            line_number: 0
+            file,
+            line_number: 1
         }
     }
 
-    pub fn build_ident(&mut self) -> Result<InstructionType, LexerError> {
-        let lexed_starting = self.current_index;
-        while let Some(c) = self.source_code.chars().nth(self.current_index) {
-            if c.is_ascii_alphabetic() || c == '_' {
+    fn peek(&self) -> Option<char> {
+        self.source_code[self.current_index..].chars().next()
+    }
+
+    /// Consumes characters while `accept` holds and returns them.
+    fn take_while(&mut self, mut accept: impl FnMut(char) -> bool) -> &'a str {
+        let start = self.current_index;
+        while let Some(c) = self.peek() {
+            if accept(c) {
                 self.advance();
             } else {
                 break;
             }
         }
-        let lexed_ending = self.current_index;
+        &self.source_code[start..self.current_index]
+    }
+
+    pub fn advance(&mut self) {
+        if let Some(c) = self.peek() {
+            self.current_index += c.len_utf8();
+            self.column += 1;
+        }
+    }
 
-        match &self.source_code[lexed_starting..lexed_ending] {
-            "NOOP"      => Ok(InstructionType::INST_NOOP),
-            "PUSH"      => Ok(InstructionType::INST_PUSH),
-            "POP"       => Ok(InstructionType::INST_POP),
-            "ADD"       => Ok(InstructionType::INST_ADD),
-            "AND"       => Ok(InstructionType::INST_AND),
-            "OR"        => Ok(InstructionType::INST_OR),
-            "XOR"       => Ok(InstructionType::INST_XOR),
-            "NOT"       => Ok(InstructionType::INST_NOT),
-            "SHL"       => Ok(InstructionType::INST_SHL),
-            "SHR"       => Ok(InstructionType::INST_SHR),
-            "MUL"       => Ok(InstructionType::INST_MUL),
-            "DIV"       => Ok(InstructionType::INST_DIV),
-            "SUB"       => Ok(InstructionType::INST_SUB),
-            "JMPZ"      => Ok(InstructionType::INST_JMPZ),
-            "JMPEQ"     => Ok(InstructionType::INST_JMPEQ),
-            "JMPNEQ"    => Ok(InstructionType::INST_JMPNEQ),
-            "JMPNZ"     => Ok(InstructionType::INST_JMPNZ),
-            "PUSH_STR"  => Ok(InstructionType::INST_PUSH_STR),
-            "ALLOC"     => Ok(InstructionType::INST_ALLOC),
-            "ALLOC_RAW" => Ok(InstructionType::INST_ALLOC_RAW),
-            "SYSCALL"   => Ok(InstructionType::INST_SYSCALL),
-            "DUP"       => Ok(InstructionType::INST_DUP),
-            "INSWAP"    => Ok(InstructionType::INST_INSWAP),
-            "PRINT"     => Ok(InstructionType::INST_PRINT),
-            "LOAD"      => Ok(InstructionType::INST_LOAD),
-            "STORE"     => Ok(InstructionType::INST_STORE),
-            "DEREF"     => Ok(InstructionType::INST_DEREF),
-            "REF"       => Ok(InstructionType::INST_REF),
-            "DEBUG"     => Ok(InstructionType::INST_DEBUG),
-            "CALL"     => Ok(InstructionType::INST_CALL),
-            "RET"     => Ok(InstructionType::INST_RET),
-            "PUT"     => Ok(InstructionType::INST_PUT),
-            "STD_SYSCALL"     => Ok(InstructionType::INST_STD_SYSCALL),
-            "DLL_LOAD"     => Ok(InstructionType::INST_DLL_LOAD),
-            "DLL_CALL"     => Ok(InstructionType::INST_DLL_CALL),
-            _ => { 
-                self.current_index -= lexed_ending - lexed_starting;
-                return Err(LexerError::InvalidInstructionType);
+    /// Lexes an identifier: a mnemonic, `true`/`false` or a label name.
+    pub fn build_ident(&mut self) -> TokenType {
+        let word = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        match word {
+            "true" => TokenType::Number(NumberType::bool(true)),
+            "false" => TokenType::Number(NumberType::bool(false)),
+            _ => match keyword(word) {
+                Some(instruction) => TokenType::InstructionType(instruction),
+                None => TokenType::Label(word.to_string()),
             },
         }
     }
 
-    pub fn build_label(&mut self) -> Result<(usize, usize), LexerError> {
-        let lexed_starting = self.current_index;
-        while let Some(c) = self.source_code.chars().nth(self.current_index) {
-            if c.is_ascii_alphabetic() {
-                self.advance();
-            } else {
-                break;
-            }
+    /// Lexes `.name`, returning `name` without the leading dot.
+    pub fn build_directive(&mut self) -> Result<String, LexerError> {
+        self.advance();
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if name.is_empty() {
+            return Err(LexerError::InvalidDirective);
         }
-        let lexed_ending = self.current_index;
+        Ok(name.to_string())
+    }
 
-        Ok((lexed_starting, lexed_ending))
+    /// Lexes the character after a `\`: `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'`,
+    /// `\xNN` or `\u{...}`.
+    fn build_escape(&mut self) -> Result<char, LexerError> {
+        let c = self.peek().ok_or(LexerError::InvalidEscape)?;
+        self.advance();
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' | '"' | '\'' => Ok(c),
+            'x' => {
+                let start = self.current_index;
+                for _ in 0..2 {
+                    if self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                        self.advance();
+                    }
+                }
+                let digits = &self.source_code[start..self.current_index];
+                match u8::from_str_radix(digits, 16) {
+                    Ok(byte) if digits.len() == 2 => Ok(char::from(byte)),
+                    _ => Err(LexerError::InvalidEscape),
+                }
+            },
+            'u' => {
+                if self.peek() != Some('{') {
+                    return Err(LexerError::InvalidEscape);
+                }
+                self.advance();
+                let digits = self.take_while(|c| c.is_ascii_hexdigit());
+                if self.peek() != Some('}') || digits.is_empty() || digits.len() > 6 {
+                    return Err(LexerError::InvalidEscape);
+                }
+                self.advance();
+                u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(LexerError::InvalidEscape)
+            },
+            _ => Err(LexerError::InvalidEscape),
+        }
     }
 
     pub fn build_string(&mut self) -> Result<String, LexerError> {
         self.advance();
-        let lexed_starting = self.current_index;
-        while let Some(c) = self.source_code.chars().nth(self.current_index) {
-            if c != '"' {
-                self.advance();
-            } else {
-                break;
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err(LexerError::UnterminatedString),
+                Some('"') => break,
+                Some('\\') => {
+                    self.advance();
+                    string.push(self.build_escape()?);
+                },
+                Some(c) => {
+                    self.advance();
+                    string.push(c);
+                }
             }
         }
         self.advance();
-        let lexed_ending = self.current_index - 1;
-        Ok(self.source_code[lexed_starting..lexed_ending].to_string())
+        Ok(string)
     }
 
-    pub fn advance(&mut self) {
-        if self.current_index < self.source_code.len() {
-            self.current_index += 1;
-            self.column += 1;
+    /// Lexes a character literal such as `'a'` or `'\n'`.
+    pub fn build_char(&mut self) -> Result<char, LexerError> {
+        self.advance();
+        let c = match self.peek() {
+            None | Some('\n') | Some('\'') => return Err(LexerError::UnterminatedChar),
+            Some('\\') => {
+                self.advance();
+                self.build_escape()?
+            },
+            Some(c) => {
+                self.advance();
+                c
+            }
+        };
+        if self.peek() != Some('\'') {
+            return Err(LexerError::UnterminatedChar);
         }
+        self.advance();
+        Ok(c)
     }
 
+    /// Lexes `12`, `-3`, `1.5`, `0xFF`, `0b1010`, `0o17` or a number with a type suffix
+    /// such as `70000u32` or `7i16`. `_` may separate digits. Without a suffix, integers
+    /// are `u16` unless they only fit in `u32`, and negative ones `i16` unless they only
+    /// fit in `i32`.
     pub fn build_number(&mut self) -> Result<NumberType, LexerError> {
-        let lexed_string = self.current_index;
-        let dot_count = 0;
-
-        while let Some(c) = self.source_code.chars().nth(self.current_index) {
-            if c.is_ascii_digit() || (c == '.' && dot_count < 1) {
-                self.advance();
-            } else {
-                break;
-            }
+        let negative = self.peek() == Some('-');
+        if negative {
+            self.advance();
         }
-
-        let lexed_ending = self.current_index;
-
-        if dot_count == 1 {
-            let float = &self.source_code[lexed_string..lexed_ending];
-            if let Ok(parsed_float) = float.parse::<f16>() {
-                Ok(NumberType::f16(parsed_float))
-            } else {
-                Err(LexerError::InvalidNumber)
-            }
+        let rest = &self.source_code[self.current_index..];
+        let radix = if rest.starts_with("0x") || rest.starts_with("0X") {
+            16
+        } else if rest.starts_with("0b") || rest.starts_with("0B") {
+            2
+        } else if rest.starts_with("0o") || rest.starts_with("0O") {
+            8
         } else {
-            let num = &self.source_code[lexed_string..lexed_ending];
-            if let Ok(parsed_number) = num.parse::<u16>() {
-                Ok(NumberType::u16(parsed_number))
-            } else {
-                Err(LexerError::InvalidNumber)
+            10
+        };
+        if radix != 10 {
+            self.advance();
+            self.advance();
+        }
+        let mut dot_count = 0;
+        let num: String = self
+            .take_while(|c| {
+                if radix == 10 && c == '.' && dot_count < 1 {
+                    dot_count += 1;
+                    return true;
+                }
+                c.is_digit(radix) || c == '_'
+            })
+            .chars()
+            .filter(|c| *c != '_')
+            .collect();
+        let suffix = self.take_while(|c| c.is_ascii_alphanumeric());
+        if num.is_empty() || num.starts_with('.') || num.ends_with('.') {
+            return Err(LexerError::InvalidNumber);
+        }
+
+        if dot_count == 1 || (radix == 10 && suffix == "f16") {
+            if !suffix.is_empty() && suffix != "f16" {
+                return Err(LexerError::InvalidNumber);
             }
+            let value: f16 = num.parse().map_err(|_| LexerError::InvalidNumber)?;
+            return Ok(NumberType::f16(if negative { -value } else { value }));
         }
 
+        let magnitude = i64::from_str_radix(&num, radix).map_err(|_| LexerError::InvalidNumber)?;
+        let value = if negative { -magnitude } else { magnitude };
+        let parsed = match suffix {
+            "" if negative => i16::try_from(value)
+                .map(NumberType::i16)
+                .or_else(|_| i32::try_from(value).map(NumberType::i32))
+                .ok(),
+            "" => u16::try_from(value)
+                .map(NumberType::u16)
+                .or_else(|_| u32::try_from(value).map(NumberType::u32))
+                .ok(),
+            "u16" => u16::try_from(value).ok().map(NumberType::u16),
+            "i16" => i16::try_from(value).ok().map(NumberType::i16),
+            "u32" => u32::try_from(value).ok().map(NumberType::u32),
+            "i32" => i32::try_from(value).ok().map(NumberType::i32),
+            _ => None,
+        };
+        parsed.ok_or(LexerError::InvalidNumber)
     }
 
-    pub fn lex(&mut self) -> Result<usize, LexerError> {
-        while self.current_index < self.source_code.len() || self.current_index == 0 {
-            if let Some(c) = self.source_code.chars().nth(self.current_index) {
-                match c {
-                    'a'..='z' | 'A'..='Z' => {
-                        let column = self.current_index;
-                        if let Ok(found_type) = self.build_ident() {
-                            self.tokens.push(Token {
-                                column,
-                                tt: TokenType::InstructionType(found_type),
-                                line_number: self.line_number
-                            });
-                        } else if let Ok((x, y)) = self.build_label() {
-                            self.tokens.push(Token {
-                                column,
-                                tt: TokenType::Label(x, y),
-                                line_number: self.line_number
-                            });
-                        } else {
-                            return Err(LexerError::InvalidLabel)
-                        }
-                    },
-                    '0'..='9' => {
-                        let column = self.column;
-                        let number = self.build_number()?;
-                        self.tokens.push(Token {
-                            column,
-                            tt: TokenType::Number(number),
-                            line_number: self.line_number
-                        });
-                    },
-                    '"' => {
-                        let column = self.column;
-                        let parsed_string = self.build_string()?;
-                        self.tokens.push(Token {
-                            column,
-                            tt: TokenType::String(parsed_string),
-                            line_number: self.line_number
-                        });
-                    },
-                    ';' => {
-                        while let Some(c) = self.source_code.chars().nth(self.current_index) {
-                            if c != '\n' {
-                                self.advance();
-                            } else {
-                                break;
-                            }
-                        }
-                    },
-                    ':' => {
-                        self.tokens.push(Token {
-                            column: self.column,
-                            tt: TokenType::Colon,
-                            line_number: self.line_number
-                        });
+    /// Turns the whole source into tokens. Lexing continues after an error so that every
+    /// problem in the file is reported at once.
+    pub fn lex(&mut self) -> Result<usize, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        while let Some(c) = self.peek() {
+            let (line, column, start) = (self.line_number, self.column, self.current_index);
+            let tt = match c {
+                'a'..='z' | 'A'..='Z' | '_' => Ok(self.build_ident()),
+                '0'..='9' => self.build_number().map(TokenType::Number),
+                '-' if self.source_code[start + 1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                    self.build_number().map(TokenType::Number)
+                },
+                '"' => self.build_string().map(TokenType::String),
+                '\'' => self.build_char().map(|c| TokenType::Number(NumberType::char(c))),
+                '-' if self.source_code[start + 1..].starts_with('>') => {
+                    self.advance();
+                    self.advance();
+                    Ok(TokenType::Arrow)
+                },
+                '.' if self.source_code[start..].starts_with("...") => {
+                    for _ in 0..3 {
                         self.advance();
-                    },
-                    '\n' => {
-                        self.line_number += 1;
-                        self.column = 1;
-                        self.advance();
-                    },
-                    '\0' => {
-                        break;
-                    },
-                    other_chars => {
-                        if IGNORE.iter().any(|&ch| ch == other_chars) {
-                            self.advance();
-                        }
                     }
+                    Ok(TokenType::Ellipsis)
+                },
+                '.' => self.build_directive().map(TokenType::Directive),
+                '(' => {
+                    self.advance();
+                    Ok(TokenType::LParen)
+                },
+                ')' => {
+                    self.advance();
+                    Ok(TokenType::RParen)
+                },
+                ':' => {
+                    self.advance();
+                    Ok(TokenType::Colon)
+                },
+                ',' => {
+                    self.advance();
+                    Ok(TokenType::Comma)
+                },
+                '=' => {
+                    self.advance();
+                    Ok(TokenType::Equals)
+                },
+                ';' => {
+                    self.take_while(|c| c != '\n');
+                    continue;
+                },
+                '\n' => {
+                    self.advance();
+                    self.line_number += 1;
+                    self.column = 1;
+                    continue;
+                },
+                other_chars if IGNORE.contains(&other_chars) => {
+                    self.advance();
+                    continue;
+                },
+                other_chars => {
+                    self.advance();
+                    Err(LexerError::UnexpectedCharacter(other_chars))
+                }
+            };
+            let length = self.source_code[start..self.current_index].chars().count();
+            let span = Span::new(self.file, line, column, length);
+            match tt {
+                Ok(tt) => self.tokens.push(Token { tt, span }),
+                Err(error) => {
+                    diagnostics.push(Diagnostic::new(span, error));
+                    // Skip the rest of the malformed token.
+                    self.take_while(|c| !IGNORE.contains(&c));
                 }
             }
         }
-        if self.tokens.is_empty() {
-            Err(LexerError::EOFWithNoTokens)
-        } else {
+        if diagnostics.is_empty() && self.tokens.is_empty() {
+            diagnostics.push(Diagnostic::new(Span::new(self.file, 1, 1, 0), LexerError::EOFWithNoTokens));
+        }
+        if diagnostics.is_empty() {
             Ok(self.tokens.len())
+        } else {
+            Err(diagnostics)
         }
     }
 }
-
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::diagnostics::Diagnostic;
+use super::lexer::lexer::escape;
+use super::parser::parser::{ASTNode, Spanned};
+
+/// How deeply macro invocations may nest before expansion gives up, which also stops a
+/// macro that invokes itself.
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub enum MacroError {
+    DuplicateMacro(String),
+    UnknownMacro(String),
+    ArgumentCount { name: String, expected: usize, found: usize },
+    RecursionLimit(String),
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateMacro(name) => write!(f, "macro `{}` is defined more than once", name),
+            Self::UnknownMacro(name) => write!(f, "unknown instruction or macro `{}`", name),
+            Self::ArgumentCount { name, expected, found } => {
+                write!(f, "macro `{}` takes {} argument(s) but {} were given", name, expected, found)
+            }
+            Self::RecursionLimit(name) => {
+                write!(f, "expanding `{}` nests more than {} macros deep; is it recursive?", name, MAX_MACRO_DEPTH)
+            }
+        }
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Spanned<ASTNode>>,
+    /// Labels defined in the body, renamed on every expansion so they don't collide.
+    labels: HashSet<String>,
+}
+
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+}
+
+impl Default for MacroExpander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MacroExpander {
+    pub fn new() -> Self {
+        Self {
+            macros: HashMap::new(),
+            expansions: 0,
+        }
+    }
+
+    /// Removes the macro definitions from `nodes` and replaces every invocation with the
+    /// macro's body. Invocations of names that are not macros become labels again.
+    pub fn expand(&mut self, nodes: Vec<Spanned<ASTNode>>) -> Result<Vec<Spanned<ASTNode>>, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let mut program = Vec::new();
+        for Spanned { node, span } in nodes {
+            match node {
+                ASTNode::Macro { name, params, body } => {
+                    if self.macros.contains_key(&name) {
+                        diagnostics.push(Diagnostic::new(span, MacroError::DuplicateMacro(name)));
+                        continue;
+                    }
+                    let labels = body
+                        .iter()
+                        .filter_map(|node| match &node.node {
+                            ASTNode::Label(label) => Some(label.clone()),
+                            ASTNode::MacroCall { name, args } if args.is_empty() => Some(name.clone()),
+                            _ => None,
+                        })
+                        .collect();
+                    self.macros.insert(name, Macro { params, body, labels });
+                }
+                node => program.push(Spanned { node, span }),
+            }
+        }
+        // A colon-less line in a body may be a local label or a call of another macro.
+        let names: HashSet<String> = self.macros.keys().cloned().collect();
+        for definition in self.macros.values_mut() {
+            definition.labels.retain(|label| !names.contains(label));
+        }
+
+        let mut expanded = Vec::with_capacity(program.len());
+        for node in program {
+            if let Err(diagnostic) = self.expand_node(node, 0, &mut expanded) {
+                diagnostics.push(diagnostic);
+            }
+        }
+        if diagnostics.is_empty() {
+            Ok(expanded)
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    fn expand_node(&mut self, node: Spanned<ASTNode>, depth: usize, out: &mut Vec<Spanned<ASTNode>>) -> Result<(), Diagnostic> {
+        let Spanned { node: ASTNode::MacroCall { name, args }, span } = node else {
+            out.push(node);
+            return Ok(());
+        };
+        self.expansions += 1;
+        let expansion = self.expansions;
+        let Some(definition) = self.macros.get(&name) else {
+            if args.is_empty() {
+                out.push(Spanned { node: ASTNode::Label(name), span });
+                return Ok(());
+            }
+            return Err(Diagnostic::new(span, MacroError::UnknownMacro(name)));
+        };
+        if args.len() != definition.params.len() {
+            return Err(Diagnostic::new(span, MacroError::ArgumentCount {
+                name,
+                expected: definition.params.len(),
+                found: args.len(),
+            }));
+        }
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(Diagnostic::new(span, MacroError::RecursionLimit(name)));
+        }
+
+        let instance = Instance {
+            bindings: definition.params.iter().cloned().zip(args).collect(),
+            labels: &definition.labels,
+            suffix: format!("__{}_{}", name, expansion),
+        };
+        let body: Vec<Spanned<ASTNode>> = definition.body.iter().map(|node| instance.substitute(node)).collect();
+        for node in body {
+            self.expand_node(node, depth + 1, out)?;
+        }
+        Ok(())
+    }
+}
+
+/// One expansion of a macro: what its parameters are bound to and how its labels are renamed.
+struct Instance<'a> {
+    bindings: HashMap<String, Spanned<ASTNode>>,
+    labels: &'a HashSet<String>,
+    suffix: String,
+}
+
+impl Instance<'_> {
+    fn rename(&self, label: &str) -> String {
+        format!("{}{}", label, self.suffix)
+    }
+
+    fn substitute(&self, node: &Spanned<ASTNode>) -> Spanned<ASTNode> {
+        let span = node.span;
+        let node = match &node.node {
+            ASTNode::Variable(name) => {
+                if let Some(arg) = self.bindings.get(name) {
+                    return arg.clone();
+                }
+                if self.labels.contains(name) {
+                    ASTNode::Variable(self.rename(name))
+                } else {
+                    ASTNode::Variable(name.clone())
+                }
+            }
+            ASTNode::Label(name) if self.labels.contains(name) => ASTNode::Label(self.rename(name)),
+            ASTNode::Instruction(it, args) => {
+                ASTNode::Instruction(*it, args.iter().map(|arg| self.substitute(arg)).collect())
+            }
+            ASTNode::MacroCall { name, args } if args.is_empty() && self.labels.contains(name) => {
+                ASTNode::Label(self.rename(name))
+            }
+            ASTNode::MacroCall { name, args } => ASTNode::MacroCall {
+                name: name.clone(),
+                args: args.iter().map(|arg| self.substitute(arg)).collect(),
+            },
+            other => other.clone(),
+        };
+        Spanned { node, span }
+    }
+}
+
+/// Writes nodes back out as QASM, as printed by `--expand-macros`.
+pub fn to_source(nodes: &[Spanned<ASTNode>]) -> String {
+    let mut out = String::new();
+    let mut in_data = false;
+    for Spanned { node, .. } in nodes {
+        let data = matches!(node, ASTNode::Data { .. });
+        if data != in_data && !matches!(node, ASTNode::Const { .. } | ASTNode::Global(_) | ASTNode::Extern { .. }) {
+            out.push_str(if data { ".data\n" } else { ".text\n" });
+            in_data = data;
+        }
+        match node {
+            ASTNode::Label(name) => out.push_str(&format!("{}:\n", name)),
+            ASTNode::Instruction(it, args) => {
+                out.push_str("    ");
+                out.push_str(&it.mnemonic());
+                for Spanned { node: arg, .. } in args {
+                    out.push(' ');
+                    out.push_str(&operand_source(arg));
+                }
+                out.push('\n');
+            }
+            ASTNode::Frame { args, locals } => {
+                if !args.is_empty() {
+                    out.push_str(&format!("    .args {}\n", args.join(", ")));
+                }
+                if !locals.is_empty() {
+                    out.push_str(&format!("    .locals {}\n", locals.join(", ")));
+                }
+            }
+            ASTNode::Global(names) => out.push_str(&format!(".global {}\n", names.join(", "))),
+            ASTNode::Const { name, value } => {
+                out.push_str(&format!(".const {} = {}\n", name, operand_source(&value.node)));
+            }
+            ASTNode::Data { name, kind, values } => {
+                let values: Vec<String> = values.iter().map(|value| operand_source(&value.node)).collect();
+                out.push_str(&format!("{}: {} {}\n", name, kind, values.join(", ")));
+            }
+            ASTNode::Extern { name, signature } => out.push_str(&format!(".extern {}{}\n", name, signature)),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn operand_source(node: &ASTNode) -> String {
+    match node {
+        ASTNode::Variable(name) | ASTNode::Label(name) => name.clone(),
+        ASTNode::Number(number) => number.to_string(),
+        ASTNode::StringLiteral(s) => format!("\"{}\"", s.chars().map(|c| escape(c, '"')).collect::<String>()),
+        ASTNode::Signature(signature) => signature.to_string(),
+        _ => String::new(),
+    }
+}
@@ -1,26 +1,100 @@
+#![allow(non_camel_case_types, non_snake_case, clippy::module_inception)]
+
 use std::env;
 use std::process;
-use crate::assembler::Assembler;
+use proton::lib::syscalls::Arch;
+use crate::assembler::{Assembler, AssemblerError};
 mod assembler;
+mod diagnostics;
 mod lexer;
 mod parser;
 mod compiler;
+mod macros;
+mod optimizer;
+
+const USAGE: &str = "Usage: assembler [-c] [-O] [--target <arch>] <input_file> <output_file>\n       assembler --expand-macros <input_file>";
+
+enum Mode {
+    Executable,
+    /// `-c`: a relocatable object file for `linker`.
+    Object,
+    ExpandMacros,
+}
+
+struct Options {
+    mode: Mode,
+    optimize: bool,
+    target: Option<Arch>,
+    input: String,
+    output: String,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        mode: Mode::Executable,
+        optimize: false,
+        target: None,
+        input: String::new(),
+        output: String::new(),
+    };
+    let mut files = Vec::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => options.mode = Mode::Object,
+            "-O" => options.optimize = true,
+            "--expand-macros" => options.mode = Mode::ExpandMacros,
+            "--target" => options.target = Some(Arch::from_name(args.next()?)?),
+            flag if flag.starts_with('-') => return None,
+            file => files.push(file.to_string()),
+        }
+    }
+    match (&options.mode, files.as_slice()) {
+        (Mode::ExpandMacros, [input]) => options.input = input.clone(),
+        (Mode::Executable | Mode::Object, [input, output]) => {
+            options.input = input.clone();
+            options.output = output.clone();
+        }
+        _ => return None,
+    }
+    Some(options)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: assembler <input_file> <output_file>");
+    let Some(options) = parse_args(&args) else {
+        eprintln!("{}", USAGE);
+        eprintln!("Targets: {}", Arch::ALL.map(Arch::name).join(", "));
         process::exit(1);
-    }
-
-
-    let input = &args[1];
-    let output = &args[2];
+    };
+    let (input, output, mode) = (&options.input, options.output.as_str(), options.mode);
 
     match Assembler::new(input, output) {
         Ok(mut assembler) => {
-            assembler.compile()
-        },
+            assembler.optimize = options.optimize;
+            if let Some(target) = options.target {
+                assembler.target = target;
+            }
+            let result = match mode {
+                Mode::Executable => assembler.compile(),
+                Mode::Object => assembler.compile_object(),
+                Mode::ExpandMacros => assembler.expanded_source().map(|source| print!("{}", source)),
+            };
+            match result {
+                Ok(()) => {}
+                Err(AssemblerError::Diagnostics(diagnostics)) => {
+                    for diagnostic in diagnostics.iter() {
+                        eprintln!("{}", assembler.render(diagnostic));
+                    }
+                    eprintln!("QUARMVM: {} error(s), no bytecode written", diagnostics.len());
+                    process::exit(1);
+                }
+                Err(AssemblerError::Write(e)) => {
+                    eprintln!("QUARMVM: Error while writing the bytecode file {}: {}", output, e);
+                    process::exit(1);
+                }
+            }
+        }
         Err(e) => {
             eprintln!("Failed to read source file: {}", e);
             process::exit(1);
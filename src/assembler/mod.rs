@@ -1,4 +1,7 @@
 pub mod assembler;
 pub mod compiler;
+pub mod diagnostics;
 pub mod lexer;
+pub mod macros;
+pub mod optimizer;
 pub mod parser;
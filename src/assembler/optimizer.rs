@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use proton::lib::bytecode::{Program, Relocation, SymbolKind};
+use proton::lib::machine_type::{DEFINE_DUP, Instruction, InstructionType, QuarkVM, StackValues, Word};
+
+/// Upper bound on how often the passes are repeated; one pass often makes room for another.
+const MAX_ROUNDS: usize = 16;
+
+/// Runs the peephole passes over `program` (`assembler -O`) until none of them changes
+/// anything. Labels, exports, relocations and debug entries are moved along with the
+/// instructions they point at.
+pub fn optimize(program: &mut Program) {
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = thread_jumps(program);
+        for pass in [peephole, remove_unreachable] {
+            if let Some(rewrite) = pass(&Context::new(program)) {
+                apply(program, rewrite);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// What the passes need to know about the program besides its instructions.
+struct Context<'a> {
+    instructions: &'a [Instruction],
+    /// Instructions that may be entered other than from the one before them: the entry
+    /// point, labels, exports and jump targets. Only the first instruction of a window
+    /// may be one of these.
+    targets: HashSet<usize>,
+    /// The relocation on operand 0 of each instruction, in object files.
+    relocations: HashMap<usize, &'a Relocation>,
+}
+
+impl<'a> Context<'a> {
+    fn new(program: &'a Program) -> Self {
+        let relocations: HashMap<usize, &Relocation> = program
+            .link
+            .iter()
+            .flat_map(|link| link.relocations.iter())
+            .filter(|relocation| relocation.operand == 0)
+            .map(|relocation| (relocation.instruction as usize, relocation))
+            .collect();
+        let mut cx = Self { instructions: &program.instructions, targets: HashSet::from([0]), relocations };
+        let jumps: Vec<usize> = (0..cx.instructions.len()).filter_map(|index| cx.code_target(index)).collect();
+        cx.targets.extend(jumps);
+        cx.targets.extend(program.symbols.iter().map(|symbol| symbol.index as usize));
+        cx.targets.extend(
+            program
+                .link
+                .iter()
+                .flat_map(|link| link.exports.iter())
+                .filter(|export| export.kind == SymbolKind::Code)
+                .map(|export| export.value as usize),
+        );
+        cx
+    }
+
+    /// Where a jump or call at `index` goes, unless it is an import the linker fills in.
+    fn code_target(&self, index: usize) -> Option<usize> {
+        let instruction = &self.instructions[index];
+        if !instruction.tt.takes_code_address() || self.relocations.get(&index).is_some_and(|r| r.symbol.is_some()) {
+            return None;
+        }
+        match operand(instruction) {
+            Some(Word::U16(target)) => Some(*target as usize),
+            _ => None,
+        }
+    }
+
+    /// The `len` instructions starting at `index`, if nothing jumps into the middle of them.
+    fn window(&self, index: usize, len: usize) -> Option<&'a [Instruction]> {
+        let window = self.instructions.get(index..index + len)?;
+        (index + 1..index + len).all(|i| !self.targets.contains(&i)).then_some(window)
+    }
+
+    fn relocated(&self, index: usize) -> bool {
+        self.relocations.contains_key(&index)
+    }
+
+    /// Whether operand 0 of both instructions refers to the same symbol once linked.
+    fn same_relocation(&self, a: usize, b: usize) -> bool {
+        match (self.relocations.get(&a), self.relocations.get(&b)) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.kind == b.kind && a.symbol == b.symbol,
+            _ => false,
+        }
+    }
+}
+
+/// A new instruction with the index of the one it stands in for, which keeps its debug
+/// entry and relocations.
+type Placed = (Instruction, Option<usize>);
+
+/// The instructions a pass produced.
+struct Rewrite {
+    instructions: Vec<Placed>,
+    /// For every old index, and one past the end, the new index execution continues at.
+    map: Vec<usize>,
+}
+
+fn operand(instruction: &Instruction) -> Option<&Word> {
+    instruction.values.as_ref()?.first()
+}
+
+fn slot(instruction: &Instruction) -> Option<u16> {
+    match operand(instruction) {
+        Some(Word::U16(slot)) => Some(*slot),
+        _ => None,
+    }
+}
+
+/// Evaluates `PUSH second; PUSH top; op` the way the VM would. Nothing is folded if the
+/// VM would fault, so the fault still happens at run time.
+fn fold(op: InstructionType, second: &Word, top: &Word) -> Option<Word> {
+    let result = QuarkVM::arithmetic(op, StackValues::from(top), StackValues::from(second)).ok()?;
+    Word::try_from(result).ok()
+}
+
+/// Finds a pattern starting at `index`, returning its replacement and how many
+/// instructions it replaces.
+fn replacement(cx: &Context, index: usize) -> Option<(Vec<Placed>, usize)> {
+    use InstructionType::*;
+    if matches!(cx.instructions[index].tt, INST_NOOP) {
+        return Some((vec![], 1));
+    }
+    if let Some([push, pop]) = cx.window(index, 2)
+        && matches!((push.tt, pop.tt), (INST_PUSH, INST_POP))
+        && !cx.relocated(index)
+    {
+        return Some((vec![], 2));
+    }
+    if let Some([store, load]) = cx.window(index, 2)
+        && matches!((store.tt, load.tt), (INST_STORE, INST_LOAD))
+        && slot(store).is_some()
+        && slot(store) == slot(load)
+        && cx.same_relocation(index, index + 1)
+    {
+        return Some((vec![(DEFINE_DUP(), None), (store.clone(), Some(index))], 2));
+    }
+    if let Some([a, b, op]) = cx.window(index, 3)
+        && matches!((a.tt, b.tt), (INST_PUSH, INST_PUSH))
+        && !cx.relocated(index)
+        && !cx.relocated(index + 1)
+        && let (Some(second), Some(top)) = (operand(a), operand(b))
+        && let Some(word) = fold(op.tt, second, top)
+    {
+        let push = Instruction { tt: INST_PUSH, values: Some(vec![word]) };
+        return Some((vec![(push, Some(index))], 3));
+    }
+    None
+}
+
+/// Folds constant arithmetic and removes `NOOP`s, `PUSH`/`POP` pairs and the reload in
+/// `STORE x; LOAD x`.
+fn peephole(cx: &Context) -> Option<Rewrite> {
+    let len = cx.instructions.len();
+    let mut rewrite = Rewrite { instructions: Vec::with_capacity(len), map: vec![0; len + 1] };
+    let mut changed = false;
+    let mut index = 0;
+    while index < len {
+        let (replacement, consumed) = match replacement(cx, index) {
+            Some(found) => {
+                changed = true;
+                found
+            }
+            None => (vec![(cx.instructions[index].clone(), Some(index))], 1),
+        };
+        for old in index..index + consumed {
+            rewrite.map[old] = rewrite.instructions.len();
+        }
+        rewrite.instructions.extend(replacement);
+        index += consumed;
+    }
+    rewrite.map[len] = rewrite.instructions.len();
+    changed.then_some(rewrite)
+}
+
+/// Drops instructions after a `RET` or `JMP` that nothing jumps to.
+fn remove_unreachable(cx: &Context) -> Option<Rewrite> {
+    let len = cx.instructions.len();
+    let mut rewrite = Rewrite { instructions: Vec::with_capacity(len), map: vec![0; len + 1] };
+    let mut reachable = true;
+    for (index, instruction) in cx.instructions.iter().enumerate() {
+        reachable |= cx.targets.contains(&index);
+        rewrite.map[index] = rewrite.instructions.len();
+        if reachable {
+            rewrite.instructions.push((instruction.clone(), Some(index)));
+        }
+        if matches!(instruction.tt, InstructionType::INST_RET | InstructionType::INST_JMP) {
+            reachable = false;
+        }
+    }
+    rewrite.map[len] = rewrite.instructions.len();
+    (rewrite.instructions.len() < len).then_some(rewrite)
+}
+
+/// Points jumps and calls whose target is a `JMP` straight at where that `JMP` goes.
+fn thread_jumps(program: &mut Program) -> bool {
+    let cx = Context::new(program);
+    let mut threaded = Vec::new();
+    for index in 0..cx.instructions.len() {
+        let Some(start) = cx.code_target(index) else { continue };
+        let mut target = start;
+        let mut seen = HashSet::new();
+        while let Some(next) = cx
+            .instructions
+            .get(target)
+            .filter(|instruction| matches!(instruction.tt, InstructionType::INST_JMP))
+            .and_then(|_| cx.code_target(target))
+        {
+            // A cycle of jumps never gets anywhere; leave it as written.
+            if !seen.insert(target) {
+                break;
+            }
+            target = next;
+        }
+        if target != start {
+            threaded.push((index, target as u16));
+        }
+    }
+    for (index, target) in threaded.iter() {
+        program.instructions[*index].values = Some(vec![Word::U16(*target)]);
+    }
+    !threaded.is_empty()
+}
+
+/// Installs the rewritten instructions and moves everything that refers to an instruction
+/// index along with them.
+fn apply(program: &mut Program, rewrite: Rewrite) {
+    let Rewrite { instructions, map } = rewrite;
+    let remap = |index: u16| map.get(index as usize).map_or(index, |&new| new as u16);
+    let moved: HashMap<u16, u16> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(new, (_, origin))| origin.map(|old| (old as u16, new as u16)))
+        .collect();
+
+    let mut imports = HashSet::new();
+    if let Some(link) = program.link.as_mut() {
+        link.relocations.retain_mut(|relocation| match moved.get(&relocation.instruction) {
+            Some(&new) => {
+                relocation.instruction = new;
+                true
+            }
+            None => false,
+        });
+        imports.extend(
+            link.relocations
+                .iter()
+                .filter(|relocation| relocation.kind == SymbolKind::Code && relocation.symbol.is_some())
+                .map(|relocation| relocation.instruction as usize),
+        );
+        for export in link.exports.iter_mut().filter(|export| export.kind == SymbolKind::Code) {
+            export.value = remap(export.value);
+        }
+    }
+
+    program.instructions = instructions.into_iter().map(|(instruction, _)| instruction).collect();
+    for (index, instruction) in program.instructions.iter_mut().enumerate() {
+        if instruction.tt.takes_code_address()
+            && !imports.contains(&index)
+            && let Some(Word::U16(target)) = instruction.values.as_mut().and_then(|values| values.first_mut())
+        {
+            *target = remap(*target);
+        }
+    }
+    for symbol in program.symbols.iter_mut() {
+        symbol.index = remap(symbol.index);
+    }
+    program.debug_info.entries.retain_mut(|entry| match moved.get(&entry.instruction) {
+        Some(&new) => {
+            entry.instruction = new;
+            true
+        }
+        None => false,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use proton::lib::bytecode::{DebugEntry, Program, Symbol};
+    use proton::lib::machine_type::{DEFINE_CALL, DEFINE_JMP, DEFINE_POP, DEFINE_PRINT, DEFINE_PUSH, DEFINE_RET, Instruction, Word};
+    use super::optimize;
+
+    fn program(instructions: Vec<Instruction>, main: u16) -> Program {
+        Program {
+            symbols: vec![Symbol { name: "main".to_string(), index: main }],
+            ..Program::new(instructions)
+        }
+    }
+
+    /// The instructions as the disassembler would write them without labels.
+    fn listing(program: &Program) -> Vec<String> {
+        program
+            .instructions
+            .iter()
+            .map(|instruction| match instruction.values.as_deref() {
+                Some([Word::U16(value)]) => format!("{} {}", instruction.tt.mnemonic(), value),
+                None => instruction.tt.mnemonic(),
+                _ => panic!("unexpected operands on {:?}", instruction),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn jumps_follow_instructions_moved_by_the_peephole_pass() {
+        let mut program = program(
+            vec![DEFINE_CALL(4), DEFINE_RET(), DEFINE_PUSH(7), DEFINE_POP(), DEFINE_PUSH(1), DEFINE_PRINT(), DEFINE_JMP(4)],
+            4,
+        );
+        program.debug_info.entries.push(DebugEntry { instruction: 5, file: 0, line: 6, column: 5 });
+        optimize(&mut program);
+        assert_eq!(listing(&program), ["CALL 2", "RET", "PUSH 1", "PRINT", "JMP 2"]);
+        assert_eq!(program.symbol("main"), Some(2));
+        assert_eq!(program.debug_info.entries[0].instruction, 3);
+    }
+
+    #[test]
+    fn jumps_over_removed_unreachable_code_are_remapped() {
+        let mut program = program(
+            vec![DEFINE_CALL(2), DEFINE_RET(), DEFINE_PUSH(1), DEFINE_JMP(6), DEFINE_PUSH(9), DEFINE_PRINT(), DEFINE_PRINT(), DEFINE_RET()],
+            2,
+        );
+        optimize(&mut program);
+        assert_eq!(listing(&program), ["CALL 2", "RET", "PUSH 1", "JMP 4", "PRINT", "RET"]);
+    }
+
+    #[test]
+    fn a_jump_to_a_jump_is_threaded_and_the_middle_one_dropped() {
+        let mut program = program(
+            vec![DEFINE_CALL(2), DEFINE_RET(), DEFINE_PUSH(1), DEFINE_JMP(5), DEFINE_PRINT(), DEFINE_JMP(7), DEFINE_PUSH(2), DEFINE_PRINT(), DEFINE_RET()],
+            2,
+        );
+        optimize(&mut program);
+        assert_eq!(listing(&program), ["CALL 2", "RET", "PUSH 1", "JMP 4", "PRINT", "RET"]);
+    }
+}
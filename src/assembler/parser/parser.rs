@@ -1,39 +1,137 @@
+use crate::diagnostics::{Diagnostic, Span};
 use crate::lexer::lexer::{TokenType, NumberType, Token};
+use proton::lib::ffi::{FfiType, Signature};
 use proton::lib::machine_type::{ InstructionType };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum ParserError {
     UnexpectedEOF,
     UnexpectedToken,
     InvalidInstructionFormat,
+    UnknownDirective(String),
+    MissingOperands { instruction: String, expected: usize },
+    MissingMacroName,
+    NestedMacro,
+    UnterminatedMacro(String),
+    UnexpectedEndm,
+    InvalidConst,
+    DataOutsideSection,
+    CodeInDataSection,
+    SectionInMacro,
+    InvalidSignature,
+    UnknownFfiType(String),
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEOF => write!(f, "unexpected end of file"),
+            Self::UnexpectedToken => write!(f, "expected an instruction, a label or a directive"),
+            Self::InvalidInstructionFormat => write!(f, "invalid operand"),
+            Self::UnknownDirective(name) => write!(f, "unknown directive `.{}`", name),
+            Self::MissingOperands { instruction, expected } => {
+                write!(f, "`{}` expects {} operand(s)", instruction, expected)
+            }
+            Self::MissingMacroName => write!(f, "expected a macro name after `.macro`"),
+            Self::NestedMacro => write!(f, "macros cannot be defined inside another macro"),
+            Self::UnterminatedMacro(name) => write!(f, "macro `{}` has no `.endm`", name),
+            Self::UnexpectedEndm => write!(f, "`.endm` without a matching `.macro`"),
+            Self::InvalidConst => write!(f, "expected `.const NAME = value` with a number or string value"),
+            Self::DataOutsideSection => write!(f, "`.string`, `.array` and `.zero` belong in the `.data` section"),
+            Self::CodeInDataSection => write!(f, "expected `name: .string`, `.array` or `.zero`; use `.text` to go back to code"),
+            Self::SectionInMacro => write!(f, "`.data` and `.text` cannot be used inside a macro"),
+            Self::InvalidSignature => write!(f, "expected a signature like `(i32, ptr, ...) -> f64`"),
+            Self::UnknownFfiType(name) => {
+                let types: Vec<&str> = FfiType::ALL.iter().map(|ty| ty.name()).collect();
+                write!(f, "unknown type `{}`, expected one of {}", name, types.join(", "))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
-    Instruction(InstructionType, Vec<ASTNode>),
+    Instruction(InstructionType, Vec<Spanned<ASTNode>>),
     Variable(String),
     Label(String),
     Number(NumberType),
     StringLiteral(String),
+    /// `.args` / `.locals` declarations at the top of a function, compiled to one `ENTER`.
+    Frame { args: Vec<String>, locals: Vec<String> },
+    /// A `.macro NAME params ... .endm` definition.
+    Macro { name: String, params: Vec<String>, body: Vec<Spanned<ASTNode>> },
+    /// A name at the start of a line without a colon: a macro invocation, or a label if
+    /// no macro has that name and it has no arguments.
+    MacroCall { name: String, args: Vec<Spanned<ASTNode>> },
+    /// `.global` names: exported if this file defines them, imported otherwise.
+    Global(Vec<String>),
+    /// `.const NAME = value`, substituted wherever `NAME` is used as an operand.
+    Const { name: String, value: Box<Spanned<ASTNode>> },
+    /// A labelled item in the `.data` section.
+    Data { name: String, kind: DataKind, values: Vec<Spanned<ASTNode>> },
+    /// `.extern NAME(types) -> type`, the signature `DLL_CALL NAME` calls with.
+    Extern { name: String, signature: Signature },
+    /// A signature written out as the operand of `DLL_CALL`.
+    Signature(Signature),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataKind {
+    /// `.string "text"`: the characters followed by a zero cell.
+    String,
+    /// `.array a, b, ...`: one cell per value.
+    Array,
+    /// `.zero n`: `n` cells set to zero.
+    Zero,
+}
+
+impl fmt::Display for DataKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String => write!(f, ".string"),
+            Self::Array => write!(f, ".array"),
+            Self::Zero => write!(f, ".zero"),
+        }
+    }
+}
+
+/// A node together with the source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
 }
 
-pub struct Parser<'a> {
+pub struct Parser {
     tokens: Vec<Token>,
     current_index: usize,
-    source_code: &'a str,
     instruction_arg_count: HashMap<&'static str, usize>,
+    /// Whether the parser is between `.data` and `.text`.
+    in_data: bool,
+    /// The source files whose last section directive was `.data`. Sections are tracked
+    /// per file, so an included file starts in `.text` and leaves its includer's section alone.
+    data_files: HashSet<usize>,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(source_code: &'a str) -> Self {
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
         let instruction_arg_count = Self::build_instruction_arg_map();
 
         Self {
             tokens: vec![],
             current_index: 0,
-            source_code,
             instruction_arg_count,
+            in_data: false,
+            data_files: HashSet::new(),
         }
     }
 
@@ -75,40 +173,92 @@ impl<'a> Parser<'a> {
         map.insert("STD_SYSCALL", 1);
         map.insert("DLL_LOAD", 0);
         map.insert("DLL_CALL", 1);
+        map.insert("HOST_CALL", 1);
+        map.insert("ENTER", 2);
+        map.insert("LOAD_ARG", 1);
+        map.insert("STORE_ARG", 1);
+        map.insert("LOAD_LOCAL", 1);
+        map.insert("STORE_LOCAL", 1);
+        map.insert("FREE", 0);
+        map.insert("FREE_RAW", 0);
+        map.insert("LT", 0);
+        map.insert("GT", 0);
+        map.insert("LE", 0);
+        map.insert("GE", 0);
+        map.insert("EQ", 0);
+        map.insert("JMP", 1);
+        map.insert("JMPLT", 1);
+        map.insert("JMPGT", 1);
 
         map
     }
 
-    pub fn parse(&mut self, tokens: Vec<Token>) -> Result<Vec<ASTNode>, ParserError> {
-        let mut nodes = Vec::new();
+    /// Parses every token. After an error the rest of that line is skipped and parsing
+    /// resumes on the next one, so all errors are reported together.
+    pub fn parse(&mut self, tokens: Vec<Token>) -> Result<Vec<Spanned<ASTNode>>, Vec<Diagnostic>> {
+        let mut nodes: Vec<Spanned<ASTNode>> = Vec::new();
+        let mut diagnostics = Vec::new();
         self.tokens = tokens;
 
         while self.current_index < self.tokens.len() {
-            // dbg!(&self.tokens[self.current_index]);
+            let Token { tt, span } = &self.tokens[self.current_index];
+            if let TokenType::Directive(directive) = tt
+                && (directive == "data" || directive == "text")
+            {
+                if directive == "data" {
+                    self.data_files.insert(span.file);
+                } else {
+                    self.data_files.remove(&span.file);
+                }
+                self.advance();
+                continue;
+            }
+            self.in_data = self.data_files.contains(&span.file);
             match self.parse_instruction() {
+                Ok(Spanned { node: ASTNode::Frame { args, locals }, span }) => {
+                    // Consecutive `.args` and `.locals` lines describe the same frame.
+                    if let Some(Spanned { node: ASTNode::Frame { args: prev_args, locals: prev_locals }, .. }) = nodes.last_mut() {
+                        prev_args.extend(args);
+                        prev_locals.extend(locals);
+                    } else {
+                        nodes.push(Spanned { node: ASTNode::Frame { args, locals }, span });
+                    }
+                }
                 Ok(node) => nodes.push(node),
-                Err(ParserError::UnexpectedEOF) => break,
-                Err(err) => return Err(err),
+                Err(diagnostic) => {
+                    let span = diagnostic.span;
+                    diagnostics.push(diagnostic);
+                    while self.current_index < self.tokens.len() && self.tokens[self.current_index].span.same_line(&span) {
+                        self.advance();
+                    }
+                }
             }
         }
 
-        Ok(nodes)
-    }
-
-    fn parse_instruction(&mut self) -> Result<ASTNode, ParserError> {
-        if self.current_index >= self.tokens.len() {
-            return Err(ParserError::UnexpectedEOF);
+        if diagnostics.is_empty() {
+            Ok(nodes)
+        } else {
+            Err(diagnostics)
         }
+    }
 
-        let token = &self.tokens[self.current_index];
+    fn parse_instruction(&mut self) -> Result<Spanned<ASTNode>, Diagnostic> {
+        let token = self.tokens[self.current_index].clone();
+        let span = token.span;
 
         match token.tt {
+            TokenType::Label(name) if self.in_data => {
+                self.advance();
+                self.parse_data_item(name, span)
+            }
+            TokenType::InstructionType(_) if self.in_data => {
+                Err(Diagnostic::new(span, ParserError::CodeInDataSection))
+            }
             TokenType::InstructionType(inst_type) => {
-                let inst_str = format!("{:?}", inst_type);
-                let inst_str = inst_str.split_at(5).1;
+                let inst_str = inst_type.mnemonic();
                 let expected_args = self
                     .instruction_arg_count
-                    .get(inst_str)
+                    .get(inst_str.as_str())
                     .copied()
                     .unwrap_or(0);
 
@@ -116,64 +266,274 @@ impl<'a> Parser<'a> {
 
                 let mut args = Vec::new();
                 for _ in 0..expected_args {
-                    if self.current_index >= self.tokens.len() {
-                        return Err(ParserError::UnexpectedEOF);
-                    }
-                    match &self.tokens[self.current_index].tt {
-                        TokenType::Number(num) => {
-                            args.push(ASTNode::Number(*num));
-                            self.advance();
-                        }
-                        TokenType::Label(start, end) => {
-                            let label_name =
-                                self.extract_label_name(*start, *end).ok_or(ParserError::UnexpectedToken)?;
-                            // dbg!(&label_name);
-                            println!("LABEL: {:?}", label_name);
-                            args.push(ASTNode::Variable(label_name));
-                            self.advance();
-                        }
-                        TokenType::String(s) => {
-                            let string_value = s;
-                            args.push(ASTNode::StringLiteral(string_value.clone()));
-                            self.advance();
+                    let arg = match self.tokens.get(self.current_index) {
+                        Some(arg) if arg.span.same_line(&span) => arg,
+                        _ => {
+                            return Err(Diagnostic::new(
+                                span,
+                                ParserError::MissingOperands { instruction: inst_str, expected: expected_args },
+                            ))
                         }
-                        _ => return Err(ParserError::InvalidInstructionFormat),
+                    };
+                    if matches!(arg.tt, TokenType::LParen) && matches!(inst_type, InstructionType::INST_DLL_CALL) {
+                        let arg_span = arg.span;
+                        let signature = self.parse_signature(span)?;
+                        args.push(Spanned { node: ASTNode::Signature(signature), span: arg_span });
+                        continue;
                     }
+                    let node = match &arg.tt {
+                        TokenType::Number(num) => ASTNode::Number(*num),
+                        TokenType::Label(label_name) => ASTNode::Variable(label_name.clone()),
+                        TokenType::String(s) => ASTNode::StringLiteral(s.clone()),
+                        _ => return Err(Diagnostic::new(arg.span, ParserError::InvalidInstructionFormat)),
+                    };
+                    args.push(Spanned { node, span: arg.span });
+                    self.advance();
+                }
+                // `SYSCALL <args> <number>` spells out the syscall number, as the disassembler prints
+                // it, and `DLL_CALL <signature> <count>` passes `count` arguments to a `...`.
+                if matches!(inst_type, InstructionType::INST_SYSCALL | InstructionType::INST_DLL_CALL)
+                    && let Some(Token { tt: TokenType::Number(num), span: arg_span }) = self.tokens.get(self.current_index)
+                    && arg_span.same_line(&span)
+                {
+                    args.push(Spanned { node: ASTNode::Number(*num), span: *arg_span });
+                    self.advance();
                 }
 
-                Ok(ASTNode::Instruction(inst_type, args))
+                Ok(Spanned { node: ASTNode::Instruction(inst_type, args), span })
             }
-            TokenType::Label(start, end) => {
-                let label_name = self.extract_label_name(start, end).ok_or(ParserError::UnexpectedToken)?;
+            TokenType::Label(label_name) => {
                 self.advance();
-                if let TokenType::Colon = self.tokens[self.current_index].tt {
+                if let Some(Token { tt: TokenType::Colon, .. }) = self.tokens.get(self.current_index) {
                     self.advance();
+                    return Ok(Spanned { node: ASTNode::Label(label_name), span });
                 }
-                Ok(ASTNode::Label(label_name))
+                let args = self.parse_operand_list(span)?;
+                Ok(Spanned { node: ASTNode::MacroCall { name: label_name, args }, span })
             }
-            _ => {
-                dbg!("WTF", token, self.tokens.clone());
-                Err(ParserError::UnexpectedToken)
-            },
+            TokenType::Directive(directive) => {
+                self.advance();
+                match directive.as_str() {
+                    "const" => return self.parse_const(span),
+                    "extern" => return self.parse_extern(span),
+                    "string" | "array" | "zero" => return Err(Diagnostic::new(span, ParserError::DataOutsideSection)),
+                    "data" | "text" => return Err(Diagnostic::new(span, ParserError::SectionInMacro)),
+                    _ => {}
+                }
+                let names = self.parse_name_list(span)?;
+                let node = match directive.as_str() {
+                    "args" => ASTNode::Frame { args: names, locals: vec![] },
+                    "locals" => ASTNode::Frame { args: vec![], locals: names },
+                    "global" => ASTNode::Global(names),
+                    "macro" => return self.parse_macro(span, names),
+                    "endm" => return Err(Diagnostic::new(span, ParserError::UnexpectedEndm)),
+                    _ => return Err(Diagnostic::new(span, ParserError::UnknownDirective(directive))),
+                };
+                Ok(Spanned { node, span })
+            }
+            _ => Err(Diagnostic::new(span, ParserError::UnexpectedToken)),
         }
     }
 
-    fn advance(&mut self) {
-        if self.current_index < self.tokens.len() {
-            self.current_index += 1;
+    /// Parses the body of a macro up to its `.endm`. `names` is the macro name followed by
+    /// its parameters. After an error the rest of the body is skipped.
+    fn parse_macro(&mut self, span: Span, mut names: Vec<String>) -> Result<Spanned<ASTNode>, Diagnostic> {
+        if names.is_empty() {
+            return Err(Diagnostic::new(span, ParserError::MissingMacroName));
+        }
+        let name = names.remove(0);
+        let mut body = Vec::new();
+        loop {
+            let Some(token) = self.tokens.get(self.current_index) else {
+                return Err(Diagnostic::new(span, ParserError::UnterminatedMacro(name)));
+            };
+            let result = match &token.tt {
+                TokenType::Directive(directive) if directive == "endm" => {
+                    self.advance();
+                    return Ok(Spanned { node: ASTNode::Macro { name, params: names, body }, span });
+                }
+                TokenType::Directive(directive) if directive == "macro" => {
+                    Err(Diagnostic::new(token.span, ParserError::NestedMacro))
+                }
+                _ => self.parse_instruction(),
+            };
+            match result {
+                Ok(node) => body.push(node),
+                Err(diagnostic) => {
+                    while let Some(token) = self.tokens.get(self.current_index) {
+                        let end = matches!(&token.tt, TokenType::Directive(directive) if directive == "endm");
+                        self.advance();
+                        if end {
+                            break;
+                        }
+                    }
+                    return Err(diagnostic);
+                }
+            }
         }
     }
 
-    fn extract_label_name(&self, start: usize, end: usize) -> Option<String> {
-        let label_name = &self.source_code[start..end];
-        if label_name.is_empty() {
-            None
-        } else {
-            Some(label_name.to_string())
+    /// Parses `.const NAME = value`; the directive itself has been consumed.
+    fn parse_const(&mut self, span: Span) -> Result<Spanned<ASTNode>, Diagnostic> {
+        let mut operands = Vec::new();
+        while let Some(token) = self.tokens.get(self.current_index) {
+            if !token.span.same_line(&span) {
+                break;
+            }
+            operands.push(token.clone());
+            self.advance();
+        }
+        match operands.as_slice() {
+            [
+                Token { tt: TokenType::Label(name), .. },
+                Token { tt: TokenType::Equals, .. },
+                value,
+            ] => {
+                let node = match &value.tt {
+                    TokenType::Number(num) => ASTNode::Number(*num),
+                    TokenType::String(s) => ASTNode::StringLiteral(s.clone()),
+                    _ => return Err(Diagnostic::new(value.span, ParserError::InvalidConst)),
+                };
+                let value = Box::new(Spanned { node, span: value.span });
+                Ok(Spanned { node: ASTNode::Const { name: name.clone(), value }, span })
+            }
+            _ => Err(Diagnostic::new(span, ParserError::InvalidConst)),
+        }
+    }
+
+    /// Parses `.extern NAME(types) -> type`; the directive itself has been consumed.
+    fn parse_extern(&mut self, span: Span) -> Result<Spanned<ASTNode>, Diagnostic> {
+        let name = match self.tokens.get(self.current_index) {
+            Some(Token { tt: TokenType::Label(name), span: name_span }) if name_span.same_line(&span) => name.clone(),
+            _ => return Err(Diagnostic::new(span, ParserError::InvalidSignature)),
+        };
+        self.advance();
+        let signature = self.parse_signature(span)?;
+        Ok(Spanned { node: ASTNode::Extern { name, signature }, span })
+    }
+
+    /// Parses `(types) -> type` on the same line as `line`. The arguments may end in
+    /// `...`, and a missing `-> type` means the function returns `void`.
+    fn parse_signature(&mut self, line: Span) -> Result<Signature, Diagnostic> {
+        let next = |parser: &mut Self| {
+            let token = parser
+                .tokens
+                .get(parser.current_index)
+                .filter(|token| token.span.same_line(&line))
+                .cloned()
+                .ok_or_else(|| Diagnostic::new(line, ParserError::InvalidSignature))?;
+            parser.advance();
+            Ok::<Token, Diagnostic>(token)
+        };
+        let ffi_type = |name: &str, span: Span| {
+            FfiType::from_name(name).ok_or_else(|| Diagnostic::new(span, ParserError::UnknownFfiType(name.to_string())))
+        };
+
+        let open = next(self)?;
+        if !matches!(open.tt, TokenType::LParen) {
+            return Err(Diagnostic::new(open.span, ParserError::InvalidSignature));
+        }
+        let mut signature = Signature { args: vec![], variadic: false, ret: FfiType::Void };
+        loop {
+            let token = next(self)?;
+            match token.tt {
+                TokenType::RParen => break,
+                TokenType::Comma => {}
+                TokenType::Ellipsis if !signature.variadic => signature.variadic = true,
+                TokenType::Label(name) if !signature.variadic => match ffi_type(&name, token.span)? {
+                    FfiType::Void => return Err(Diagnostic::new(token.span, ParserError::InvalidSignature)),
+                    ty => signature.args.push(ty),
+                },
+                _ => return Err(Diagnostic::new(token.span, ParserError::InvalidSignature)),
+            }
+        }
+        if let Some(Token { tt: TokenType::Arrow, span }) = self.tokens.get(self.current_index)
+            && span.same_line(&line)
+        {
+            self.advance();
+            signature.ret = match next(self)? {
+                Token { tt: TokenType::Label(name), span } => ffi_type(&name, span)?,
+                token => return Err(Diagnostic::new(token.span, ParserError::InvalidSignature)),
+            };
         }
+        Ok(signature)
     }
 
-    fn extract_string(&self) -> String {
-        "STRING_VALUE".to_string()
+    /// Parses the rest of `name: .string "text"`, `name: .array 1, 2` or `name: .zero 16`
+    /// after its label.
+    fn parse_data_item(&mut self, name: String, span: Span) -> Result<Spanned<ASTNode>, Diagnostic> {
+        if let Some(Token { tt: TokenType::Colon, .. }) = self.tokens.get(self.current_index) {
+            self.advance();
+        }
+        let kind = match self.tokens.get(self.current_index) {
+            Some(Token { tt: TokenType::Directive(directive), span: directive_span }) if directive_span.same_line(&span) => {
+                match directive.as_str() {
+                    "string" => DataKind::String,
+                    "array" => DataKind::Array,
+                    "zero" => DataKind::Zero,
+                    _ => return Err(Diagnostic::new(*directive_span, ParserError::UnknownDirective(directive.clone()))),
+                }
+            }
+            _ => return Err(Diagnostic::new(span, ParserError::CodeInDataSection)),
+        };
+        let directive_span = self.tokens[self.current_index].span;
+        self.advance();
+        let values = self.parse_operand_list(span)?;
+        let well_formed = match kind {
+            DataKind::String => matches!(values.as_slice(), [Spanned { node: ASTNode::StringLiteral(_) | ASTNode::Variable(_), .. }]),
+            DataKind::Array => !values.is_empty(),
+            DataKind::Zero => values.len() == 1,
+        };
+        if !well_formed {
+            return Err(Diagnostic::new(directive_span, ParserError::InvalidInstructionFormat));
+        }
+        Ok(Spanned { node: ASTNode::Data { name, kind, values }, span })
+    }
+
+    /// Collects the comma separated operands that follow on the same line, as taken by
+    /// macro invocations and data items.
+    fn parse_operand_list(&mut self, call: Span) -> Result<Vec<Spanned<ASTNode>>, Diagnostic> {
+        let mut args = Vec::new();
+        while let Some(token) = self.tokens.get(self.current_index) {
+            if !token.span.same_line(&call) {
+                break;
+            }
+            let node = match &token.tt {
+                TokenType::Number(num) => ASTNode::Number(*num),
+                TokenType::Label(name) => ASTNode::Variable(name.clone()),
+                TokenType::String(s) => ASTNode::StringLiteral(s.clone()),
+                TokenType::Comma => {
+                    self.advance();
+                    continue;
+                }
+                _ => return Err(Diagnostic::new(token.span, ParserError::InvalidInstructionFormat)),
+            };
+            args.push(Spanned { node, span: token.span });
+            self.advance();
+        }
+        Ok(args)
+    }
+
+    /// Collects the comma separated names that follow a directive on the same line.
+    fn parse_name_list(&mut self, directive: Span) -> Result<Vec<String>, Diagnostic> {
+        let mut names = Vec::new();
+        while let Some(token) = self.tokens.get(self.current_index) {
+            if !token.span.same_line(&directive) {
+                break;
+            }
+            match &token.tt {
+                TokenType::Label(name) => names.push(name.clone()),
+                TokenType::Comma => {}
+                _ => return Err(Diagnostic::new(token.span, ParserError::InvalidInstructionFormat)),
+            }
+            self.advance();
+        }
+        Ok(names)
+    }
+
+    fn advance(&mut self) {
+        if self.current_index < self.tokens.len() {
+            self.current_index += 1;
+        }
     }
 }
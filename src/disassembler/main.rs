@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process;
+
+use proton::lib::bytecode::{ByteCodeCompiler, DataInit, DataItem, Program};
+use proton::lib::ffi::Signature;
+use proton::lib::machine_type::{ENTRY_PROLOGUE_LEN, Instruction, InstructionType, Word};
+use proton::lib::syscalls::Arch;
+
+/// Whether the program starts with the `CALL main; RET` the assembler prepends to every program.
+fn has_entry_prologue(program: &Program) -> bool {
+    match program.instructions.get(..ENTRY_PROLOGUE_LEN) {
+        Some([Instruction { tt: InstructionType::INST_CALL, values: Some(values) }, Instruction { tt: InstructionType::INST_RET, .. }]) => {
+            match (values.first(), program.symbol("main")) {
+                (Some(Word::U16(target)), Some(main)) => *target == main,
+                (Some(Word::U16(_)), None) => program.symbols.is_empty(),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// In an object file, the symbol each importing instruction refers to.
+fn imports(program: &Program) -> BTreeMap<u16, String> {
+    program
+        .link
+        .iter()
+        .flat_map(|link| link.relocations.iter())
+        .filter_map(|relocation| Some((relocation.instruction, relocation.symbol.clone()?)))
+        .collect()
+}
+
+/// Names every instruction index that is a symbol or a branch target. Names from the
+/// symbols section win; anything else gets a synthesized `label_<index>`.
+fn label_names(program: &Program, entry_prologue: bool) -> BTreeMap<u16, String> {
+    let mut labels = BTreeMap::new();
+    for symbol in program.symbols.iter() {
+        labels.entry(symbol.index).or_insert_with(|| symbol.name.clone());
+    }
+    let imports = imports(program);
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if !instruction.tt.takes_code_address() || imports.contains_key(&(index as u16)) {
+            continue;
+        }
+        if let Some(Word::U16(target)) = instruction.values.as_ref().and_then(|v| v.first()) {
+            let name = if index == 0 && entry_prologue {
+                "main".to_string()
+            } else {
+                format!("label_{}", target)
+            };
+            labels.entry(*target).or_insert(name);
+        }
+    }
+    labels
+}
+
+fn format_word(word: &Word) -> String {
+    match word {
+        Word::U16(v) => v.to_string(),
+        Word::I16(v) => format!("{}i16", v),
+        Word::U32(v) => format!("{}u32", v),
+        Word::I32(v) => format!("{}i32", v),
+        Word::F16(v) => format!("{:?}", v.to_f32()),
+        Word::Char(c) => format!("'{}'", escape(*c, '\'')),
+        Word::Bool(b) => b.to_string(),
+    }
+}
+
+/// Writes `c` the way the assembler reads it back inside a literal closed by `quote`.
+fn escape(c: char, quote: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\0' => "\\0".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if c == quote => format!("\\{}", c),
+        c if (c as u32) < 0x80 && c.is_control() => format!("\\x{:02x}", c as u32),
+        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+fn format_string(chars: &[Word]) -> String {
+    let mut s = String::from("\"");
+    for word in chars {
+        if let Word::Char(c) = word {
+            s.push_str(&escape(*c, '"'));
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn format_operands(instruction: &Instruction, labels: &BTreeMap<u16, String>, data: &[DataItem]) -> String {
+    let values = match &instruction.values {
+        Some(values) => values,
+        None => return String::new(),
+    };
+    match instruction.tt {
+        InstructionType::INST_LOAD | InstructionType::INST_STORE => match values.first() {
+            Some(Word::U16(slot)) => data
+                .iter()
+                .find(|item| item.slot == *slot)
+                .map(|item| item.name.clone())
+                .unwrap_or_else(|| slot.to_string()),
+            Some(other) => format_word(other),
+            None => String::new(),
+        },
+        InstructionType::INST_PUSH_STR | InstructionType::INST_HOST_CALL => {
+            format_string(&values[1..])
+        }
+        // A declared signature, followed by how many arguments go to its `...`.
+        InstructionType::INST_DLL_CALL => match Signature::decode(values) {
+            Ok(Some((count, signature))) if count as usize > signature.args.len() => {
+                format!("{} {}", signature, count as usize - signature.args.len())
+            }
+            Ok(Some((_, signature))) => signature.to_string(),
+            _ => values.iter().map(format_word).collect::<Vec<_>>().join(" "),
+        },
+        tt if tt.takes_code_address() => match values.first() {
+            Some(Word::U16(target)) => labels
+                .get(target)
+                .cloned()
+                .unwrap_or_else(|| target.to_string()),
+            Some(other) => format_word(other),
+            None => String::new(),
+        },
+        _ => values.iter().map(format_word).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// For `SYSCALL <args> <number>`, the syscall's name on the architecture the disassembler
+/// runs on; bytecode assembled with another `--target` uses that target's numbers.
+fn syscall_name(instruction: &Instruction) -> Option<String> {
+    let Some([_, Word::U16(number)]) = instruction.values.as_deref() else {
+        return None;
+    };
+    if !matches!(instruction.tt, InstructionType::INST_SYSCALL) {
+        return None;
+    }
+    let arch = Arch::host()?;
+    Some(format!("{} on {}", arch.syscall_name(*number)?, arch))
+}
+
+/// Writes a data item the way it would appear in the `.data` section of a QASM file.
+fn format_data(item: &DataItem) -> String {
+    match &item.init {
+        DataInit::Zeroed(count) => format!("{}: .zero {}", item.name, count),
+        DataInit::Words(words) => match words.split_last() {
+            Some((Word::Char('\0'), text)) if text.iter().all(|word| matches!(word, Word::Char(_))) => {
+                format!("{}: .string {}", item.name, format_string(text))
+            }
+            _ => format!(
+                "{}: .array {}",
+                item.name,
+                words.iter().map(format_word).collect::<Vec<_>>().join(", ")
+            ),
+        },
+    }
+}
+
+fn disassemble(program: &Program) -> String {
+    let entry_prologue = has_entry_prologue(program);
+    let labels = label_names(program, entry_prologue);
+    let imports = imports(program);
+    let mut out = String::new();
+
+    out.push_str(&format!(
+        "; {} instructions, {} symbols\n",
+        program.instructions.len(),
+        program.symbols.len()
+    ));
+    if let Some(link) = &program.link {
+        let exports: Vec<&str> = link.exports.iter().map(|export| export.name.as_str()).collect();
+        out.push_str(&format!(
+            "; object file: {} relocations, exports: {}\n",
+            link.relocations.len(),
+            if exports.is_empty() { "none".to_string() } else { exports.join(", ") }
+        ));
+    }
+
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if let Some(label) = labels.get(&(index as u16)) {
+            out.push_str(&format!("\n{}:\n", label));
+        }
+        let operands = match imports.get(&(index as u16)) {
+            Some(symbol) => symbol.clone(),
+            None => format_operands(instruction, &labels, &program.data),
+        };
+        let text = if operands.is_empty() {
+            instruction.tt.mnemonic()
+        } else {
+            format!("{} {}", instruction.tt.mnemonic(), operands)
+        };
+        if index < ENTRY_PROLOGUE_LEN && entry_prologue {
+            out.push_str(&format!("  ; {:<30} ; {:>4} (entry, inserted by the assembler)\n", text, index));
+        } else if let Some(name) = syscall_name(instruction) {
+            out.push_str(&format!("  {:<32} ; {:>4} ({})\n", text, index, name));
+        } else {
+            out.push_str(&format!("  {:<32} ; {:>4}\n", text, index));
+        }
+    }
+
+    for (target, label) in labels.range(program.instructions.len() as u16..) {
+        out.push_str(&format!("\n{}:\n  {:<32} ; {:>4} (past the end, keeps the label addressable)\n", label, "NOOP", target));
+    }
+
+    if !program.data.is_empty() {
+        out.push_str("\n.data\n");
+        for item in program.data.iter() {
+            out.push_str(&format!("{}\n", format_data(item)));
+        }
+    }
+    out
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("Usage: disassembler <input_file> [output_file]");
+        process::exit(1);
+    }
+
+    let input_file = &args[1];
+
+    let program = match ByteCodeCompiler::new(input_file).load_file() {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Failed to load bytecode file {}: {}", input_file, e);
+            process::exit(1);
+        }
+    };
+
+    let listing = disassemble(&program);
+    match args.get(2) {
+        Some(output_file) => {
+            if let Err(e) = fs::write(output_file, listing) {
+                eprintln!("Failed to write {}: {}", output_file, e);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", listing),
+    }
+}
@@ -1,4 +1,12 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
 pub mod lib {
     pub mod bytecode;
+    pub mod ffi;
+    pub mod gc;
+    pub mod host;
     pub mod machine_type;
+    pub mod sandbox;
+    pub mod stdlib;
+    pub mod syscalls;
 }
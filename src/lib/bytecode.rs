@@ -1,7 +1,322 @@
-use core::panic;
-use std::{fs::File, io::{Read, Write}};
+use std::{fmt, fs::File, io::{self, Read, Write}};
 use half::f16;
-use crate::lib::machine_type::{ InstructionType, Instruction, Word };
+use crate::lib::machine_type::{ InstructionType, Instruction, Word, CONSTANT_POOL_SIZE, INSTRUCTION_SET_VERSION };
+
+/// Every bytecode file starts with these four bytes.
+pub const MAGIC: [u8; 4] = *b"QASM";
+/// Version of the container layout itself (header, section table, section encodings).
+pub const FORMAT_VERSION: u16 = 5;
+/// Header flag set on relocatable object files, which must be linked before they can run.
+pub const FLAG_OBJECT: u16 = 1;
+
+const HEADER_SIZE: usize = 16;
+const SECTION_ENTRY_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SectionKind {
+    Code = 0,
+    Data = 1,
+    Symbols = 2,
+    Debug = 3,
+    Link = 4,
+}
+
+impl TryFrom<u8> for SectionKind {
+    type Error = BytecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Code),
+            1 => Ok(Self::Data),
+            2 => Ok(Self::Symbols),
+            3 => Ok(Self::Debug),
+            4 => Ok(Self::Link),
+            other => Err(BytecodeError::UnknownSection(other)),
+        }
+    }
+}
+
+/// A named instruction index, e.g. a label defined in the QASM source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub index: u16,
+}
+
+/// Maps an instruction back to the source position it was assembled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugEntry {
+    pub instruction: u16,
+    pub file: u16,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub entries: Vec<DebugEntry>,
+}
+
+/// How a static data item is initialised when the program is loaded.
+#[derive(Debug, Clone)]
+pub enum DataInit {
+    /// One heap cell per word.
+    Words(Vec<Word>),
+    /// This many cells set to zero.
+    Zeroed(u16),
+}
+
+impl DataInit {
+    /// Number of heap cells the item occupies.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Words(words) => words.len(),
+            Self::Zeroed(count) => *count as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A block the machine places on the heap at load time. A pointer to it is stored in
+/// constant pool slot `slot`, where code reads it with `LOAD`.
+#[derive(Debug, Clone)]
+pub struct DataItem {
+    pub name: String,
+    pub slot: u16,
+    pub init: DataInit,
+}
+
+/// What an exported symbol or a relocation refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SymbolKind {
+    /// An instruction index, as taken by `CALL` and the jumps.
+    Code = 0,
+    /// A constant pool slot, as taken by `LOAD` and `STORE` and used by data items.
+    Slot = 1,
+}
+
+impl TryFrom<u8> for SymbolKind {
+    type Error = BytecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Code),
+            1 => Ok(Self::Slot),
+            _ => Err(BytecodeError::InvalidOperand),
+        }
+    }
+}
+
+/// A symbol an object file defines for other objects to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Instruction index or slot, relative to the start of the object.
+    pub value: u16,
+}
+
+/// An operand the linker has to patch once it knows where everything ends up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub instruction: u16,
+    /// Index into the instruction's operands.
+    pub operand: u8,
+    pub kind: SymbolKind,
+    /// The imported symbol the operand refers to. Without one, the operand is an index
+    /// local to the object and is shifted by where the object's code or slots are placed.
+    pub symbol: Option<String>,
+}
+
+/// What an object file carries on top of an executable program so it can be linked.
+#[derive(Debug, Clone, Default)]
+pub struct LinkInfo {
+    /// Constant pool slots the object uses, numbered from zero.
+    pub slots: u16,
+    pub exports: Vec<Export>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// Everything stored in a bytecode file.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub data: Vec<DataItem>,
+    pub symbols: Vec<Symbol>,
+    pub debug_info: DebugInfo,
+    /// Present on object files only.
+    pub link: Option<LinkInfo>,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            ..Self::default()
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.index)
+    }
+}
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedFormatVersion(u16),
+    IncompatibleInstructionSet { expected: u16, found: u16 },
+    ChecksumMismatch { expected: u32, found: u32 },
+    Truncated,
+    UnknownSection(u8),
+    MissingSection(SectionKind),
+    /// An object file was given where a linked program is needed.
+    NotExecutable,
+    InvalidInstruction(u8),
+    InvalidOperandType(u8),
+    InvalidOperand,
+    /// A data item placed in a constant pool slot the machine doesn't have.
+    SlotOutOfRange(u16),
+    /// A data item with more cells than one allocation can hold.
+    DataTooLarge { name: String, cells: usize },
+    /// More of something than the field storing its count or length can hold.
+    TooLarge { what: &'static str, count: usize },
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::BadMagic => write!(f, "not a QASM bytecode file (bad magic number)"),
+            Self::UnsupportedFormatVersion(v) => {
+                write!(f, "unsupported bytecode format version {} (expected {})", v, FORMAT_VERSION)
+            }
+            Self::IncompatibleInstructionSet { expected, found } => write!(
+                f,
+                "bytecode targets instruction set version {}, this machine supports {}",
+                found, expected
+            ),
+            Self::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch (header says {:#010x}, contents hash to {:#010x})",
+                expected, found
+            ),
+            Self::Truncated => write!(f, "bytecode file is truncated"),
+            Self::UnknownSection(kind) => write!(f, "unknown section kind {}", kind),
+            Self::MissingSection(kind) => write!(f, "missing {:?} section", kind),
+            Self::NotExecutable => write!(f, "this is an object file; link it with `linker` first"),
+            Self::InvalidInstruction(op) => write!(f, "invalid instruction opcode {}", op),
+            Self::InvalidOperandType(t) => write!(f, "invalid operand type {}", t),
+            Self::InvalidOperand => write!(f, "invalid operand encoding"),
+            Self::SlotOutOfRange(slot) => {
+                write!(f, "data item in constant pool slot {}, the pool has {}", slot, CONSTANT_POOL_SIZE)
+            }
+            Self::DataTooLarge { name, cells } => {
+                write!(f, "data item `{}` has {} cells, at most {} fit in one allocation", name, cells, u16::MAX)
+            }
+            Self::TooLarge { what, count } => write!(f, "too many {} to encode ({})", what, count),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl From<io::Error> for BytecodeError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// CRC-32 (IEEE 802.3) over `bytes`.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Bounds-checked big-endian reader over a section payload.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self.position.checked_add(count).ok_or(BytecodeError::Truncated)?;
+        let slice = self.bytes.get(self.position..end).ok_or(BytecodeError::Truncated)?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// A type tag followed by the value, as written by `Word::tag` and `Word::to_be_bytes`.
+    fn word(&mut self) -> Result<Word, BytecodeError> {
+        Ok(match self.u8()? {
+            0 => Word::U16(self.u16()?),
+            1 => Word::F16(f16::from_bits(self.u16()?)),
+            2 => Word::Char(char::from_u32(self.u32()?).ok_or(BytecodeError::InvalidOperand)?),
+            3 => Word::I16(self.u16()? as i16),
+            4 => Word::U32(self.u32()?),
+            5 => Word::I32(self.u32()? as i32),
+            6 => match self.u8()? {
+                0 => Word::Bool(false),
+                1 => Word::Bool(true),
+                _ => return Err(BytecodeError::InvalidOperand),
+            },
+            other => return Err(BytecodeError::InvalidOperandType(other)),
+        })
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| BytecodeError::InvalidOperand)
+    }
+}
+
+/// `len` as the integer type it is stored as, if it fits.
+fn length<T: TryFrom<usize>>(what: &'static str, len: usize) -> Result<T, BytecodeError> {
+    T::try_from(len).map_err(|_| BytecodeError::TooLarge { what, count: len })
+}
+
+fn push_string(buffer: &mut Vec<u8>, s: &str) -> Result<(), BytecodeError> {
+    buffer.extend_from_slice(&length::<u16>("bytes in a name", s.len())?.to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+    Ok(())
+}
 
 #[derive(Debug)]
 pub struct ByteCodeCompiler {
@@ -15,50 +330,181 @@ impl ByteCodeCompiler {
         }
     }
 
-    pub fn store_file(&mut self, instructions: &[Instruction]) {
-        let mut file = File::create(&self.file_name).expect("QUARMVM: Error while creating file");
-        for instruction in instructions.iter() {
-            file.write_all(&instruction.to_bytes()).expect("QUARMVM: Error while writing instruction");
-        }
+    pub fn store_file(&mut self, program: &Program) -> Result<(), BytecodeError> {
+        let mut file = File::create(&self.file_name)?;
+        file.write_all(&Self::encode(program)?)?;
+        Ok(())
     }
 
-    pub fn load_file(&mut self) -> Vec<Instruction> {
-        let mut file = File::open(&self.file_name).expect("QUARMVM: Error while opening the file");
+    pub fn load_file(&mut self) -> Result<Program, BytecodeError> {
+        let mut file = File::open(&self.file_name)?;
         let mut buffer: Vec<u8> = vec![];
-        file.read_to_end(&mut buffer).expect("QUARMVM: Error while reading the file");
-        let mut i = 0;
-        let mut ins = vec![];
-        while i < buffer.iter().len() {
-            let instruction = buffer[i];
-            i += 1;
-            let argument_length = buffer[i];
-            i += 1;
-            let mut args: Vec<Word> = vec![];
-            for x in 0..argument_length {
-                let arg_type = buffer[i];
-                i += 1;
-                let arg = u16::from_be_bytes([buffer[i], buffer[i + 1]]);
-                i += 2;
-
-                match arg_type {
-                    0 => {
-                        args.push(Word::U16(arg));
-                    }
-                    1 => {
-                        args.push(Word::F16(f16::from_bits(arg)));
-                    }
-                    2 => {
-                        args.push(Word::Char(char::from_u32(u16::from_be_bytes(arg.to_be_bytes()) as u32).expect("QUARMVM: Error while decoding character")));
-                    }
-                    3 => {
-                        args.push(Word::I16(i16::from_be_bytes([buffer[i - 2 + x as usize], buffer[i - 2 + x as usize + 1]])));
-                    }
-                    _ => {
-                        panic!("QUARMVM: Unknown argument type while parsing the qasm file: {:?}", instruction);
+        file.read_to_end(&mut buffer)?;
+        Self::decode(&buffer)
+    }
+
+    /// Serializes `program`, failing if a count or length doesn't fit the field it is
+    /// stored in.
+    pub fn encode(program: &Program) -> Result<Vec<u8>, BytecodeError> {
+        let mut code = vec![];
+        for instruction in program.instructions.iter() {
+            length::<u16>("operands in an instruction", instruction.values.as_ref().map_or(0, |values| values.len()))?;
+            code.extend_from_slice(&instruction.to_bytes());
+        }
+
+        let mut data = vec![];
+        data.extend_from_slice(&length::<u16>("data items", program.data.len())?.to_be_bytes());
+        for item in program.data.iter() {
+            push_string(&mut data, &item.name)?;
+            data.extend_from_slice(&item.slot.to_be_bytes());
+            match &item.init {
+                DataInit::Words(words) => {
+                    data.push(0);
+                    data.extend_from_slice(&length::<u16>("words in a data item", words.len())?.to_be_bytes());
+                    for word in words {
+                        data.push(word.tag());
+                        data.extend_from_slice(&word.to_be_bytes());
                     }
                 }
+                DataInit::Zeroed(count) => {
+                    data.push(1);
+                    data.extend_from_slice(&count.to_be_bytes());
+                }
             }
-            let instruction_type = InstructionType::try_from(instruction).expect("QUARMVM: Error while converting instruction to token type");
+        }
+
+        let mut symbols = vec![];
+        symbols.extend_from_slice(&length::<u16>("symbols", program.symbols.len())?.to_be_bytes());
+        for symbol in program.symbols.iter() {
+            push_string(&mut symbols, &symbol.name)?;
+            symbols.extend_from_slice(&symbol.index.to_be_bytes());
+        }
+
+        let mut debug = vec![];
+        debug.extend_from_slice(&length::<u16>("debug files", program.debug_info.files.len())?.to_be_bytes());
+        for file in program.debug_info.files.iter() {
+            push_string(&mut debug, file)?;
+        }
+        debug.extend_from_slice(&length::<u32>("debug entries", program.debug_info.entries.len())?.to_be_bytes());
+        for entry in program.debug_info.entries.iter() {
+            debug.extend_from_slice(&entry.instruction.to_be_bytes());
+            debug.extend_from_slice(&entry.file.to_be_bytes());
+            debug.extend_from_slice(&entry.line.to_be_bytes());
+            debug.extend_from_slice(&entry.column.to_be_bytes());
+        }
+
+        let mut sections = vec![
+            (SectionKind::Code, code),
+            (SectionKind::Data, data),
+            (SectionKind::Symbols, symbols),
+            (SectionKind::Debug, debug),
+        ];
+        let mut flags = 0;
+        if let Some(link) = &program.link {
+            flags |= FLAG_OBJECT;
+            sections.push((SectionKind::Link, Self::encode_link_info(link)?));
+        }
+
+        let mut body = vec![];
+        let mut offset = HEADER_SIZE + SECTION_ENTRY_SIZE * sections.len();
+        for (kind, payload) in sections.iter() {
+            body.push(*kind as u8);
+            body.extend_from_slice(&length::<u32>("bytes before a section", offset)?.to_be_bytes());
+            body.extend_from_slice(&length::<u32>("bytes in a section", payload.len())?.to_be_bytes());
+            offset += payload.len();
+        }
+        for (_, payload) in sections.iter() {
+            body.extend_from_slice(payload);
+        }
+
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + body.len());
+        buffer.extend_from_slice(&MAGIC);
+        buffer.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&INSTRUCTION_SET_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&flags.to_be_bytes());
+        buffer.extend_from_slice(&checksum(&body).to_be_bytes());
+        buffer.extend_from_slice(&body);
+        Ok(buffer)
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Program, BytecodeError> {
+        if buffer.len() < MAGIC.len() || buffer[..MAGIC.len()] != MAGIC {
+            return Err(BytecodeError::BadMagic);
+        }
+        let mut header = Cursor::new(buffer.get(..HEADER_SIZE).ok_or(BytecodeError::Truncated)?);
+        header.take(MAGIC.len())?;
+        let format_version = header.u16()?;
+        if format_version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedFormatVersion(format_version));
+        }
+        let isa_version = header.u16()?;
+        if isa_version != INSTRUCTION_SET_VERSION {
+            return Err(BytecodeError::IncompatibleInstructionSet {
+                expected: INSTRUCTION_SET_VERSION,
+                found: isa_version,
+            });
+        }
+        let section_count = header.u16()?;
+        let flags = header.u16()?;
+        let expected_checksum = header.u32()?;
+
+        let mut table = Cursor::new(&buffer[HEADER_SIZE..]);
+        let mut sections = Vec::with_capacity(section_count as usize);
+        for _ in 0..section_count {
+            let kind = SectionKind::try_from(table.u8()?)?;
+            let offset = table.u32()? as usize;
+            let length = table.u32()? as usize;
+            let payload = offset
+                .checked_add(length)
+                .and_then(|end| buffer.get(offset..end))
+                .ok_or(BytecodeError::Truncated)?;
+            sections.push((kind, payload));
+        }
+
+        let found_checksum = checksum(&buffer[HEADER_SIZE..]);
+        if expected_checksum != found_checksum {
+            return Err(BytecodeError::ChecksumMismatch {
+                expected: expected_checksum,
+                found: found_checksum,
+            });
+        }
+
+        let mut program = Program::default();
+        let mut has_code = false;
+        for (kind, payload) in sections {
+            match kind {
+                SectionKind::Code => {
+                    program.instructions = Self::decode_instructions(payload)?;
+                    has_code = true;
+                }
+                SectionKind::Data => program.data = Self::decode_data(payload)?,
+                SectionKind::Symbols => program.symbols = Self::decode_symbols(payload)?,
+                SectionKind::Debug => program.debug_info = Self::decode_debug_info(payload)?,
+                SectionKind::Link => program.link = Some(Self::decode_link_info(payload)?),
+            }
+        }
+        if !has_code {
+            return Err(BytecodeError::MissingSection(SectionKind::Code));
+        }
+        if flags & FLAG_OBJECT != 0 && program.link.is_none() {
+            return Err(BytecodeError::MissingSection(SectionKind::Link));
+        }
+        Ok(program)
+    }
+
+    fn decode_instructions(buffer: &[u8]) -> Result<Vec<Instruction>, BytecodeError> {
+        let mut cursor = Cursor::new(buffer);
+        let mut ins = vec![];
+        while !cursor.is_empty() {
+            let instruction = cursor.u8()?;
+            let argument_length = cursor.u16()?;
+            let mut args: Vec<Word> = vec![];
+            for _ in 0..argument_length {
+                args.push(cursor.word()?);
+            }
+            let instruction_type = InstructionType::try_from(instruction)
+                .map_err(|_| BytecodeError::InvalidInstruction(instruction))?;
             let instruction = Instruction {
                 tt: instruction_type,
                 values: if argument_length > 0 {
@@ -69,6 +515,185 @@ impl ByteCodeCompiler {
             };
             ins.push(instruction);
         }
-        ins
+        Ok(ins)
+    }
+
+    fn encode_link_info(link: &LinkInfo) -> Result<Vec<u8>, BytecodeError> {
+        let mut buffer = vec![];
+        buffer.extend_from_slice(&link.slots.to_be_bytes());
+        buffer.extend_from_slice(&length::<u16>("exports", link.exports.len())?.to_be_bytes());
+        for export in link.exports.iter() {
+            push_string(&mut buffer, &export.name)?;
+            buffer.push(export.kind as u8);
+            buffer.extend_from_slice(&export.value.to_be_bytes());
+        }
+        buffer.extend_from_slice(&length::<u32>("relocations", link.relocations.len())?.to_be_bytes());
+        for relocation in link.relocations.iter() {
+            buffer.extend_from_slice(&relocation.instruction.to_be_bytes());
+            buffer.push(relocation.operand);
+            buffer.push(relocation.kind as u8);
+            match &relocation.symbol {
+                Some(symbol) => {
+                    buffer.push(1);
+                    push_string(&mut buffer, symbol)?;
+                }
+                None => buffer.push(0),
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn decode_link_info(buffer: &[u8]) -> Result<LinkInfo, BytecodeError> {
+        let mut cursor = Cursor::new(buffer);
+        let mut link = LinkInfo {
+            slots: cursor.u16()?,
+            ..LinkInfo::default()
+        };
+        for _ in 0..cursor.u16()? {
+            link.exports.push(Export {
+                name: cursor.string()?,
+                kind: SymbolKind::try_from(cursor.u8()?)?,
+                value: cursor.u16()?,
+            });
+        }
+        for _ in 0..cursor.u32()? {
+            let instruction = cursor.u16()?;
+            let operand = cursor.u8()?;
+            let kind = SymbolKind::try_from(cursor.u8()?)?;
+            let symbol = match cursor.u8()? {
+                0 => None,
+                1 => Some(cursor.string()?),
+                _ => return Err(BytecodeError::InvalidOperand),
+            };
+            link.relocations.push(Relocation { instruction, operand, kind, symbol });
+        }
+        Ok(link)
+    }
+
+    fn decode_data(buffer: &[u8]) -> Result<Vec<DataItem>, BytecodeError> {
+        let mut cursor = Cursor::new(buffer);
+        let count = cursor.u16()?;
+        let mut items = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = cursor.string()?;
+            let slot = cursor.u16()?;
+            if slot as usize >= CONSTANT_POOL_SIZE {
+                return Err(BytecodeError::SlotOutOfRange(slot));
+            }
+            let init = match cursor.u8()? {
+                0 => {
+                    let len = cursor.u16()?;
+                    let mut words = Vec::with_capacity(len as usize);
+                    for _ in 0..len {
+                        words.push(cursor.word()?);
+                    }
+                    DataInit::Words(words)
+                }
+                1 => DataInit::Zeroed(cursor.u16()?),
+                _ => return Err(BytecodeError::InvalidOperand),
+            };
+            if init.is_empty() {
+                return Err(BytecodeError::InvalidOperand);
+            }
+            items.push(DataItem { name, slot, init });
+        }
+        Ok(items)
+    }
+
+    fn decode_symbols(buffer: &[u8]) -> Result<Vec<Symbol>, BytecodeError> {
+        let mut cursor = Cursor::new(buffer);
+        let count = cursor.u16()?;
+        let mut symbols = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = cursor.string()?;
+            let index = cursor.u16()?;
+            symbols.push(Symbol { name, index });
+        }
+        Ok(symbols)
+    }
+
+    fn decode_debug_info(buffer: &[u8]) -> Result<DebugInfo, BytecodeError> {
+        let mut cursor = Cursor::new(buffer);
+        let mut debug_info = DebugInfo::default();
+        for _ in 0..cursor.u16()? {
+            debug_info.files.push(cursor.string()?);
+        }
+        for _ in 0..cursor.u32()? {
+            debug_info.entries.push(DebugEntry {
+                instruction: cursor.u16()?,
+                file: cursor.u16()?,
+                line: cursor.u32()?,
+                column: cursor.u32()?,
+            });
+        }
+        Ok(debug_info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::machine_type::{DEFINE_ADD, DEFINE_CALL, DEFINE_JMP, DEFINE_LOAD, DEFINE_PRINT, DEFINE_PUSH, DEFINE_RET};
+
+    fn sample() -> Program {
+        Program {
+            instructions: vec![
+                DEFINE_CALL(2),
+                DEFINE_RET(),
+                DEFINE_LOAD(Some(0)),
+                DEFINE_PUSH(7),
+                DEFINE_ADD(),
+                DEFINE_PRINT(),
+                DEFINE_JMP(1),
+                Instruction { tt: InstructionType::INST_PUSH, values: Some(vec![Word::F16(f16::from_f32(1.5))]) },
+            ],
+            data: vec![
+                DataItem { name: "msg".to_string(), slot: 0, init: DataInit::Words(vec![Word::Char('h'), Word::Char('\u{1F600}'), Word::Char('\0')]) },
+                DataItem { name: "buffer".to_string(), slot: 1, init: DataInit::Zeroed(16) },
+            ],
+            symbols: vec![Symbol { name: "main".to_string(), index: 2 }],
+            debug_info: DebugInfo {
+                files: vec!["sample.qasm".to_string()],
+                entries: vec![DebugEntry { instruction: 2, file: 0, line: 3, column: 5 }],
+            },
+            link: Some(LinkInfo {
+                slots: 2,
+                exports: vec![Export { name: "main".to_string(), kind: SymbolKind::Code, value: 2 }],
+                relocations: vec![Relocation { instruction: 2, operand: 0, kind: SymbolKind::Slot, symbol: Some("counter".to_string()) }],
+            }),
+        }
+    }
+
+    #[test]
+    fn a_program_survives_a_round_trip() {
+        let program = sample();
+        let decoded = ByteCodeCompiler::decode(&ByteCodeCompiler::encode(&program).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", program));
+    }
+
+    #[test]
+    fn a_corrupted_body_fails_the_checksum() {
+        let mut bytes = ByteCodeCompiler::encode(&sample()).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(ByteCodeCompiler::decode(&bytes), Err(BytecodeError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let bytes = ByteCodeCompiler::encode(&sample()).unwrap();
+
+        let mut format = bytes.clone();
+        format[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            ByteCodeCompiler::decode(&format),
+            Err(BytecodeError::UnsupportedFormatVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+
+        let mut isa = bytes;
+        isa[6..8].copy_from_slice(&(INSTRUCTION_SET_VERSION - 1).to_be_bytes());
+        assert!(matches!(
+            ByteCodeCompiler::decode(&isa),
+            Err(BytecodeError::IncompatibleInstructionSet { found, .. }) if found == INSTRUCTION_SET_VERSION - 1
+        ));
     }
 }
@@ -0,0 +1,340 @@
+use std::ffi::c_void;
+use std::fmt;
+
+use libffi::middle::{Arg, Cif, CodePtr, Type};
+
+use super::machine_type::{QuarkVM, StackValues, VmError, Word};
+
+/// A C type in a `DLL_CALL` signature.
+///
+/// The stack has no 64-bit values and only 16-bit floats, so results are narrowed:
+/// `i64`/`u64` results must fit in an `I32`/`U32`, and `f32`/`f64` results are rounded
+/// to `F16` and must lie within its range. A result that doesn't fit is a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfiType {
+    Void,
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    Pointer,
+}
+
+impl FfiType {
+    pub const ALL: [FfiType; 12] = [
+        FfiType::Void,
+        FfiType::I8,
+        FfiType::U8,
+        FfiType::I16,
+        FfiType::U16,
+        FfiType::I32,
+        FfiType::U32,
+        FfiType::I64,
+        FfiType::U64,
+        FfiType::F32,
+        FfiType::F64,
+        FfiType::Pointer,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FfiType::Void => "void",
+            FfiType::I8 => "i8",
+            FfiType::U8 => "u8",
+            FfiType::I16 => "i16",
+            FfiType::U16 => "u16",
+            FfiType::I32 => "i32",
+            FfiType::U32 => "u32",
+            FfiType::I64 => "i64",
+            FfiType::U64 => "u64",
+            FfiType::F32 => "f32",
+            FfiType::F64 => "f64",
+            FfiType::Pointer => "ptr",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FfiType> {
+        Self::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+
+    /// The number the type is stored as in bytecode.
+    pub fn code(self) -> u16 {
+        Self::ALL.iter().position(|ty| *ty == self).expect("every type is in ALL") as u16
+    }
+
+    pub fn from_code(code: u16) -> Option<FfiType> {
+        Self::ALL.get(code as usize).copied()
+    }
+
+    fn ffi_type(self) -> Type {
+        match self {
+            FfiType::Void => Type::void(),
+            FfiType::I8 => Type::i8(),
+            FfiType::U8 => Type::u8(),
+            FfiType::I16 => Type::i16(),
+            FfiType::U16 => Type::u16(),
+            FfiType::I32 => Type::i32(),
+            FfiType::U32 => Type::u32(),
+            FfiType::I64 => Type::i64(),
+            FfiType::U64 => Type::u64(),
+            FfiType::F32 => Type::f32(),
+            FfiType::F64 => Type::f64(),
+            FfiType::Pointer => Type::pointer(),
+        }
+    }
+}
+
+impl fmt::Display for FfiType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The declared C signature of a function called with `DLL_CALL`, written
+/// `(i32, f64) -> i32` in QASM. A trailing `...` makes the function variadic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// The fixed arguments, in the order they are popped.
+    pub args: Vec<FfiType>,
+    pub variadic: bool,
+    pub ret: FfiType,
+}
+
+impl Signature {
+    /// The `DLL_CALL` operands for a call passing `count` arguments in total: the count,
+    /// the return type, whether the function is variadic, then the fixed argument types.
+    pub fn encode(&self, count: u16) -> Vec<Word> {
+        let mut words = vec![Word::U16(count), Word::U16(self.ret.code()), Word::Bool(self.variadic)];
+        words.extend(self.args.iter().map(|ty| Word::U16(ty.code())));
+        words
+    }
+
+    /// Reads back what `encode` wrote, returning the argument count and the signature.
+    /// `None` for the single operand of an untyped `DLL_CALL n`.
+    pub fn decode(words: &[Word]) -> Result<Option<(u16, Signature)>, VmError> {
+        let (count, ret, variadic, args) = match words {
+            [Word::U16(_)] => return Ok(None),
+            [Word::U16(count), ret, Word::Bool(variadic), args @ ..] => (*count, ret, *variadic, args),
+            [other, ..] => return Err(VmError::InvalidOperand(other.clone())),
+            [] => return Err(VmError::MissingOperand),
+        };
+        let ffi_type = |word: &Word| match word {
+            Word::U16(code) => FfiType::from_code(*code).ok_or_else(|| VmError::InvalidOperand(word.clone())),
+            other => Err(VmError::InvalidOperand(other.clone())),
+        };
+        let signature = Signature {
+            args: args.iter().map(ffi_type).collect::<Result<_, _>>()?,
+            variadic,
+            ret: ffi_type(ret)?,
+        };
+        Ok(Some((count, signature)))
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args: Vec<&str> = self.args.iter().map(|ty| ty.name()).collect();
+        if self.variadic {
+            args.push("...");
+        }
+        write!(f, "({}) -> {}", args.join(", "), self.ret)
+    }
+}
+
+/// An argument converted to the C type it is passed as.
+enum NativeValue {
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Pointer(*mut c_void),
+}
+
+impl NativeValue {
+    fn arg(&self) -> Arg {
+        match self {
+            NativeValue::I8(v) => Arg::new(v),
+            NativeValue::U8(v) => Arg::new(v),
+            NativeValue::I16(v) => Arg::new(v),
+            NativeValue::U16(v) => Arg::new(v),
+            NativeValue::I32(v) => Arg::new(v),
+            NativeValue::U32(v) => Arg::new(v),
+            NativeValue::I64(v) => Arg::new(v),
+            NativeValue::U64(v) => Arg::new(v),
+            NativeValue::F32(v) => Arg::new(v),
+            NativeValue::F64(v) => Arg::new(v),
+            NativeValue::Pointer(v) => Arg::new(v),
+        }
+    }
+}
+
+/// The value of an integer, `Char` or `Bool` on the stack.
+fn integer(value: &StackValues) -> Option<i64> {
+    match *value {
+        StackValues::U16(v) => Some(v.into()),
+        StackValues::I16(v) => Some(v.into()),
+        StackValues::U32(v) => Some(v.into()),
+        StackValues::I32(v) => Some(v.into()),
+        StackValues::Char(c) => Some(u32::from(c).into()),
+        StackValues::Bool(b) => Some(b.into()),
+        _ => None,
+    }
+}
+
+/// Rounds a returned float to `F16`. A finite result too large for it is a fault rather
+/// than an infinity, the same as an integer result that doesn't fit.
+fn float_result(value: f64, ty: FfiType) -> Result<StackValues, VmError> {
+    let narrowed = half::f16::from_f64(value);
+    if value.is_finite() && !narrowed.is_finite() {
+        return Err(VmError::FfiFailure(format!("{} result {} does not fit in an f16", ty, value)));
+    }
+    Ok(StackValues::F16(narrowed))
+}
+
+/// Converts a returned integer to the narrowest stack value holding it. The stack has no
+/// 64-bit values, so a 64-bit result that needs more than 32 bits is a fault.
+fn integer_result(value: i128, ty: FfiType) -> Result<StackValues, VmError> {
+    let result = match ty {
+        FfiType::I8 | FfiType::I16 => i16::try_from(value).ok().map(StackValues::I16),
+        FfiType::U8 | FfiType::U16 => u16::try_from(value).ok().map(StackValues::U16),
+        FfiType::I32 | FfiType::I64 => i32::try_from(value).ok().map(StackValues::I32),
+        _ => u32::try_from(value).ok().map(StackValues::U32),
+    };
+    result.ok_or_else(|| VmError::FfiFailure(format!("{} result {} does not fit on the stack", ty, value)))
+}
+
+impl QuarkVM {
+    /// Looks up `name` in the library `DLL_LOAD` returned handle `dll` for.
+    pub fn dll_function(&self, dll: u16, name: &str) -> Result<CodePtr, VmError> {
+        let dlls = self.dlls.borrow();
+        let library = dlls
+            .get(dll as usize)
+            .ok_or_else(|| VmError::FfiFailure(format!("no DLL registered at {}", dll)))?;
+        let function = unsafe { library.get::<unsafe extern "C" fn()>(name.as_bytes()) }
+            .map_err(|e| VmError::FfiFailure(e.to_string()))?;
+        Ok(CodePtr::from_fun(*function))
+    }
+
+    /// Converts a popped value to the declared type of argument `index`. Integers must fit
+    /// the type; floats accept `F16` and integers; pointers accept heap and foreign pointers.
+    fn native_argument(&mut self, value: StackValues, ty: FfiType, index: usize) -> Result<NativeValue, VmError> {
+        let mismatch = |value| VmError::TypeMismatch { expected: ty.name(), found: value };
+        let out_of_range = |n: i64| VmError::FfiFailure(format!("argument {}: {} does not fit in {}", index, n, ty));
+        let native = match ty {
+            FfiType::F32 | FfiType::F64 => {
+                let float = match value {
+                    StackValues::F16(v) => v.to_f64(),
+                    other => integer(&other).ok_or_else(|| mismatch(other))? as f64,
+                };
+                if ty == FfiType::F32 { NativeValue::F32(float as f32) } else { NativeValue::F64(float) }
+            }
+            FfiType::Pointer => match value {
+                StackValues::Pointer(ptr) => {
+                    self.find_allocation(ptr).ok_or(VmError::InvalidPointer(ptr))?;
+                    NativeValue::Pointer(self.native_address(ptr).cast())
+                }
+                StackValues::ForeignPointer(ptr) => NativeValue::Pointer(ptr.cast()),
+                other => return Err(mismatch(other)),
+            },
+            FfiType::Void => return Err(VmError::FfiFailure(format!("argument {} is declared void", index))),
+            _ => {
+                let n = integer(&value).ok_or_else(|| mismatch(value))?;
+                let native = match ty {
+                    FfiType::I8 => i8::try_from(n).ok().map(NativeValue::I8),
+                    FfiType::U8 => u8::try_from(n).ok().map(NativeValue::U8),
+                    FfiType::I16 => i16::try_from(n).ok().map(NativeValue::I16),
+                    FfiType::U16 => u16::try_from(n).ok().map(NativeValue::U16),
+                    FfiType::I32 => i32::try_from(n).ok().map(NativeValue::I32),
+                    FfiType::U32 => u32::try_from(n).ok().map(NativeValue::U32),
+                    FfiType::I64 => Some(NativeValue::I64(n)),
+                    _ => u64::try_from(n).ok().map(NativeValue::U64),
+                };
+                native.ok_or_else(|| out_of_range(n))?
+            }
+        };
+        Ok(native)
+    }
+
+    /// The C type a variadic argument is passed as, after the default argument promotions:
+    /// integers narrower than `int` become `int` and `F16` becomes `double`.
+    fn variadic_argument(&mut self, value: StackValues, index: usize) -> Result<(NativeValue, FfiType), VmError> {
+        let ty = match value {
+            StackValues::U32(_) => FfiType::U32,
+            StackValues::F16(_) => FfiType::F64,
+            StackValues::Pointer(_) | StackValues::ForeignPointer(_) => FfiType::Pointer,
+            _ => FfiType::I32,
+        };
+        Ok((self.native_argument(value, ty, index)?, ty))
+    }
+
+    /// Calls `function` with `count` arguments popped off the stack as `signature`
+    /// declares them, and pushes the result unless the function returns `void`.
+    /// Integer results go on the stack as the narrowest matching value, floats as `F16`
+    /// and pointers as `ForeignPointer`; see `FfiType` for results that don't fit.
+    pub fn ffi_call(&mut self, function: CodePtr, signature: &Signature, count: u16) -> Result<(), VmError> {
+        let count = count as usize;
+        if count < signature.args.len() || (!signature.variadic && count != signature.args.len()) {
+            return Err(VmError::FfiFailure(format!(
+                "{} arguments passed to a function declared {}",
+                count, signature
+            )));
+        }
+        let mut values = Vec::with_capacity(count);
+        let mut types = Vec::with_capacity(count);
+        for index in 0..count {
+            let value = self.pop_stack()?;
+            let (native, ty) = match signature.args.get(index) {
+                Some(ty) => (self.native_argument(value, *ty, index)?, *ty),
+                None => self.variadic_argument(value, index)?,
+            };
+            values.push(native);
+            types.push(ty.ffi_type());
+        }
+        let arguments: Vec<Arg> = values.iter().map(NativeValue::arg).collect();
+
+        let ret = signature.ret.ffi_type();
+        let cif = if signature.variadic {
+            Cif::new_variadic(types, signature.args.len(), ret)
+        } else {
+            Cif::new(types, ret)
+        };
+        // libffi widens integer results narrower than a register to a full `ffi_arg`, so
+        // they are read as `u64` and truncated to the declared type.
+        let result = match signature.ret {
+            FfiType::Void => {
+                unsafe { cif.call::<()>(function, &arguments) };
+                return Ok(());
+            }
+            FfiType::F32 => float_result(unsafe { cif.call::<f32>(function, &arguments) }.into(), FfiType::F32)?,
+            FfiType::F64 => float_result(unsafe { cif.call::<f64>(function, &arguments) }, FfiType::F64)?,
+            FfiType::Pointer => StackValues::ForeignPointer(unsafe { cif.call::<*mut ()>(function, &arguments) }),
+            ty => {
+                let raw = unsafe { cif.call::<u64>(function, &arguments) };
+                let value: i128 = match ty {
+                    FfiType::I8 => (raw as i8).into(),
+                    FfiType::U8 => (raw as u8).into(),
+                    FfiType::I16 => (raw as i16).into(),
+                    FfiType::U16 => (raw as u16).into(),
+                    FfiType::I32 => (raw as i32).into(),
+                    FfiType::U32 => (raw as u32).into(),
+                    FfiType::I64 => (raw as i64).into(),
+                    _ => raw.into(),
+                };
+                integer_result(value, ty)?
+            }
+        };
+        self.push_stack(result)
+    }
+}
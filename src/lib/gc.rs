@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+
+use super::machine_type::{Handle, PointerType, QuarkVM, StackValues};
+
+/// Heap cells allocated between two automatic collections unless configured otherwise.
+pub const DEFAULT_GC_THRESHOLD: usize = 1024;
+
+/// Opt-in tracing collector for the managed heap.
+///
+/// When enabled, a collection runs before the next instruction once `threshold` heap
+/// cells have been allocated since the previous one. Raw memory is never collected.
+#[derive(Debug, Clone)]
+pub struct GcConfig {
+    pub enabled: bool,
+    pub threshold: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: DEFAULT_GC_THRESHOLD,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    pub collections: usize,
+    pub blocks_freed: usize,
+    pub cells_freed: usize,
+    pub allocated_since_collection: usize,
+}
+
+impl QuarkVM {
+    /// Runs a collection if the collector is enabled and the allocation threshold was reached.
+    pub fn maybe_collect_garbage(&mut self) {
+        if self.gc.enabled && self.gc_stats.allocated_since_collection >= self.gc.threshold {
+            self.collect_garbage();
+        }
+    }
+
+    /// Frees every heap block that is not reachable from the stack, the constant pool or a
+    /// call frame, following pointers stored in heap cells. Returns the number of blocks freed.
+    ///
+    /// Handles held only by the host (e.g. captured in a host function) are not roots.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut blocks: Vec<(Handle, u16)> = self
+            .allocated_memory
+            .iter()
+            .filter(|(start, _)| start.region == PointerType::StackValuesPointer)
+            .map(|(start, (size, _))| (*start, *size))
+            .collect();
+        blocks.sort_by_key(|(start, _)| start.offset);
+
+        let mut pending: Vec<StackValues> = Vec::new();
+        if self.sp >= 0 {
+            pending.extend_from_slice(&self.stack[..=self.sp as usize]);
+        }
+        pending.extend_from_slice(&self.constant_pools);
+        pending.extend_from_slice(&self.frame_slots);
+
+        let mut marked: HashSet<Handle> = HashSet::new();
+        while let Some(value) = pending.pop() {
+            let StackValues::Pointer(ptr) = value else {
+                continue;
+            };
+            if let Some((start, size)) = Self::containing_block(&blocks, ptr)
+                && marked.insert(start)
+            {
+                pending.extend_from_slice(&self.heap[start.offset..start.offset + size as usize]);
+            }
+        }
+
+        let garbage: Vec<(Handle, u16)> = blocks
+            .into_iter()
+            .filter(|(start, _)| !marked.contains(start))
+            .collect();
+        for (start, size) in garbage.iter() {
+            // Reused blocks start out zeroed, like freshly grown ones.
+            self.heap[start.offset..start.offset + *size as usize].fill(StackValues::U16(0));
+            self.deallocate(*start);
+            self.gc_stats.cells_freed += *size as usize;
+        }
+        self.gc_stats.blocks_freed += garbage.len();
+        self.gc_stats.collections += 1;
+        self.gc_stats.allocated_since_collection = 0;
+        garbage.len()
+    }
+
+    /// Finds the heap block `ptr` points into, including pointers to the middle of a block.
+    fn containing_block(blocks: &[(Handle, u16)], ptr: Handle) -> Option<(Handle, u16)> {
+        if ptr.region != PointerType::StackValuesPointer {
+            return None;
+        }
+        let index = blocks.partition_point(|(start, _)| start.offset <= ptr.offset);
+        let (start, size) = *blocks.get(index.checked_sub(1)?)?;
+        (ptr.offset < start.offset + size as usize).then_some((start, size))
+    }
+
+    /// Heap cells currently handed out to live allocations.
+    pub fn live_heap_cells(&self) -> usize {
+        self.allocated_memory
+            .iter()
+            .filter(|(start, _)| start.region == PointerType::StackValuesPointer)
+            .map(|(_, (size, _))| *size as usize)
+            .sum()
+    }
+}
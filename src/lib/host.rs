@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use half::f16;
+
+use super::machine_type::{Handle, QuarkVM, StackValues, VmError};
+
+type HostFn = dyn FnMut(&mut QuarkVM, &[StackValues]) -> Result<Option<StackValues>, VmError>;
+
+/// A Rust function callable from QASM through `HOST_CALL "name"`.
+///
+/// `HOST_CALL` pops `arity` values; the first value popped becomes the first argument,
+/// the same order `DLL_CALL` and `STD_SYSCALL` use. A returned value is pushed back.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub arity: usize,
+    pub function: Rc<RefCell<HostFn>>,
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFunction").field("arity", &self.arity).finish()
+    }
+}
+
+/// Converts a stack value into a Rust argument for a host function.
+pub trait FromStackValue: Sized {
+    fn from_stack_value(vm: &QuarkVM, value: StackValues) -> Result<Self, VmError>;
+}
+
+/// Converts a host function's return value into what gets pushed onto the stack.
+pub trait IntoStackValue {
+    fn into_stack_value(self, vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError>;
+}
+
+impl FromStackValue for StackValues {
+    fn from_stack_value(_vm: &QuarkVM, value: StackValues) -> Result<Self, VmError> {
+        Ok(value)
+    }
+}
+
+macro_rules! impl_from_stack_value {
+    ($($ty:ty => $variant:ident, $name:literal);* $(;)?) => {
+        $(
+            impl FromStackValue for $ty {
+                fn from_stack_value(_vm: &QuarkVM, value: StackValues) -> Result<Self, VmError> {
+                    match value {
+                        StackValues::$variant(v) => Ok(v),
+                        other => Err(VmError::TypeMismatch { expected: $name, found: other }),
+                    }
+                }
+            }
+
+            impl IntoStackValue for $ty {
+                fn into_stack_value(self, _vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+                    Ok(Some(StackValues::$variant(self)))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_stack_value! {
+    u16 => U16, "u16";
+    i16 => I16, "i16";
+    u32 => U32, "u32";
+    i32 => I32, "i32";
+    f16 => F16, "f16";
+    char => Char, "char";
+    bool => Bool, "bool";
+}
+
+impl FromStackValue for Handle {
+    fn from_stack_value(_vm: &QuarkVM, value: StackValues) -> Result<Self, VmError> {
+        match value {
+            StackValues::Pointer(ptr) => Ok(ptr),
+            other => Err(VmError::TypeMismatch { expected: "pointer", found: other }),
+        }
+    }
+}
+
+/// Reads a NUL-terminated string the argument points to.
+impl FromStackValue for String {
+    fn from_stack_value(vm: &QuarkVM, value: StackValues) -> Result<Self, VmError> {
+        let ptr = Handle::from_stack_value(vm, value)?;
+        vm.get_str_from_ptr(ptr)
+    }
+}
+
+impl IntoStackValue for () {
+    fn into_stack_value(self, _vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        Ok(None)
+    }
+}
+
+impl IntoStackValue for StackValues {
+    fn into_stack_value(self, _vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        Ok(Some(self))
+    }
+}
+
+impl IntoStackValue for Handle {
+    fn into_stack_value(self, _vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        Ok(Some(StackValues::Pointer(self)))
+    }
+}
+
+/// Copies the string into the heap and pushes a pointer to it.
+impl IntoStackValue for String {
+    fn into_stack_value(self, vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        Ok(Some(StackValues::Pointer(vm.allocate_str(&self)?)))
+    }
+}
+
+impl IntoStackValue for &str {
+    fn into_stack_value(self, vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        Ok(Some(StackValues::Pointer(vm.allocate_str(self)?)))
+    }
+}
+
+impl<T: IntoStackValue> IntoStackValue for Option<T> {
+    fn into_stack_value(self, vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        match self {
+            Some(value) => value.into_stack_value(vm),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Lets host functions fail; the error is reported as a fault at the `HOST_CALL`.
+impl<T: IntoStackValue> IntoStackValue for Result<T, VmError> {
+    fn into_stack_value(self, vm: &mut QuarkVM) -> Result<Option<StackValues>, VmError> {
+        self?.into_stack_value(vm)
+    }
+}
+
+/// Implemented for closures whose arguments and return type can be marshalled.
+pub trait IntoHostFunction<Args> {
+    fn into_host_function(self) -> HostFunction;
+}
+
+macro_rules! impl_into_host_function {
+    ($count:expr $(, $arg:ident)*) => {
+        impl<F, R $(, $arg)*> IntoHostFunction<($($arg,)*)> for F
+        where
+            F: FnMut($($arg),*) -> R + 'static,
+            R: IntoStackValue,
+            $($arg: FromStackValue,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_host_function(mut self) -> HostFunction {
+                HostFunction {
+                    arity: $count,
+                    function: Rc::new(RefCell::new(move |vm: &mut QuarkVM, args: &[StackValues]| {
+                        let mut args = args.iter();
+                        $(
+                            let $arg = $arg::from_stack_value(
+                                vm,
+                                *args.next().ok_or(VmError::StackUnderflow)?,
+                            )?;
+                        )*
+                        self($($arg),*).into_stack_value(vm)
+                    })),
+                }
+            }
+        }
+    };
+}
+
+impl_into_host_function!(0);
+impl_into_host_function!(1, A);
+impl_into_host_function!(2, A, B);
+impl_into_host_function!(3, A, B, C);
+impl_into_host_function!(4, A, B, C, D);
+impl_into_host_function!(5, A, B, C, D, E);
+impl_into_host_function!(6, A, B, C, D, E, G);
+
+impl QuarkVM {
+    /// Registers a Rust closure callable from QASM as `HOST_CALL "name"`.
+    ///
+    /// Arguments and the return value are converted with `FromStackValue` and
+    /// `IntoStackValue`, so `|a: u16, b: u16| a + b` or `|s: String| s.len() as u16` work as is.
+    /// Registering the same name again replaces the previous function.
+    pub fn register_host_function<Args, F: IntoHostFunction<Args>>(&mut self, name: &str, function: F) {
+        self.host_functions
+            .insert(name.to_string(), function.into_host_function());
+    }
+
+    /// Registers a host function that works on raw stack values and has access to the VM,
+    /// e.g. to read or allocate memory itself.
+    pub fn register_raw_host_function<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: FnMut(&mut QuarkVM, &[StackValues]) -> Result<Option<StackValues>, VmError> + 'static,
+    {
+        self.host_functions.insert(
+            name.to_string(),
+            HostFunction {
+                arity,
+                function: Rc::new(RefCell::new(function)),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bytecode::Program;
+    use super::super::machine_type::{DEFINE_HOST_CALL, QuarkVM, VmError};
+
+    #[test]
+    fn a_host_function_stepping_into_itself_faults() {
+        let mut vm = QuarkVM::default();
+        vm.load_program(Program::new(vec![DEFINE_HOST_CALL("again")])).unwrap();
+        vm.register_raw_host_function("again", 0, |vm, _| {
+            vm.step().map_err(|fault| fault.error)?;
+            Ok(None)
+        });
+        let fault = vm.run().unwrap_err();
+        assert!(matches!(fault.error, VmError::ReentrantHostCall(ref name) if name == "again"));
+    }
+}
@@ -1,20 +1,38 @@
-use super::bytecode::ByteCodeCompiler;
-use core::{arch::asm, panic};
+use super::bytecode::{ByteCodeCompiler, BytecodeError, DataInit, DebugInfo, Program, Symbol};
+use super::ffi::Signature;
+use super::gc::{GcConfig, GcStats};
+use super::host::HostFunction;
+use super::sandbox::{SyscallPolicy, SyscallRecord};
+use core::panic;
 use half::f16;
-use libffi::{self, high::Arg};
-use libloading::{Library, Symbol};
+use libloading::Library;
 use std::fs::File;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::{
+    cmp::Ordering,
     collections::{HashMap, VecDeque},
-    ops::Deref,
-    os::fd::{AsRawFd, RawFd},
-    process::exit,
+    fmt,
+    time::Instant,
 };
-use libc;
 
-const MAX_STACK_SIZE: usize = 4096;
+/// Values the operand stack holds unless `Limits::stack_size` says otherwise.
+pub const DEFAULT_STACK_SIZE: usize = 4096;
+/// Nested `CALL`s allowed unless `Limits::call_depth` says otherwise.
+pub const DEFAULT_CALL_DEPTH: usize = 1024;
+/// How many instructions `run` executes between two looks at the clock.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+/// Number of slots `LOAD`/`STORE` can address.
+pub const CONSTANT_POOL_SIZE: usize = 4096;
+/// Arguments a `SYSCALL` can pass, the most any supported architecture takes in registers.
+pub const MAX_SYSCALL_ARGS: usize = 6;
+/// Instructions the assembler and the linker put in front of a program with a `main`.
+pub const ENTRY_PROLOGUE_LEN: usize = 2;
+
+/// The size to `allocate` for `cells` cells, if one allocation can hold that many.
+pub fn block_size(cells: usize) -> Result<u16, VmError> {
+    u16::try_from(cells).map_err(|_| VmError::AllocationTooLarge(cells))
+}
 
 #[derive(Debug, Clone)]
 pub enum Word {
@@ -22,15 +40,36 @@ pub enum Word {
     U16(u16),
     F16(f16),
     I16(i16),
+    U32(u32),
+    I32(i32),
+    Bool(bool),
 }
 
 impl Word {
-    pub fn to_be_bytes(&self) -> [u8; 2] {
+    /// The operand type tag written in front of the value in bytecode.
+    pub fn tag(&self) -> u8 {
         match self {
-            Self::U16(x) => x.to_be_bytes(),
-            Self::F16(x) => x.to_be_bytes(),
-            Self::I16(x) => x.to_be_bytes(),
-            Self::Char(c) => (*c as u16).to_be_bytes(),
+            Self::U16(_) => 0,
+            Self::F16(_) => 1,
+            Self::Char(_) => 2,
+            Self::I16(_) => 3,
+            Self::U32(_) => 4,
+            Self::I32(_) => 5,
+            Self::Bool(_) => 6,
+        }
+    }
+
+    /// Big-endian encoding of the value: 2 bytes for 16-bit words, 4 for `U32`, `I32`
+    /// and `Char` (a full Unicode scalar value), 1 for `Bool`.
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::U16(x) => x.to_be_bytes().to_vec(),
+            Self::F16(x) => x.to_be_bytes().to_vec(),
+            Self::I16(x) => x.to_be_bytes().to_vec(),
+            Self::Char(c) => (*c as u32).to_be_bytes().to_vec(),
+            Self::U32(x) => x.to_be_bytes().to_vec(),
+            Self::I32(x) => x.to_be_bytes().to_vec(),
+            Self::Bool(b) => vec![*b as u8],
         }
     }
 }
@@ -59,54 +98,307 @@ impl From<i16> for Word {
     }
 }
 
+impl From<u32> for Word {
+    fn from(value: u32) -> Self {
+        Self::U32(value)
+    }
+}
+
+impl From<i32> for Word {
+    fn from(value: i32) -> Self {
+        Self::I32(value)
+    }
+}
+
+impl From<bool> for Word {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum StackValues {
     U16(u16),
     I16(i16),
-    Pointer(*mut ()),
+    U32(u32),
+    I32(i32),
+    F16(f16),
+    Char(char),
+    Bool(bool),
+    Pointer(Handle),
+    /// An address that lives outside the VM, e.g. returned by a native syscall or a DLL call.
+    ForeignPointer(*mut ()),
+}
+
+impl From<&Word> for StackValues {
+    fn from(word: &Word) -> Self {
+        match *word {
+            Word::U16(v) => Self::U16(v),
+            Word::I16(v) => Self::I16(v),
+            Word::U32(v) => Self::U32(v),
+            Word::I32(v) => Self::I32(v),
+            Word::F16(v) => Self::F16(v),
+            Word::Char(v) => Self::Char(v),
+            Word::Bool(v) => Self::Bool(v),
+        }
+    }
+}
+
+/// Pointers have no bytecode encoding, so only plain values convert back to a `Word`.
+impl TryFrom<StackValues> for Word {
+    type Error = StackValues;
+
+    fn try_from(value: StackValues) -> Result<Self, Self::Error> {
+        match value {
+            StackValues::U16(v) => Ok(Self::U16(v)),
+            StackValues::I16(v) => Ok(Self::I16(v)),
+            StackValues::U32(v) => Ok(Self::U32(v)),
+            StackValues::I32(v) => Ok(Self::I32(v)),
+            StackValues::F16(v) => Ok(Self::F16(v)),
+            StackValues::Char(v) => Ok(Self::Char(v)),
+            StackValues::Bool(v) => Ok(Self::Bool(v)),
+            other => Err(other),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
 pub enum PointerType {
     RawPointer,
     StackValuesPointer,
 }
 
+/// A stable reference into one of the VM's memory regions.
+///
+/// `region` selects the backing store (`memory` for raw pointers, `heap` for stack values)
+/// and `offset` is the index into it, so handles stay valid when the region reallocates.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct Handle {
+    pub region: PointerType,
+    pub offset: usize,
+}
+
+impl Handle {
+    pub fn new(region: PointerType, offset: usize) -> Self {
+        Self { region, offset }
+    }
+
+    pub fn wrapping_add(self, count: usize) -> Self {
+        Self::new(self.region, self.offset.wrapping_add(count))
+    }
+
+    pub fn wrapping_sub(self, count: usize) -> Self {
+        Self::new(self.region, self.offset.wrapping_sub(count))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum VmError {
+    StackUnderflow,
+    StackOverflow,
+    /// More nested calls than `Limits::call_depth` allows.
+    CallStackOverflow(usize),
+    TypeMismatch { expected: &'static str, found: StackValues },
+    InvalidPointer(Handle),
+    /// More cells than one allocation can hold.
+    AllocationTooLarge(usize),
+    DivisionByZero,
+    UnknownSyscall(u16),
+    TooManySyscallArgs(u16),
+    SyscallDenied { number: u16, reason: String },
+    FfiFailure(String),
+    UnknownHostFunction(String),
+    /// A host function ran `HOST_CALL` on itself while it was still running.
+    ReentrantHostCall(String),
+    HostFailure(String),
+    Io(String),
+    MissingOperand,
+    InvalidOperand(Word),
+    UnsupportedInstruction(InstructionType),
+    PcOutOfBounds,
+    NoActiveFrame,
+    DoubleFree(Handle),
+    InvalidFree(Handle),
+    InvalidFrameSlot { kind: &'static str, index: u16, count: u16 },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StackUnderflow => write!(f, "stack underflow"),
+            Self::StackOverflow => write!(f, "stack overflow"),
+            Self::CallStackOverflow(depth) => write!(f, "call stack overflow: more than {} nested calls", depth),
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {}, found {:?}", expected, found)
+            }
+            Self::InvalidPointer(ptr) => write!(f, "invalid pointer {:?}", ptr),
+            Self::AllocationTooLarge(cells) => {
+                write!(f, "{} cells do not fit in one allocation of at most {}", cells, u16::MAX)
+            }
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::UnknownSyscall(id) => write!(f, "unknown syscall {}", id),
+            Self::TooManySyscallArgs(count) => write!(f, "a syscall takes at most {} arguments, not {}", MAX_SYSCALL_ARGS, count),
+            Self::SyscallDenied { number, reason } => write!(f, "syscall {} denied: {}", number, reason),
+            Self::FfiFailure(reason) => write!(f, "FFI failure: {}", reason),
+            Self::UnknownHostFunction(name) => write!(f, "no host function named `{}`", name),
+            Self::ReentrantHostCall(name) => write!(f, "host function `{}` called itself while running", name),
+            Self::HostFailure(reason) => write!(f, "host function failed: {}", reason),
+            Self::Io(reason) => write!(f, "I/O error: {}", reason),
+            Self::MissingOperand => write!(f, "missing operand"),
+            Self::InvalidOperand(word) => write!(f, "invalid operand {:?}", word),
+            Self::UnsupportedInstruction(tt) => write!(f, "unsupported instruction {:?}", tt),
+            Self::PcOutOfBounds => write!(f, "program counter out of bounds"),
+            Self::NoActiveFrame => write!(f, "no active call frame"),
+            Self::DoubleFree(ptr) => write!(f, "double free of {:?}", ptr),
+            Self::InvalidFree(ptr) => {
+                write!(f, "{:?} is not the start of a live allocation", ptr)
+            }
+            Self::InvalidFrameSlot { kind, index, count } => {
+                write!(f, "{} {} out of range, the frame has {}", kind, index, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// A `VmError` together with the location where execution stopped.
+#[derive(Debug, Clone)]
+pub struct VmFault {
+    pub pc: u16,
+    pub instruction: Option<InstructionType>,
+    pub error: VmError,
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.instruction {
+            Some(tt) => write!(f, "QUARKVM: fault at pc {} ({:?}): {}", self.pc, tt, self.error),
+            None => write!(f, "QUARKVM: fault at pc {}: {}", self.pc, self.error),
+        }
+    }
+}
+
+impl std::error::Error for VmFault {}
+
+/// One activation record on the call stack, pushed by `CALL` and popped by `RET`.
+///
+/// `fp` is where the frame's slots start in `QuarkVM::frame_slots`: `args` arguments
+/// followed by `locals` locals, both sized by the function's `ENTER`.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub return_pc: u16,
+    pub fp: usize,
+    pub args: u16,
+    pub locals: u16,
+}
+
+/// How deep the operand stack and the call stack may grow, and how long `run` may take.
+/// Exceeding a stack limit is a `StackOverflow` or `CallStackOverflow` fault rather than
+/// a panic or runaway memory use.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// At most `i16::MAX` values are usable, as `sp` is an `i16`.
+    pub stack_size: usize,
+    pub call_depth: usize,
+    /// `run` stops with `RunStatus::DeadlineExceeded` once this has passed. The clock is
+    /// read every `DEADLINE_CHECK_INTERVAL` instructions, so it may overshoot slightly.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            call_depth: DEFAULT_CALL_DEPTH,
+            deadline: None,
+        }
+    }
+}
+
+/// Why `run` returned without a fault. Only `Halted` is final: after `OutOfFuel` or
+/// `DeadlineExceeded` the VM is left before the next instruction, and calling `run`
+/// again once more fuel or time was granted continues from there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The program returned from its entry point or ran off the end.
+    Halted,
+    OutOfFuel,
+    DeadlineExceeded,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    /// Instructions executed by the current or most recent call to `run`.
+    pub instructions: u64,
+    /// Instructions executed by `run` since the program was loaded.
+    pub total_instructions: u64,
+}
+
 #[derive(Debug)]
 pub struct QuarkVM {
-    pub stack: [StackValues; MAX_STACK_SIZE],
+    /// Grows on demand up to `limits.stack_size`; only `stack[..=sp]` is live.
+    pub stack: Vec<StackValues>,
     pub memory: Vec<u8>,
     pub heap: Vec<StackValues>,
-    pub constant_pools: [StackValues; 4096],
-    pub call_stack: Vec<u16>,
-    pub free_list: Vec<(*mut (), (u16, PointerType))>,
-    pub allocated_memory: HashMap<*mut (), (u16, PointerType)>,
+    pub constant_pools: [StackValues; CONSTANT_POOL_SIZE],
+    pub call_stack: Vec<Frame>,
+    pub frame_slots: Vec<StackValues>,
+    pub free_list: Vec<(Handle, (u16, PointerType))>,
+    pub allocated_memory: HashMap<Handle, (u16, PointerType)>,
     pub sp: i16,
     pub pc: u16,
     pub running: bool,
     pub instructions: Vec<Rc<Instruction>>,
+    pub symbols: Vec<Symbol>,
+    pub debug_info: DebugInfo,
     pub byte_code_file: Option<ByteCodeCompiler>,
-    pub fd_table: HashMap<u16, i32>,
+    /// Files opened with the `open` standard call, by the handle the program got back.
+    pub fd_table: HashMap<u16, File>,
     pub dlls: Rc<RefCell<Vec<Library>>>,
+    pub host_functions: HashMap<String, HostFunction>,
+    pub gc: GcConfig,
+    pub gc_stats: GcStats,
+    pub limits: Limits,
+    /// Instructions `run` may still execute; `None` means no budget.
+    pub fuel: Option<u64>,
+    pub run_stats: RunStats,
+    pub syscall_policy: SyscallPolicy,
+    /// Refused raw syscalls, and allowed ones if the policy asks for it.
+    pub syscall_log: Vec<SyscallRecord>,
+    /// Set by the `exit` standard call, which stops the program.
+    pub exit_code: Option<i32>,
 }
 
 impl Default for QuarkVM {
     fn default() -> Self {
         Self {
-            stack: [StackValues::U16(0); MAX_STACK_SIZE],
+            stack: vec![],
             memory: vec![],
             heap: vec![],
-            constant_pools: [StackValues::U16(0); 4096],
+            constant_pools: [StackValues::U16(0); CONSTANT_POOL_SIZE],
             call_stack: vec![],
+            frame_slots: vec![],
             free_list: vec![],
             allocated_memory: HashMap::new(),
             sp: -1,
             pc: 0,
             running: false,
             instructions: vec![],
+            symbols: vec![],
+            debug_info: DebugInfo::default(),
             byte_code_file: None,
             fd_table: HashMap::new(),
             dlls: Rc::new(RefCell::new(Vec::new())),
+            host_functions: HashMap::new(),
+            gc: GcConfig::default(),
+            gc_stats: GcStats::default(),
+            limits: Limits::default(),
+            fuel: None,
+            run_stats: RunStats::default(),
+            syscall_policy: SyscallPolicy::default(),
+            syscall_log: Vec::new(),
+            exit_code: None,
         }
     }
 }
@@ -114,11 +406,12 @@ impl Default for QuarkVM {
 impl QuarkVM {
     pub fn new(byte_code_compiler: ByteCodeCompiler) -> Self {
         Self {
-            stack: [StackValues::U16(0); MAX_STACK_SIZE],
+            stack: Vec::new(),
             memory: Vec::new(),
             heap: Vec::new(),
-            constant_pools: [StackValues::U16(0); 4096],
+            constant_pools: [StackValues::U16(0); CONSTANT_POOL_SIZE],
             call_stack: Vec::new(),
+            frame_slots: Vec::new(),
             free_list: Vec::new(),
             allocated_memory: HashMap::new(),
             sp: -1,
@@ -126,28 +419,60 @@ impl QuarkVM {
             running: true,
             fd_table: HashMap::new(),
             dlls: Rc::new(RefCell::new(Vec::new())),
+            host_functions: HashMap::new(),
+            gc: GcConfig::default(),
+            gc_stats: GcStats::default(),
+            limits: Limits::default(),
+            fuel: None,
+            run_stats: RunStats::default(),
+            syscall_policy: SyscallPolicy::default(),
+            syscall_log: Vec::new(),
+            exit_code: None,
             instructions: vec![],
+            symbols: vec![],
+            debug_info: DebugInfo::default(),
             byte_code_file: Some(byte_code_compiler),
         }
     }
 
-    pub fn pop_stack(&mut self) -> StackValues {
+    pub fn pop_stack(&mut self) -> Result<StackValues, VmError> {
+        if self.sp < 0 {
+            return Err(VmError::StackUnderflow);
+        }
         let popped_value = self.stack[self.sp as usize];
         self.sp -= 1;
-        popped_value
+        Ok(popped_value)
     }
 
-    pub fn push_stack(&mut self, value: StackValues) {
+    pub fn push_stack(&mut self, value: StackValues) -> Result<(), VmError> {
+        let top = (self.sp + 1) as usize;
+        if top >= self.limits.stack_size.min(i16::MAX as usize) {
+            return Err(VmError::StackOverflow);
+        }
+        if top == self.stack.len() {
+            self.stack.push(value);
+        } else {
+            self.stack[top] = value;
+        }
         self.sp += 1;
-        self.stack[self.sp as usize] = value;
+        Ok(())
+    }
+
+    /// Reads the value `depth` slots below the top of the stack without popping it.
+    pub fn peek_stack(&self, depth: usize) -> Result<StackValues, VmError> {
+        let index = self.sp as isize - depth as isize;
+        if index < 0 {
+            return Err(VmError::StackUnderflow);
+        }
+        Ok(self.stack[index as usize])
     }
 
-    pub fn store_file(&mut self) {
+    pub fn store_file(&mut self) -> Result<(), BytecodeError> {
         match &mut self.byte_code_file {
             Some(bc) => {
                 let cloned: Vec<Instruction> =
                     self.instructions.iter().map(|rc| (**rc).clone()).collect();
-                bc.store_file(&cloned)
+                bc.store_file(&Program::new(cloned))
             }
             None => {
                 panic!("QUARMVM: Error while storing to file, bytecode compiler not provided.")
@@ -155,10 +480,14 @@ impl QuarkVM {
         }
     }
 
-    pub fn load_file(&mut self) {
+    pub fn load_file(&mut self) -> Result<(), BytecodeError> {
         match &mut self.byte_code_file {
             Some(bc) => {
-                self.instructions = bc.load_file().into_iter().map(|ins| Rc::new(ins)).collect();
+                let program = bc.load_file()?;
+                if program.link.is_some() {
+                    return Err(BytecodeError::NotExecutable);
+                }
+                self.load_program(program)
             }
             None => {
                 panic!("QUARMVM: Error while storing to file, bytecode compiler not provided.")
@@ -166,20 +495,56 @@ impl QuarkVM {
         }
     }
 
-    pub fn allocate(&mut self, size: u16, pointer_type: PointerType) -> Result<*mut (), ()> {
-        for (i, (ptr, (free_size, _))) in self.free_list.iter_mut().enumerate() {
-            if *free_size >= size {
-                let allocated_start = *ptr;
-                *ptr = ptr.wrapping_add(size.into());
-                *free_size -= size;
-                if *free_size == 0 {
-                    self.free_list.remove(i);
-                }
-                self.allocated_memory
-                    .insert(allocated_start, (size, pointer_type));
-                println!("RETURNING FROM FREELIST");
-                return Ok(allocated_start);
+    /// Loads an already decoded program and resets the program counter to its start.
+    /// Static data is placed on the heap and its address stored in the item's pool slot.
+    /// Nothing is loaded if a data item doesn't fit its slot or one allocation.
+    pub fn load_program(&mut self, program: Program) -> Result<(), BytecodeError> {
+        let mut sizes = Vec::with_capacity(program.data.len());
+        for item in program.data.iter() {
+            if item.slot as usize >= CONSTANT_POOL_SIZE {
+                return Err(BytecodeError::SlotOutOfRange(item.slot));
+            }
+            let cells = item.init.len();
+            sizes.push(block_size(cells).map_err(|_| BytecodeError::DataTooLarge { name: item.name.clone(), cells })?);
+        }
+        for (item, size) in program.data.into_iter().zip(sizes) {
+            let cells: Vec<StackValues> = match &item.init {
+                DataInit::Words(words) => words.iter().map(StackValues::from).collect(),
+                DataInit::Zeroed(count) => vec![StackValues::U16(0); *count as usize],
+            };
+            let ptr = Handle::new(PointerType::StackValuesPointer, self.heap.len());
+            self.heap.extend(cells);
+            self.allocated_memory.insert(ptr, (size, PointerType::StackValuesPointer));
+            self.constant_pools[item.slot as usize] = StackValues::Pointer(ptr);
+        }
+        self.instructions = program.instructions.into_iter().map(Rc::new).collect();
+        self.symbols = program.symbols;
+        self.debug_info = program.debug_info;
+        self.pc = 0;
+        self.running = true;
+        self.run_stats = RunStats::default();
+        Ok(())
+    }
+
+    pub fn allocate(&mut self, size: u16, pointer_type: PointerType) -> Result<Handle, VmError> {
+        if pointer_type == PointerType::StackValuesPointer {
+            self.gc_stats.allocated_since_collection += size as usize;
+        }
+        if let Some(i) = self
+            .free_list
+            .iter()
+            .position(|(ptr, (free_size, _))| ptr.region == pointer_type && *free_size >= size)
+        {
+            let (ptr, (free_size, _)) = &mut self.free_list[i];
+            let allocated_start = *ptr;
+            *ptr = ptr.wrapping_add(size.into());
+            *free_size -= size;
+            if *free_size == 0 {
+                self.free_list.remove(i);
             }
+            self.allocated_memory
+                .insert(allocated_start, (size, pointer_type));
+            return Ok(allocated_start);
         }
 
         let starting_index = match pointer_type {
@@ -188,446 +553,556 @@ impl QuarkVM {
         };
 
         if pointer_type == PointerType::StackValuesPointer {
-            for _ in 0..size {
-                self.heap.push(StackValues::U16(0));
-            }
-            self.allocated_memory.insert(
-                &mut self.heap[starting_index] as *mut StackValues as *mut (),
-                (size, pointer_type),
-            );
-            Ok(&mut self.heap[starting_index] as *mut StackValues as *mut ())
+            self.heap
+                .resize(starting_index + size as usize, StackValues::U16(0));
         } else {
-            for _ in 0..size {
-                self.memory.push(0);
-            }
-            self.allocated_memory.insert(
-                &mut self.memory[starting_index] as *mut u8 as *mut (),
-                (size, pointer_type),
-            );
-            Ok(&mut self.memory[starting_index] as *mut u8 as *mut ())
+            self.memory.resize(starting_index + size as usize, 0);
         }
+        let handle = Handle::new(pointer_type, starting_index);
+        self.allocated_memory.insert(handle, (size, pointer_type));
+        Ok(handle)
     }
 
-    pub fn deallocate(&mut self, ptr: *mut ()) {
+    /// Returns the block starting at `ptr` to the free list, merging it with adjacent free blocks.
+    /// Does nothing if `ptr` is not the start of a live allocation; see `free` for the checked version.
+    pub fn deallocate(&mut self, ptr: Handle) {
         let removed_value = self.allocated_memory.remove(&ptr);
-        if let Some(freed_size) = removed_value {
-            self.free_list
-                .push((ptr, (freed_size.0, PointerType::RawPointer)));
+        if let Some((freed_size, pointer_type)) = removed_value {
+            self.free_list.push((ptr, (freed_size, pointer_type)));
         }
 
-        self.free_list.sort_by_key(|&(ptr, _)| ptr as usize);
+        self.free_list.sort_by_key(|&(ptr, _)| ptr);
+
+        let mut new_free_list: Vec<(Handle, (u16, PointerType))> = Vec::new();
+        for &(start, (size, pointer_type)) in self.free_list.iter() {
+            if let Some((last_start, (last_size, last_type))) = new_free_list.last_mut()
+                && *last_type == pointer_type
+                && last_start.region == start.region
+                && last_start.wrapping_add(*last_size as usize) == start
+                && let Some(merged) = last_size.checked_add(size)
+            {
+                *last_size = merged;
+                continue;
+            }
+            new_free_list.push((start, (size, pointer_type)));
+        }
 
-        let mut new_free_list: Vec<(*mut (), (u16, PointerType))> = Vec::new();
-        let mut i = 0;
+        self.free_list = new_free_list;
+    }
 
-        while i < self.free_list.len() {
-            let (start1, (size1, ptr1type)) = &self.free_list[i];
+    /// Frees the block `ptr` points to, as `FREE` and `FREE_RAW` do. `ptr` must be the start
+    /// of a live block in `region`; freeing a block twice is reported as `DoubleFree`.
+    pub fn free(&mut self, ptr: Handle, region: PointerType) -> Result<(), VmError> {
+        if ptr.region != region {
+            let expected = match region {
+                PointerType::RawPointer => "raw pointer",
+                PointerType::StackValuesPointer => "heap pointer",
+            };
+            return Err(VmError::TypeMismatch { expected, found: StackValues::Pointer(ptr) });
+        }
+        if self.allocated_memory.contains_key(&ptr) {
+            self.deallocate(ptr);
+            return Ok(());
+        }
+        let already_freed = self.free_list.iter().any(|(start, (size, _))| {
+            start.region == ptr.region
+                && ptr.offset >= start.offset
+                && ptr.offset < start.offset + *size as usize
+        });
+        if already_freed {
+            Err(VmError::DoubleFree(ptr))
+        } else {
+            Err(VmError::InvalidFree(ptr))
+        }
+    }
 
-            if i + 1 < self.free_list.len() {
-                let (start2, (size2, ptr2type)) = &self.free_list[i + 1];
+    /// Copies `string` into a fresh heap block, one `Char` cell per character followed by
+    /// a `Char('\0')`, the same layout as a `.string` data item.
+    pub fn allocate_str(&mut self, string: &str) -> Result<Handle, VmError> {
+        let mut str_buffer: Vec<StackValues> = string.chars().map(StackValues::Char).collect();
+        str_buffer.push(StackValues::Char('\0'));
+        let ptr = self.allocate(block_size(str_buffer.len())?, PointerType::StackValuesPointer)?;
+        self.heap[ptr.offset..ptr.offset + str_buffer.len()].copy_from_slice(&str_buffer);
+        Ok(ptr)
+    }
 
-                if ptr1type == ptr2type {
-                    let can_merge = match ptr1type {
-                        PointerType::StackValuesPointer => {
-                            (*start1 as *mut StackValues).wrapping_add(*size1 as usize)
-                                == (*start2 as *mut StackValues)
-                        }
-                        PointerType::RawPointer => {
-                            (*start1 as *mut u8).wrapping_add(*size1 as usize)
-                                == (*start2 as *mut u8)
-                        }
-                    };
+    /// Returns the start and size of the live allocation that `ptr` points into.
+    pub fn find_allocation(&self, ptr: Handle) -> Option<(Handle, u16)> {
+        self.allocated_memory
+            .iter()
+            .find(|(start, (size, _))| {
+                start.region == ptr.region
+                    && ptr.offset >= start.offset
+                    && ptr.offset < start.offset + *size as usize
+            })
+            .map(|(start, (size, _))| (*start, *size))
+    }
 
-                    if can_merge {
-                        new_free_list.push((*start1, (*size1 + *size2, *ptr1type)));
-                        i += 2;
-                        continue;
-                    }
-                }
-            }
+    /// Moves `ptr` by `delta` elements of its region, as long as it stays inside the same allocation.
+    pub fn offset_pointer(&self, ptr: Handle, delta: isize) -> Option<Handle> {
+        let (start, size) = self.find_allocation(ptr)?;
+        let offset = ptr.offset.checked_add_signed(delta)?;
+        if offset >= start.offset && offset < start.offset + size as usize {
+            Some(Handle::new(ptr.region, offset))
+        } else {
+            None
+        }
+    }
 
-            new_free_list.push((*start1, (*size1, *ptr1type)));
-            i += 1;
+    pub fn read_heap(&self, ptr: Handle) -> Option<StackValues> {
+        match ptr.region {
+            PointerType::StackValuesPointer => self.heap.get(ptr.offset).copied(),
+            PointerType::RawPointer => None,
         }
+    }
 
-        self.free_list = new_free_list;
+    pub fn write_heap(&mut self, ptr: Handle, value: StackValues) -> Option<()> {
+        match ptr.region {
+            PointerType::StackValuesPointer => {
+                *self.heap.get_mut(ptr.offset)? = value;
+                Some(())
+            }
+            PointerType::RawPointer => None,
+        }
+    }
+
+    /// Resolves a handle to a host address for native code (syscalls and DLL calls).
+    ///
+    /// The address is only valid until the next allocation in the same region.
+    pub fn native_address(&mut self, ptr: Handle) -> *mut () {
+        match ptr.region {
+            PointerType::RawPointer => self.memory.as_mut_ptr().wrapping_add(ptr.offset) as *mut (),
+            PointerType::StackValuesPointer => {
+                self.heap.as_mut_ptr().wrapping_add(ptr.offset) as *mut ()
+            }
+        }
     }
 
-    pub fn print(s: StackValues) {
+    pub fn print(&self, s: StackValues) {
         if let StackValues::Pointer(v) = s {
-            unsafe { println!("{:?}", *v) };
+            match v.region {
+                PointerType::StackValuesPointer => match self.heap.get(v.offset) {
+                    Some(value) => println!("{:?}", value),
+                    None => println!("{:?}", v),
+                },
+                PointerType::RawPointer => match self.memory.get(v.offset) {
+                    Some(byte) => println!("{:?}", byte),
+                    None => println!("{:?}", v),
+                },
+            }
         } else {
             println!("{:?}", s);
         }
     }
 
-    pub fn determine_function(&mut self) {
-        match self.instructions[self.pc as usize].tt {
-            InstructionType::INST_ADD => {
-                let t = self.pop_stack();
-                if let StackValues::U16(a) = t {
-                    let y = self.pop_stack();
-                    if let StackValues::U16(b) = y {
-                        self.push_stack(StackValues::U16(a + b));
-                    } else if let StackValues::Pointer(b) = y {
-                        for (&ptr, &(size, ptr_type)) in self.allocated_memory.clone().iter() {
-                            unsafe {
-                                let adjusted_ptr =
-                                    (b as *mut u8).wrapping_add(a as usize) as *mut ();
-                                if ptr_type == PointerType::RawPointer {
-                                    if (adjusted_ptr as *mut u8) >= (ptr as *mut u8)
-                                        && (adjusted_ptr as *mut u8)
-                                            <= (ptr as *mut u8).add(size as usize)
-                                    {
-                                        self.push_stack(StackValues::Pointer(adjusted_ptr));
-                                        break;
-                                    }
-                                } else if ptr_type == PointerType::StackValuesPointer
-                                    && (b as *mut StackValues) >= (ptr as *mut StackValues)
-                                    && (b as *mut StackValues)
-                                        < (ptr as *mut StackValues).add(size as usize)
-                                {
-                                    self.push_stack(StackValues::Pointer(
-                                        (b as *mut StackValues).wrapping_add(a as usize) as *mut (),
-                                    ));
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                } else if let StackValues::I16(a) = t {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a + b));
-                    }
-                } else if let StackValues::Pointer(a) = t {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        for (&ptr, &(size, ptr_type)) in self.allocated_memory.iter() {
-                            unsafe {
-                                let adjusted_ptr =
-                                    (a as *mut u8).wrapping_add(b as usize) as *mut ();
-
-                                if ptr_type == PointerType::RawPointer {
-                                    if (adjusted_ptr as *mut u8) >= (ptr as *mut u8)
-                                        && (adjusted_ptr as *mut u8)
-                                            < (ptr as *mut u8).add(size as usize)
-                                    {
-                                        self.push_stack(StackValues::Pointer(adjusted_ptr));
-                                        return;
-                                    }
-                                } else if ptr_type == PointerType::StackValuesPointer
-                                    && (adjusted_ptr as *mut StackValues)
-                                        >= (ptr as *mut StackValues)
-                                    && (adjusted_ptr as *mut StackValues)
-                                        < (ptr as *mut StackValues).add(size as usize)
-                                {
-                                    self.push_stack(StackValues::Pointer(adjusted_ptr));
-                                    return;
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    println!("WTF IS A : {:?}", t);
-                }
-                self.pc += 1;
+    fn operand(instruction: &Instruction, index: usize) -> Result<&Word, VmError> {
+        instruction
+            .values
+            .as_ref()
+            .and_then(|values| values.get(index))
+            .ok_or(VmError::MissingOperand)
+    }
+
+    fn operand_u16(instruction: &Instruction, index: usize) -> Result<u16, VmError> {
+        match Self::operand(instruction, index)? {
+            Word::U16(v) => Ok(*v),
+            other => Err(VmError::InvalidOperand(other.clone())),
+        }
+    }
+
+    /// Decodes a string operand stored as a length followed by one `Char` word per character.
+    fn string_operand(instruction: &Instruction) -> Result<String, VmError> {
+        let str_len = Self::operand_u16(instruction, 0)?;
+        let mut string = String::with_capacity(str_len as usize);
+        for i in 0..str_len as usize {
+            match Self::operand(instruction, 1 + i)? {
+                Word::Char(c) => string.push(*c),
+                other => return Err(VmError::InvalidOperand(other.clone())),
             }
-            InstructionType::INST_PUSH => match &self.instructions[self.pc as usize].values {
-                Some(value) => {
-                    if let Word::U16(v) = value[0] {
-                        self.push_stack(StackValues::U16(v));
-                    } else if let Word::I16(v) = value[0] {
-                        self.push_stack(StackValues::I16(v));
-                    }
-                    self.pc += 1;
-                }
-                None => {
-                    panic!(
-                        "QUARMVM: does not have a value to push {:?}",
-                        self.instructions[self.pc as usize]
-                    );
-                }
-            },
-            InstructionType::INST_POP => {
-                self.pop_stack();
-                self.pc += 1;
+        }
+        Ok(string)
+    }
+
+    fn jump_target(instruction: &Instruction) -> Result<u16, VmError> {
+        match Self::operand(instruction, 0)? {
+            Word::U16(v) => Ok(*v),
+            Word::I16(v) => Ok(*v as u16),
+            other => Err(VmError::InvalidOperand(other.clone())),
+        }
+    }
+
+    fn pop_pointer(&mut self) -> Result<Handle, VmError> {
+        match self.pop_stack()? {
+            StackValues::Pointer(ptr) => Ok(ptr),
+            other => Err(VmError::TypeMismatch { expected: "pointer", found: other }),
+        }
+    }
+
+    fn pop_u16(&mut self) -> Result<u16, VmError> {
+        match self.pop_stack()? {
+            StackValues::U16(v) => Ok(v),
+            other => Err(VmError::TypeMismatch { expected: "u16", found: other }),
+        }
+    }
+
+    /// Widens a `U16`/`U32` or `I16`/`I32` pair to the wider type, and a 16-bit integer
+    /// next to an `F16` to `F16`, so mixed arithmetic works. Other pairs are returned unchanged.
+    fn promote(a: StackValues, b: StackValues) -> (StackValues, StackValues) {
+        use StackValues::*;
+        match (a, b) {
+            (U16(a), U32(b)) => (U32(a.into()), U32(b)),
+            (U32(a), U16(b)) => (U32(a), U32(b.into())),
+            (I16(a), I32(b)) => (I32(a.into()), I32(b)),
+            (I32(a), I16(b)) => (I32(a), I32(b.into())),
+            (U16(a), F16(b)) => (F16(f16::from_f32(a.into())), F16(b)),
+            (I16(a), F16(b)) => (F16(f16::from_f32(a.into())), F16(b)),
+            (F16(a), U16(b)) => (F16(a), F16(f16::from_f32(b.into()))),
+            (F16(a), I16(b)) => (F16(a), F16(f16::from_f32(b.into()))),
+            pair => pair,
+        }
+    }
+
+    fn is_numeric(value: StackValues) -> bool {
+        matches!(
+            value,
+            StackValues::U16(_)
+                | StackValues::I16(_)
+                | StackValues::U32(_)
+                | StackValues::I32(_)
+                | StackValues::F16(_)
+        )
+    }
+
+    fn offset_char(c: char, offset: i64) -> Result<char, VmError> {
+        let code = c as i64 + offset;
+        u32::try_from(code)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(VmError::TypeMismatch { expected: "char", found: StackValues::I32(code as i32) })
+    }
+
+    /// Pops the top two values and pushes `top op second`, except for `SUB` which
+    /// computes `second - top`. A pointer and a `U16` offset may be added or subtracted.
+    fn binary_op(&mut self, op: InstructionType) -> Result<(), VmError> {
+        let a = self.pop_stack()?;
+        let b = self.pop_stack()?;
+        let result = match (a, b) {
+            (StackValues::U16(offset), StackValues::Pointer(ptr))
+            | (StackValues::Pointer(ptr), StackValues::U16(offset))
+                if matches!(op, InstructionType::INST_ADD) =>
+            {
+                StackValues::Pointer(
+                    self.offset_pointer(ptr, offset as isize)
+                        .ok_or(VmError::InvalidPointer(ptr))?,
+                )
             }
-            InstructionType::INST_MUL => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a * b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a * b));
-                    }
-                }
-                self.pc += 1;
+            (StackValues::U16(offset), StackValues::Pointer(ptr))
+                if matches!(op, InstructionType::INST_SUB) =>
+            {
+                StackValues::Pointer(
+                    self.offset_pointer(ptr, -(offset as isize))
+                        .ok_or(VmError::InvalidPointer(ptr))?,
+                )
             }
-            InstructionType::INST_DIV => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a / b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a / b));
+            (a, b) => Self::arithmetic(op, a, b)?,
+        };
+        self.push_stack(result)
+    }
+
+    /// Computes `top op second` for an arithmetic or bitwise instruction the way the VM
+    /// executes it, with `SUB` giving `second - top`. A `Char` and a `U16` may be added
+    /// or subtracted. Pointer arithmetic needs the heap and is left to `binary_op`.
+    pub fn arithmetic(op: InstructionType, a: StackValues, b: StackValues) -> Result<StackValues, VmError> {
+        let result = match Self::promote(a, b) {
+            (StackValues::U16(a), StackValues::U16(b)) => StackValues::U16(match op {
+                InstructionType::INST_ADD => a.wrapping_add(b),
+                InstructionType::INST_SUB => b.saturating_sub(a),
+                InstructionType::INST_MUL => a.wrapping_mul(b),
+                InstructionType::INST_DIV => a.checked_div(b).ok_or(VmError::DivisionByZero)?,
+                InstructionType::INST_AND => a & b,
+                InstructionType::INST_OR => a | b,
+                InstructionType::INST_XOR => a ^ b,
+                InstructionType::INST_SHL => a.wrapping_shl(b.into()),
+                InstructionType::INST_SHR => a.wrapping_shr(b.into()),
+                _ => return Err(VmError::UnsupportedInstruction(op)),
+            }),
+            (StackValues::I16(a), StackValues::I16(b)) => StackValues::I16(match op {
+                InstructionType::INST_ADD => a.wrapping_add(b),
+                InstructionType::INST_SUB => b.wrapping_sub(a),
+                InstructionType::INST_MUL => a.wrapping_mul(b),
+                InstructionType::INST_DIV => {
+                    if b == 0 {
+                        return Err(VmError::DivisionByZero);
                     }
+                    a.wrapping_div(b)
                 }
-                self.pc += 1;
-            }
-            InstructionType::INST_SUB => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        if (b as i16 - a as i16) < 0 {
-                            self.push_stack(StackValues::U16(0));
-                        } else {
-                            self.push_stack(StackValues::U16(b - a));
-                        }
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(b - a));
-                    }
-                } else if let StackValues::Pointer(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        if self.allocated_memory[&a].1 == PointerType::StackValuesPointer {
-                            self.push_stack(StackValues::Pointer(
-                                (a as *mut StackValues).wrapping_sub(b as usize) as *mut (),
-                            ));
-                        } else {
-                            self.push_stack(StackValues::Pointer(
-                                (a as *mut u8).wrapping_sub(b as usize) as *mut (),
-                            ));
-                        }
+                InstructionType::INST_AND => a & b,
+                InstructionType::INST_OR => a | b,
+                InstructionType::INST_XOR => a ^ b,
+                InstructionType::INST_SHL => a.wrapping_shl(b as u32),
+                InstructionType::INST_SHR => a.wrapping_shr(b as u32),
+                _ => return Err(VmError::UnsupportedInstruction(op)),
+            }),
+            (StackValues::U32(a), StackValues::U32(b)) => StackValues::U32(match op {
+                InstructionType::INST_ADD => a.wrapping_add(b),
+                InstructionType::INST_SUB => b.saturating_sub(a),
+                InstructionType::INST_MUL => a.wrapping_mul(b),
+                InstructionType::INST_DIV => a.checked_div(b).ok_or(VmError::DivisionByZero)?,
+                InstructionType::INST_AND => a & b,
+                InstructionType::INST_OR => a | b,
+                InstructionType::INST_XOR => a ^ b,
+                InstructionType::INST_SHL => a.wrapping_shl(b),
+                InstructionType::INST_SHR => a.wrapping_shr(b),
+                _ => return Err(VmError::UnsupportedInstruction(op)),
+            }),
+            (StackValues::I32(a), StackValues::I32(b)) => StackValues::I32(match op {
+                InstructionType::INST_ADD => a.wrapping_add(b),
+                InstructionType::INST_SUB => b.wrapping_sub(a),
+                InstructionType::INST_MUL => a.wrapping_mul(b),
+                InstructionType::INST_DIV => {
+                    if b == 0 {
+                        return Err(VmError::DivisionByZero);
                     }
+                    a.wrapping_div(b)
                 }
-                self.pc += 1;
+                InstructionType::INST_AND => a & b,
+                InstructionType::INST_OR => a | b,
+                InstructionType::INST_XOR => a ^ b,
+                InstructionType::INST_SHL => a.wrapping_shl(b as u32),
+                InstructionType::INST_SHR => a.wrapping_shr(b as u32),
+                _ => return Err(VmError::UnsupportedInstruction(op)),
+            }),
+            // Floats follow IEEE 754, so dividing by zero gives an infinity rather than a fault.
+            (StackValues::F16(a), StackValues::F16(b)) => StackValues::F16(match op {
+                InstructionType::INST_ADD => a + b,
+                InstructionType::INST_SUB => b - a,
+                InstructionType::INST_MUL => a * b,
+                InstructionType::INST_DIV => a / b,
+                _ => return Err(VmError::TypeMismatch { expected: "integer", found: StackValues::F16(a) }),
+            }),
+            (StackValues::Bool(a), StackValues::Bool(b)) => StackValues::Bool(match op {
+                InstructionType::INST_AND => a & b,
+                InstructionType::INST_OR => a | b,
+                InstructionType::INST_XOR => a ^ b,
+                _ => return Err(VmError::TypeMismatch { expected: "number", found: StackValues::Bool(a) }),
+            }),
+            (StackValues::U16(offset), StackValues::Char(c))
+            | (StackValues::Char(c), StackValues::U16(offset))
+                if matches!(op, InstructionType::INST_ADD) =>
+            {
+                StackValues::Char(Self::offset_char(c, offset.into())?)
             }
-            InstructionType::INST_AND => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a & b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a & b));
-                    }
-                }
+            (StackValues::U16(offset), StackValues::Char(c))
+                if matches!(op, InstructionType::INST_SUB) =>
+            {
+                StackValues::Char(Self::offset_char(c, -i64::from(offset))?)
+            }
+            _ if Self::is_numeric(a) => {
+                return Err(VmError::TypeMismatch { expected: Self::type_name(a), found: b });
+            }
+            _ => {
+                return Err(VmError::TypeMismatch { expected: "number", found: a });
+            }
+        };
+        Ok(result)
+    }
+
+    /// Orders two values. Integers of any width compare by value, floats against floats or
+    /// integers, and chars, booleans and pointers only against their own kind. `None` means
+    /// the values are comparable but unordered (a NaN, or pointers into different regions).
+    pub fn compare_values(a: StackValues, b: StackValues) -> Result<Option<Ordering>, VmError> {
+        use StackValues::*;
+        let integer = |v: StackValues| match v {
+            U16(v) => Some(i64::from(v)),
+            I16(v) => Some(i64::from(v)),
+            U32(v) => Some(i64::from(v)),
+            I32(v) => Some(i64::from(v)),
+            _ => None,
+        };
+        let float = |v: StackValues| match v {
+            F16(v) => Some(v.to_f32()),
+            other => integer(other).map(|v| v as f32),
+        };
+        Ok(match (a, b) {
+            (F16(_), _) | (_, F16(_)) => match (float(a), float(b)) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => return Err(VmError::TypeMismatch { expected: Self::type_name(a), found: b }),
+            },
+            (Char(a), Char(b)) => Some(a.cmp(&b)),
+            (Bool(a), Bool(b)) => Some(a.cmp(&b)),
+            (Pointer(a), Pointer(b)) if a.region == b.region => Some(a.offset.cmp(&b.offset)),
+            (Pointer(_), Pointer(_)) => None,
+            (ForeignPointer(a), ForeignPointer(b)) => Some((a as usize).cmp(&(b as usize))),
+            _ => match (integer(a), integer(b)) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ => return Err(VmError::TypeMismatch { expected: Self::type_name(a), found: b }),
+            },
+        })
+    }
+
+    /// Whether `JMPZ` treats the value as zero: numeric zero, `false` or `'\0'`.
+    fn is_zero(value: StackValues) -> Result<bool, VmError> {
+        Ok(match value {
+            StackValues::U16(v) => v == 0,
+            StackValues::I16(v) => v == 0,
+            StackValues::U32(v) => v == 0,
+            StackValues::I32(v) => v == 0,
+            StackValues::F16(v) => v == f16::ZERO,
+            StackValues::Char(c) => c == '\0',
+            StackValues::Bool(b) => !b,
+            other => return Err(VmError::TypeMismatch { expected: "number", found: other }),
+        })
+    }
+
+    fn type_name(value: StackValues) -> &'static str {
+        match value {
+            StackValues::U16(_) => "u16",
+            StackValues::I16(_) => "i16",
+            StackValues::U32(_) => "u32",
+            StackValues::I32(_) => "i32",
+            StackValues::F16(_) => "f16",
+            StackValues::Char(_) => "char",
+            StackValues::Bool(_) => "bool",
+            StackValues::Pointer(_) => "pointer",
+            StackValues::ForeignPointer(_) => "foreign pointer",
+        }
+    }
+
+    /// Resolves the operand of `LOAD_ARG`/`STORE_ARG`/`LOAD_LOCAL`/`STORE_LOCAL`
+    /// to an index into `frame_slots`.
+    fn frame_slot(&self, instruction: &Instruction) -> Result<usize, VmError> {
+        let index = Self::operand_u16(instruction, 0)?;
+        let frame = self.call_stack.last().ok_or(VmError::NoActiveFrame)?;
+        let (kind, first, count) = match instruction.tt {
+            InstructionType::INST_LOAD_ARG | InstructionType::INST_STORE_ARG => {
+                ("argument", 0, frame.args)
+            }
+            _ => ("local", frame.args, frame.locals),
+        };
+        if index >= count {
+            return Err(VmError::InvalidFrameSlot { kind, index, count });
+        }
+        Ok(frame.fp + first as usize + index as usize)
+    }
+
+    /// Executes the instruction at `pc`, reporting any fault together with its location.
+    pub fn determine_function(&mut self) -> Result<(), VmFault> {
+        self.maybe_collect_garbage();
+        let pc = self.pc;
+        let instruction = self
+            .instructions
+            .get(pc as usize)
+            .cloned()
+            .ok_or(VmFault { pc, instruction: None, error: VmError::PcOutOfBounds })?;
+        self.execute(&instruction).map_err(|error| VmFault {
+            pc,
+            instruction: Some(instruction.tt),
+            error,
+        })
+    }
+
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), VmError> {
+        match instruction.tt {
+            InstructionType::INST_ADD
+            | InstructionType::INST_SUB
+            | InstructionType::INST_MUL
+            | InstructionType::INST_DIV
+            | InstructionType::INST_AND
+            | InstructionType::INST_OR
+            | InstructionType::INST_XOR
+            | InstructionType::INST_SHL
+            | InstructionType::INST_SHR => {
+                self.binary_op(instruction.tt)?;
                 self.pc += 1;
             }
-            InstructionType::INST_OR => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a | b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a | b));
-                    }
-                }
+            InstructionType::INST_PUSH => {
+                let value = StackValues::from(Self::operand(instruction, 0)?);
+                self.push_stack(value)?;
                 self.pc += 1;
             }
-            InstructionType::INST_XOR => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a ^ b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a ^ b));
-                    }
-                }
+            InstructionType::INST_POP => {
+                self.pop_stack()?;
                 self.pc += 1;
             }
             InstructionType::INST_NOT => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    self.push_stack(StackValues::U16(!a));
+                match self.pop_stack()? {
+                    StackValues::U16(a) => self.push_stack(StackValues::U16(!a))?,
+                    StackValues::I16(a) => self.push_stack(StackValues::I16(!a))?,
+                    StackValues::U32(a) => self.push_stack(StackValues::U32(!a))?,
+                    StackValues::I32(a) => self.push_stack(StackValues::I32(!a))?,
+                    StackValues::Bool(a) => self.push_stack(StackValues::Bool(!a))?,
+                    other => {
+                        return Err(VmError::TypeMismatch { expected: "integer", found: other });
+                    }
                 }
                 self.pc += 1;
             }
-            InstructionType::INST_SHL => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a << b));
-                    }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a << b));
-                    }
+            InstructionType::INST_JMPZ | InstructionType::INST_JMPNZ => {
+                let zero = Self::is_zero(self.peek_stack(0)?)?;
+                let jump = match instruction.tt {
+                    InstructionType::INST_JMPZ => zero,
+                    _ => !zero,
+                };
+                if jump {
+                    self.pc = Self::jump_target(instruction)?;
+                } else {
+                    self.pc += 1;
                 }
-                self.pc += 1;
             }
-            InstructionType::INST_SHR => {
-                if let StackValues::U16(a) = self.pop_stack() {
-                    if let StackValues::U16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::U16(a >> b));
+            InstructionType::INST_LT
+            | InstructionType::INST_GT
+            | InstructionType::INST_LE
+            | InstructionType::INST_GE
+            | InstructionType::INST_EQ => {
+                let top = self.pop_stack()?;
+                let second = self.pop_stack()?;
+                let ordering = Self::compare_values(second, top)?;
+                let result = match instruction.tt {
+                    InstructionType::INST_LT => ordering == Some(Ordering::Less),
+                    InstructionType::INST_GT => ordering == Some(Ordering::Greater),
+                    InstructionType::INST_LE => {
+                        matches!(ordering, Some(Ordering::Less | Ordering::Equal))
                     }
-                } else if let StackValues::I16(a) = self.pop_stack() {
-                    if let StackValues::I16(b) = self.pop_stack() {
-                        self.push_stack(StackValues::I16(a >> b));
+                    InstructionType::INST_GE => {
+                        matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
                     }
-                }
+                    _ => ordering == Some(Ordering::Equal),
+                };
+                self.push_stack(StackValues::Bool(result))?;
                 self.pc += 1;
             }
-            InstructionType::INST_JMPZ => {
-                if let Some(t) = match self.stack[self.sp as usize] {
-                    StackValues::U16(v) => Some(v as i16),
-                    StackValues::I16(v) => Some(v),
-                    _ => None,
-                } {
-                    if t == 0 {
-                        match &self.instructions[self.pc as usize].values {
-                            Some(value) => {
-                                if let Word::U16(v) = value[0] {
-                                    self.pc = v;
-                                } else if let Word::I16(v) = value[0] {
-                                    self.pc = v as u16;
-                                } else {
-                                    self.pc += 1;
-                                }
-                            }
-                            None => {
-                                panic!(
-                                    "QUARMVM: does not have a value to push {:?}",
-                                    self.instructions[self.pc as usize]
-                                );
-                            }
-                        }
-                    } else {
-                        self.pc += 1;
-                    }
-                }
+            InstructionType::INST_JMP => {
+                self.pc = Self::jump_target(instruction)?;
             }
-            InstructionType::INST_JMPEQ => {
-                if let Some(x) = match self.stack[self.sp as usize] {
-                    StackValues::U16(x) => Some(x as i16),
-                    StackValues::I16(x) => Some(x),
-                    _ => None,
-                } {
-                    if let Some(y) = match self.stack[self.sp as usize - 1] {
-                        StackValues::U16(y) => Some(y as i16),
-                        StackValues::I16(y) => Some(y),
-                        _ => None,
-                    } {
-                        if x == y {
-                            match &self.instructions[self.pc as usize].values {
-                                Some(value) => {
-                                    if let Word::U16(v) = value[0] {
-                                        self.pc = v;
-                                    } else if let Word::I16(v) = value[0] {
-                                        self.pc = v as u16;
-                                    } else {
-                                        self.pc += 1;
-                                    }
-                                }
-                                None => {
-                                    panic!(
-                                        "QUARMVM: does not have a value to push {:?}",
-                                        self.instructions[self.pc as usize]
-                                    );
-                                }
-                            }
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
-                }
-            }
-            InstructionType::INST_JMPNEQ => {
-                if let Some(x) = match self.stack[self.sp as usize] {
-                    StackValues::U16(x) => Some(x as i16),
-                    StackValues::I16(x) => Some(x),
-                    _ => None,
-                } {
-                    if let Some(y) = match self.stack[self.sp as usize - 1] {
-                        StackValues::U16(y) => Some(y as i16),
-                        StackValues::I16(y) => Some(y),
-                        _ => None,
-                    } {
-                        if x != y {
-                            match &self.instructions[self.pc as usize].values {
-                                Some(value) => {
-                                    if let Word::U16(v) = value[0] {
-                                        self.pc = v;
-                                    } else if let Word::I16(v) = value[0] {
-                                        self.pc = v as u16;
-                                    } else {
-                                        self.pc += 1;
-                                    }
-                                }
-                                None => {
-                                    panic!(
-                                        "QUARMVM: does not have a value to push {:?}",
-                                        self.instructions[self.pc as usize]
-                                    );
-                                }
-                            }
-                        } else {
-                            self.pc += 1;
-                        }
-                    }
+            InstructionType::INST_JMPLT | InstructionType::INST_JMPGT => {
+                let ordering = Self::compare_values(self.peek_stack(1)?, self.peek_stack(0)?)?;
+                let jump = match instruction.tt {
+                    InstructionType::INST_JMPLT => ordering == Some(Ordering::Less),
+                    _ => ordering == Some(Ordering::Greater),
+                };
+                if jump {
+                    self.pc = Self::jump_target(instruction)?;
+                } else {
+                    self.pc += 1;
                 }
             }
-            InstructionType::INST_JMPNZ => {
-                if let Some(x) = match self.stack[self.sp as usize] {
-                    StackValues::U16(x) => Some(x as i16),
-                    StackValues::I16(x) => Some(x),
-                    _ => None,
-                } {
-                    if x != 0 {
-                        match &self.instructions[self.pc as usize].values {
-                            Some(value) => {
-                                if let Word::U16(v) = value[0] {
-                                    self.pc = v;
-                                } else if let Word::I16(v) = value[0] {
-                                    self.pc = v as u16;
-                                } else {
-                                    self.pc += 1;
-                                }
-                            }
-                            None => {
-                                panic!(
-                                    "QUARMVM: does not have a value to push {:?}",
-                                    self.instructions[self.pc as usize]
-                                );
-                            }
-                        }
-                    } else {
-                        self.pc += 1;
-                    }
+            InstructionType::INST_JMPEQ | InstructionType::INST_JMPNEQ => {
+                let equal = Self::compare_values(self.peek_stack(0)?, self.peek_stack(1)?)?
+                    == Some(Ordering::Equal);
+                let jump = match instruction.tt {
+                    InstructionType::INST_JMPEQ => equal,
+                    _ => !equal,
+                };
+                if jump {
+                    self.pc = Self::jump_target(instruction)?;
+                } else {
+                    self.pc += 1;
                 }
             }
             InstructionType::INST_PUSH_STR => {
-                if let Some(args) = &self.instructions[self.pc as usize].values {
-                    let str_len = match args[0] {
-                        Word::U16(l) => l,
-                        _ => panic!("QUARMVM: Expected a u16 as a string size"),
-                    };
-
-                    let mut str_buffer: Vec<StackValues> = Vec::with_capacity(str_len as usize + 1);
-                    for i in 0..str_len as usize {
-                        if let Word::Char(c) =
-                            args.get(1 + i).expect("QUARMVM: Invalid string index")
-                        {
-                            str_buffer.push(StackValues::U16(*c as u16));
-                        } else {
-                            panic!("QUARMVM: Expected a Char in string");
-                        }
-                    }
-
-                    str_buffer.push(StackValues::U16('\0' as u16));
-                    let starting_pointer =
-                        self.allocate(str_len + 1, PointerType::StackValuesPointer);
-                    if let Ok(ptr) = starting_pointer {
-                        for i in 0..(str_len + 1) {
-                            unsafe {
-                                let dest = (ptr as *mut StackValues).add(i as usize);
-                                *dest.cast::<StackValues>() = str_buffer[i as usize];
-                            }
-                        }
-                        self.push_stack(StackValues::Pointer(ptr));
-                    }
-                }
+                let string = Self::string_operand(instruction)?;
+                let ptr = self.allocate_str(&string)?;
+                self.push_stack(StackValues::Pointer(ptr))?;
 
                 self.pc += 1;
             }
@@ -637,142 +1112,118 @@ impl QuarkVM {
             }
 
             InstructionType::INST_SYSCALL => {
-                if let StackValues::U16(syscall_num) = self.pop_stack() {
-                    let mut args: [usize; 6] = [0; 6];
-
-                    if let Some(t_values) = &self.instructions[self.pc as usize].values {
-                        if let Word::U16(len) = t_values[0] {
-                            (0..(len as usize)).for_each(|i| match self.pop_stack() {
-                                StackValues::U16(v) => args[i] = v as usize,
-                                StackValues::Pointer(v) => args[i] = v as usize,
-                                _ => {}
-                            });
+                // `SYSCALL name` carries the number as a second operand; `SYSCALL n` pops it.
+                let syscall_num = match instruction.values.as_ref().is_some_and(|values| values.len() > 1) {
+                    true => Self::operand_u16(instruction, 1)?,
+                    false => self.pop_u16()?,
+                };
+                let mut args: [usize; MAX_SYSCALL_ARGS] = [0; MAX_SYSCALL_ARGS];
+
+                let len = Self::operand_u16(instruction, 0)?;
+                let Some(args_used) = args.get_mut(..len as usize) else {
+                    return Err(VmError::TooManySyscallArgs(len));
+                };
+                for arg in args_used.iter_mut() {
+                    *arg = match self.pop_stack()? {
+                        StackValues::U16(v) => v as usize,
+                        StackValues::I16(v) => v as isize as usize,
+                        StackValues::U32(v) => v as usize,
+                        StackValues::I32(v) => v as isize as usize,
+                        StackValues::Char(c) => c as usize,
+                        StackValues::Bool(b) => b as usize,
+                        StackValues::F16(v) => {
+                            return Err(VmError::TypeMismatch { expected: "integer", found: StackValues::F16(v) });
                         }
-                    }
-
-                    let result: *mut u8;
-                    unsafe {
-                        asm!(
-                            "syscall",
-                            in("rax") syscall_num as usize,
-                            in("rdi") args[0],
-                            in("rsi") args[1],
-                            in("rdx") args[2],
-                            in("r10") args[3],
-                            in("r8")  args[4],
-                            in("r9")  args[5],
-                            lateout("rax") result,
-                        );
-                    }
-                    self.push_stack(StackValues::Pointer(result as *mut ()));
+                        StackValues::Pointer(v) => {
+                            self.find_allocation(v).ok_or(VmError::InvalidPointer(v))?;
+                            self.native_address(v) as usize
+                        }
+                        StackValues::ForeignPointer(v) => v as usize,
+                    };
                 }
+
+                self.check_syscall(syscall_num, &args)?;
+                let result = Self::raw_syscall(syscall_num, &args)?;
+                self.push_stack(StackValues::ForeignPointer(result as *mut ()))?;
                 self.pc += 1;
             }
 
             InstructionType::INST_ALLOC => {
-                if let Some(values) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(size) = values[0] {
-                        if let Ok(ptr) = self.allocate(size, PointerType::StackValuesPointer) {
-                            self.push_stack(StackValues::Pointer(ptr));
-                        }
-                    }
-                }
+                let size = Self::operand_u16(instruction, 0)?;
+                let ptr = self.allocate(size, PointerType::StackValuesPointer)?;
+                self.push_stack(StackValues::Pointer(ptr))?;
                 self.pc += 1;
             }
 
             InstructionType::INST_ALLOC_RAW => {
-                if let Some(values) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(size) = values[0] {
-                        if let Ok(ptr) = self.allocate(size, PointerType::RawPointer) {
-                            self.push_stack(StackValues::Pointer(ptr));
-                        }
-                    }
-                }
+                let size = Self::operand_u16(instruction, 0)?;
+                let ptr = self.allocate(size, PointerType::RawPointer)?;
+                self.push_stack(StackValues::Pointer(ptr))?;
                 self.pc += 1;
             }
 
             InstructionType::INST_DUP => {
-                let value = self.pop_stack();
-                self.push_stack(value);
-                self.push_stack(value);
+                let value = self.peek_stack(0)?;
+                self.push_stack(value)?;
                 self.pc += 1;
             }
 
             InstructionType::INST_INSWAP => {
-                if let Some(value) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(index) = value[0] {
-                        self.stack.swap(
-                            self.sp as usize,
-                            self.sp.wrapping_sub(index as i16) as usize,
-                        );
-                    }
+                let index = Self::operand_u16(instruction, 0)?;
+                if index as i16 > self.sp {
+                    return Err(VmError::StackUnderflow);
                 }
+                self.stack.swap(self.sp as usize, (self.sp - index as i16) as usize);
                 self.pc += 1;
             }
 
             InstructionType::INST_PRINT => {
-                Self::print(self.stack[self.sp as usize]);
+                self.print(self.peek_stack(0)?);
                 self.pc += 1;
             }
 
             InstructionType::INST_STORE => {
-                if let Some(value) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(index) = value[0] {
-                        self.constant_pools[index as usize] = self.pop_stack();
-                    }
-                }
+                let index = Self::operand_u16(instruction, 0)?;
+                let value = self.pop_stack()?;
+                *self
+                    .constant_pools
+                    .get_mut(index as usize)
+                    .ok_or(VmError::InvalidOperand(Word::U16(index)))? = value;
                 self.pc += 1;
             }
 
             InstructionType::INST_LOAD => {
-                if let Some(value) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(index) = value[0] {
-                        self.push_stack(self.constant_pools[index as usize]);
-                    }
-                }
+                let index = Self::operand_u16(instruction, 0)?;
+                let value = *self
+                    .constant_pools
+                    .get(index as usize)
+                    .ok_or(VmError::InvalidOperand(Word::U16(index)))?;
+                self.push_stack(value)?;
                 self.pc += 1;
             }
 
             InstructionType::INST_DEREF => {
-                let stack_ptr = self.pop_stack();
-                if let StackValues::Pointer(x) = stack_ptr {
-                    for (&ptr, &(size, ptr_type)) in self.allocated_memory.clone().iter() {
-                        unsafe {
-                            if ptr_type == PointerType::RawPointer
-                                && (x as *mut u8) >= (ptr as *mut u8)
-                                && (x as *mut u8) < (ptr as *mut u8).add(size as usize)
-                            {
-                                if let Ok(all_ptr) =
-                                    self.allocate(size, PointerType::StackValuesPointer)
-                                {
-                                    for i in 0..size {
-                                        let raw_byte = *((ptr as *mut u8).wrapping_add(i as usize));
-                                        *((all_ptr as *mut StackValues).wrapping_add(i as usize)) =
-                                            StackValues::U16(raw_byte as u16);
-                                    }
-                                    self.push_stack(StackValues::Pointer(all_ptr));
-                                }
-                                break;
-                            } else if ptr_type == PointerType::StackValuesPointer
-                                && (x as *mut StackValues) >= (ptr as *mut StackValues)
-                                && (x as *mut StackValues)
-                                    < (ptr as *mut StackValues).add(size as usize)
-                            {
-                                self.push_stack(*(x as *mut StackValues));
-                                break;
-                            }
-                        }
+                let x = self.pop_pointer()?;
+                let (ptr, size) = self.find_allocation(x).ok_or(VmError::InvalidPointer(x))?;
+                if ptr.region == PointerType::RawPointer {
+                    let all_ptr = self.allocate(size, PointerType::StackValuesPointer)?;
+                    for i in 0..size as usize {
+                        let raw_byte = self.memory[ptr.offset + i];
+                        self.heap[all_ptr.offset + i] = StackValues::U16(raw_byte as u16);
                     }
+                    self.push_stack(StackValues::Pointer(all_ptr))?;
+                } else {
+                    self.push_stack(self.heap[x.offset])?;
                 }
                 self.pc += 1;
             }
 
             InstructionType::INST_REF => {
-                let mut value = self.pop_stack();
-                let _s = std::mem::size_of::<StackValues>();
-                let v = StackValues::Pointer(&mut value as *mut _ as *mut ());
-                self.push_stack(value);
-                self.push_stack(v);
+                let value = self.pop_stack()?;
+                let ptr = self.allocate(1, PointerType::StackValuesPointer)?;
+                self.heap[ptr.offset] = value;
+                self.push_stack(value)?;
+                self.push_stack(StackValues::Pointer(ptr))?;
                 self.pc += 1;
             }
 
@@ -782,224 +1233,262 @@ impl QuarkVM {
             }
 
             InstructionType::INST_CALL => {
-                if let Some(value) = &self.instructions[self.pc as usize].values {
-                    if let Word::U16(index) = value[0] {
-                        self.call_stack.push(self.pc + 1);
-                        self.pc = index;
-                    }
+                let index = Self::operand_u16(instruction, 0)?;
+                if self.call_stack.len() >= self.limits.call_depth {
+                    return Err(VmError::CallStackOverflow(self.limits.call_depth));
                 }
+                self.call_stack.push(Frame {
+                    return_pc: self.pc + 1,
+                    fp: self.frame_slots.len(),
+                    args: 0,
+                    locals: 0,
+                });
+                self.pc = index;
             }
 
             InstructionType::INST_RET => {
-                if let Some(to_return) = self.call_stack.pop() {
-                    self.pc = to_return;
+                if let Some(frame) = self.call_stack.pop() {
+                    self.frame_slots.truncate(frame.fp);
+                    self.pc = frame.return_pc;
+                } else {
+                    self.running = false;
+                }
+            }
+
+            InstructionType::INST_FREE | InstructionType::INST_FREE_RAW => {
+                let ptr = self.pop_pointer()?;
+                let region = match instruction.tt {
+                    InstructionType::INST_FREE => PointerType::StackValuesPointer,
+                    _ => PointerType::RawPointer,
+                };
+                self.free(ptr, region)?;
+                self.pc += 1;
+            }
+
+            InstructionType::INST_ENTER => {
+                let args = Self::operand_u16(instruction, 0)?;
+                let locals = Self::operand_u16(instruction, 1)?;
+                let frame = self.call_stack.last_mut().ok_or(VmError::NoActiveFrame)?;
+                frame.args = args;
+                frame.locals = locals;
+                let fp = frame.fp;
+                self.frame_slots.truncate(fp);
+                for _ in 0..args {
+                    let value = self.pop_stack()?;
+                    self.frame_slots.push(value);
                 }
+                self.frame_slots
+                    .resize(fp + args as usize + locals as usize, StackValues::U16(0));
+                self.pc += 1;
+            }
+
+            InstructionType::INST_LOAD_ARG | InstructionType::INST_LOAD_LOCAL => {
+                let slot = self.frame_slot(instruction)?;
+                self.push_stack(self.frame_slots[slot])?;
+                self.pc += 1;
+            }
+
+            InstructionType::INST_STORE_ARG | InstructionType::INST_STORE_LOCAL => {
+                let slot = self.frame_slot(instruction)?;
+                self.frame_slots[slot] = self.pop_stack()?;
+                self.pc += 1;
             }
 
             InstructionType::INST_PUT => {
-                if let StackValues::Pointer(ptr) = self.pop_stack() {
-                    unsafe {
-                        println!("ptr: {:?}", ptr);
-                        let (_, ptr_type) = self
-                            .allocated_memory
-                            .get(&ptr)
-                            .expect("QUARMVM: Error while getting info for pointer.");
-                        if *ptr_type == PointerType::StackValuesPointer {
-                            *(ptr as *mut StackValues) = self.pop_stack();
-                        } else if let StackValues::U16(v) = self.pop_stack() {
-                            let lsb = (v & 0xFF) as u8;
-                            let msb = (v >> 8) as u8;
-                            *(ptr as *mut u8) = lsb;
-                            if msb != 0 {
-                                *((ptr as *mut u8).wrapping_add(1)) = lsb;
-                            }
-                        }
+                let ptr = self.pop_pointer()?;
+                self.find_allocation(ptr).ok_or(VmError::InvalidPointer(ptr))?;
+                if ptr.region == PointerType::StackValuesPointer {
+                    let value = self.pop_stack()?;
+                    self.write_heap(ptr, value);
+                } else {
+                    let v = self.pop_u16()?;
+                    let lsb = (v & 0xFF) as u8;
+                    let msb = (v >> 8) as u8;
+                    self.memory[ptr.offset] = lsb;
+                    if msb != 0 {
+                        let next = self
+                            .offset_pointer(ptr, 1)
+                            .ok_or(VmError::InvalidPointer(ptr))?;
+                        self.memory[next.offset] = msb;
                     }
                 }
+                self.pc += 1;
             }
 
             InstructionType::INST_STD_SYSCALL => {
-                if let StackValues::U16(syscall_num) = self.pop_stack() {
-                    let mut args: VecDeque<StackValues> = VecDeque::new();
-
-                    if let Some(t_values) = &self.instructions[self.pc as usize].values {
-                        if let Word::U16(len) = t_values[0] {
-                            (0..(len as usize)).for_each(|i| {
-                                self.debug_stack();
-                                args.push_back(self.pop_stack());
-                            });
-                        }
-                    }
+                let syscall_num = self.pop_u16()?;
+                let mut args: VecDeque<StackValues> = VecDeque::new();
 
-                    self.std_syscall_match(syscall_num, args);
-                    self.pc += 1;
-                } else {
-                    panic!("QUARKVM: Expected a u16");
+                let len = Self::operand_u16(instruction, 0)?;
+                for _ in 0..len {
+                    args.push_back(self.pop_stack()?);
                 }
+
+                self.std_syscall_match(syscall_num, args)?;
+                self.pc += 1;
             }
 
             InstructionType::INST_DLL_LOAD => {
-                if let StackValues::Pointer(str_ptr) = self.pop_stack() {
-                    let dll_path = self.get_str_from_ptr(str_ptr);
-                    unsafe {
-                        let lib = Library::new(dll_path.as_str());
-                        if let Ok(loaded_lib) = lib {
-                            self.dlls.borrow_mut().push(loaded_lib);
-                            self.push_stack(StackValues::U16((self.dlls.clone().borrow().len() - 1) as u16));
-                        } else {
-                            dbg!(&lib);
-                            panic!("{:?}", lib);
-                        }
-                    }
-                }
+                let str_ptr = self.pop_pointer()?;
+                let dll_path = self.get_str_from_ptr(str_ptr)?;
+                let loaded_lib = unsafe { Library::new(dll_path.as_str()) }
+                    .map_err(|e| VmError::FfiFailure(e.to_string()))?;
+                self.dlls.borrow_mut().push(loaded_lib);
+                self.push_stack(StackValues::U16((self.dlls.clone().borrow().len() - 1) as u16))?;
                 self.pc += 1;
             }
 
             InstructionType::INST_DLL_CALL => {
-                let maybe_value = self.instructions[self.pc as usize].values.clone();
-                let dlls = self.dlls.clone();
-                let mut mut_ref_dlls = dlls.borrow_mut();
-
-                if let Some(value) = maybe_value {
-                    if let StackValues::U16(stack_value_holding_dll) = self.pop_stack() {
-                        let dll = mut_ref_dlls.get_mut(stack_value_holding_dll as usize)
-                            .expect("QUARKVM: No such DLL Registered");
-
-                        if let Word::U16(number_of_args) = value.first().expect("QUARKVM: No method name passed") {
-                            if let StackValues::Pointer(method_name) = self.pop_stack() {
-                                let method_name_string = self.get_str_from_ptr(method_name);
-
-                                let mut arguments: Vec<libffi::middle::Arg> = vec![];
-                                let mut argument_types: Vec<libffi::middle::Type> = vec![];
-                                let mut arg_storage: Vec<Box<dyn std::any::Any>> = vec![];
-
-                                for _ in 0..(*number_of_args) {
-                                    let arg_type = match self.pop_stack() {
-                                        StackValues::U16(u) => {
-                                            let u = Box::new(u);
-                                            arguments.push(libffi::middle::Arg::new(&*u));
-                                            arg_storage.push(u);
-                                            libffi::middle::Type::c_uint()
-                                        }
-                                        StackValues::I16(u) => {
-                                            let u = Box::new(u);
-                                            arguments.push(libffi::middle::Arg::new(&*u));
-                                            arg_storage.push(u);
-                                            libffi::middle::Type::c_int()
-                                        }
-                                        StackValues::Pointer(u) => {
-                                            let u = Box::new(u);
-                                            arguments.push(libffi::middle::Arg::new(&*u));
-                                            arg_storage.push(u);
-                                            libffi::middle::Type::pointer()
-                                        }
-                                    };
-                                    argument_types.push(arg_type);
-                                }
-                                let method_handle = unsafe { dll.get(method_name_string.as_bytes()) }
-                                    .expect("QUARKVM: Can not load the method");
-
-                                let cif = libffi::middle::Cif::new(argument_types, libffi::middle::Type::pointer());
-                                let cp = libffi::middle::CodePtr::from_fun(*method_handle);
-                                unsafe {
-                                    let output: *mut () = cif.call(cp, &arguments);
-                                    self.push_stack(StackValues::Pointer(output));
-                                    self.pc += 1;
-                                }
-                            }
-                        }
-                    }
+                let signature = Signature::decode(instruction.values.as_deref().unwrap_or_default())?;
+                let stack_value_holding_dll = self.pop_u16()?;
+                let method_name = self.pop_pointer()?;
+                let method_name_string = self.get_str_from_ptr(method_name)?;
+
+                if let Some((number_of_args, signature)) = signature {
+                    let function = self.dll_function(stack_value_holding_dll, &method_name_string)?;
+                    self.ffi_call(function, &signature, number_of_args)?;
+                    self.pc += 1;
+                    return Ok(());
                 }
-            },
-            InstructionType::INST_DEREF_FOREIGN => {}
-        }
-    }
 
-    pub fn get_str_from_ptr(&self, str_ptr: *mut ()) -> String {
-        for (&ptr, &(size, ptr_type)) in self.allocated_memory.clone().iter() {
-            unsafe {
-                if ptr_type == PointerType::RawPointer
-                    && (str_ptr as *mut u8) >= (ptr as *mut u8)
-                    && (str_ptr as *mut u8) < (ptr as *mut u8).add(size as usize)
-                {
-                    let mut dup_str_ptr = str_ptr as *mut u8;
-                    let mut s = String::new();
-                    while *dup_str_ptr as char != '\0' {
-                        s.push(*dup_str_ptr as char);
-                        dup_str_ptr = dup_str_ptr.wrapping_add(1);
-                    }
-                    return s;
-                } else if ptr_type == PointerType::StackValuesPointer
-                    && (str_ptr as *mut StackValues) >= (ptr as *mut StackValues)
-                    && (str_ptr as *mut StackValues) < (ptr as *mut StackValues).add(size as usize)
-                {
-                    let mut dup_str_ptr = str_ptr as *mut StackValues;
-                    if let StackValues::U16(mut x) = *dup_str_ptr {
-                        let mut s = String::new();
-                        while char::from_u32(x.into())
-                            .expect("QUARKVM: Error can not convert to char.")
-                            != '\0'
-                        {
-                            s.push(char::from_u32(x.into()).expect("UNREACHABLE"));
-                            dup_str_ptr = dup_str_ptr.wrapping_add(1);
-                            if let StackValues::U16(x_new) = *dup_str_ptr {
-                                x = x_new;
-                            }
+                // Without a declared signature the argument types are guessed from the
+                // stack values and the result is taken to be a pointer.
+                let number_of_args = Self::operand_u16(instruction, 0)?;
+                let mut arguments: Vec<libffi::middle::Arg> = vec![];
+                let mut argument_types: Vec<libffi::middle::Type> = vec![];
+                let mut arg_storage: Vec<Box<dyn std::any::Any>> = vec![];
+
+                for _ in 0..number_of_args {
+                    let arg_type = match self.pop_stack()? {
+                        // 16-bit integers widen to `unsigned int`/`int`, `F16` to `float`,
+                        // `Char` to a 32-bit code point and `Bool` to a one byte C `bool`.
+                        StackValues::U16(u) => {
+                            let u = Box::new(u32::from(u));
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::c_uint()
                         }
-                        return s;
-                    }
+                        StackValues::I16(u) => {
+                            let u = Box::new(i32::from(u));
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::c_int()
+                        }
+                        StackValues::U32(u) => {
+                            let u = Box::new(u);
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::u32()
+                        }
+                        StackValues::I32(u) => {
+                            let u = Box::new(u);
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::i32()
+                        }
+                        StackValues::F16(u) => {
+                            let u = Box::new(u.to_f32());
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::f32()
+                        }
+                        StackValues::Char(u) => {
+                            let u = Box::new(u as u32);
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::u32()
+                        }
+                        StackValues::Bool(u) => {
+                            let u = Box::new(u as u8);
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::u8()
+                        }
+                        StackValues::Pointer(u) => {
+                            self.find_allocation(u).ok_or(VmError::InvalidPointer(u))?;
+                            let u = Box::new(self.native_address(u));
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::pointer()
+                        }
+                        StackValues::ForeignPointer(u) => {
+                            let u = Box::new(u);
+                            arguments.push(libffi::middle::Arg::new(&*u));
+                            arg_storage.push(u);
+                            libffi::middle::Type::pointer()
+                        }
+                    };
+                    argument_types.push(arg_type);
                 }
+
+                let cif = libffi::middle::Cif::new(argument_types, libffi::middle::Type::pointer());
+                let cp = self.dll_function(stack_value_holding_dll, &method_name_string)?;
+                let output: *mut () = unsafe { cif.call(cp, &arguments) };
+                self.push_stack(StackValues::ForeignPointer(output))?;
+                self.pc += 1;
+            }
+            InstructionType::INST_DEREF_FOREIGN => {
+                return Err(VmError::UnsupportedInstruction(instruction.tt));
             }
-        }
 
-        panic!("QUARKVM: Can't find string");
+            InstructionType::INST_HOST_CALL => {
+                let name = Self::string_operand(instruction)?;
+                let host_function = self
+                    .host_functions
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| VmError::UnknownHostFunction(name.clone()))?;
+                // A host function that steps the VM into a `HOST_CALL` of its own would need
+                // a second mutable borrow of itself.
+                let mut function = host_function
+                    .function
+                    .try_borrow_mut()
+                    .map_err(|_| VmError::ReentrantHostCall(name))?;
+                let mut args = Vec::with_capacity(host_function.arity);
+                for _ in 0..host_function.arity {
+                    args.push(self.pop_stack()?);
+                }
+                let result = (function)(self, &args)?;
+                if let Some(value) = result {
+                    self.push_stack(value)?;
+                }
+                self.pc += 1;
+            }
+        }
+        Ok(())
     }
 
-    pub fn std_syscall_match(&mut self, id: u16, mut args: VecDeque<StackValues>) {
-        match id {
-            0 => {
-                // SYSCALL to Exit.
-                if let StackValues::U16(exit_code) = self.pop_stack() {
-                    exit(exit_code.into());
+    pub fn get_str_from_ptr(&self, str_ptr: Handle) -> Result<String, VmError> {
+        self.find_allocation(str_ptr).ok_or(VmError::InvalidPointer(str_ptr))?;
+        let mut s = String::new();
+        match str_ptr.region {
+            PointerType::RawPointer => {
+                for &byte in self.memory[str_ptr.offset..].iter() {
+                    if byte == 0 {
+                        break;
+                    }
+                    s.push(byte as char);
                 }
             }
-            1 => {
-                // Get FILE fd
-                if let StackValues::Pointer(mut file_name) =
-                    args.pop_front().expect("QUARMVM: Expected a file name")
-                {
-                    let mut name = String::new();
-                    unsafe {
-                        if let StackValues::U16(mut current_char) = *(file_name as *mut StackValues)
-                        {
-                            while char::from_u32(current_char.into())
-                                .expect("QUARKVM: Not a character while reading a file path")
-                                != '\0'
-                            {
-                                name.push(
-                                    char::from_u32(current_char.into()).expect("UNREACHABLE"),
-                                );
-                                file_name =
-                                    (file_name as *mut StackValues).wrapping_add(1) as *mut ();
-                                if let StackValues::U16(next_char) =
-                                    *(file_name as *mut StackValues)
-                                {
-                                    current_char = next_char;
-                                }
-                            }
+            PointerType::StackValuesPointer => {
+                for cell in self.heap[str_ptr.offset..].iter() {
+                    match *cell {
+                        StackValues::U16(0) | StackValues::Char('\0') => break,
+                        StackValues::Char(c) => s.push(c),
+                        StackValues::U16(x) => s.push(
+                            char::from_u32(x.into()).ok_or(VmError::TypeMismatch {
+                                expected: "char",
+                                found: *cell,
+                            })?,
+                        ),
+                        other => {
+                            return Err(VmError::TypeMismatch { expected: "char", found: other });
                         }
                     }
-                    let f = File::open(name);
-                    if let Ok(ff) = f {
-                        dbg!(&ff);
-                        self.push_stack(StackValues::I16(ff.as_raw_fd() as i16));
-                    } else {
-                        panic!("QUARMVM: Error while opening the file: {:?}", f)
-                    }
                 }
             }
-            _ => {}
         }
+        Ok(s)
     }
 
     pub fn debug_stack(&self) {
@@ -1007,7 +1496,7 @@ impl QuarkVM {
         println!(
             "SP: {:?} stack: {:?} pc: {:?}",
             self.sp,
-            &self.stack[0..(self.sp.max(0) as usize + 1)],
+            &self.stack[..(self.sp + 1) as usize],
             self.pc
         );
         println!("MEMORY: {:?}", self.memory);
@@ -1017,19 +1506,84 @@ impl QuarkVM {
         println!("______________________________________________________________________");
     }
 
-    pub fn run(&mut self) {
+    /// Makes a Linux syscall through libc, which knows each architecture's calling
+    /// convention. As with the bare instruction, a failure is returned as `-errno`.
+    #[cfg(target_os = "linux")]
+    fn raw_syscall(number: u16, args: &[usize; 6]) -> Result<isize, VmError> {
+        let result = unsafe {
+            libc::syscall(
+                libc::c_long::from(number),
+                args[0] as libc::c_long,
+                args[1] as libc::c_long,
+                args[2] as libc::c_long,
+                args[3] as libc::c_long,
+                args[4] as libc::c_long,
+                args[5] as libc::c_long,
+            )
+        };
+        if result == -1 {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            return Ok(-(errno as isize));
+        }
+        Ok(result as isize)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn raw_syscall(_number: u16, _args: &[usize; 6]) -> Result<isize, VmError> {
+        Err(VmError::UnsupportedInstruction(InstructionType::INST_SYSCALL))
+    }
+
+    /// Executes instructions until the program halts, faults, runs out of fuel or passes
+    /// its deadline.
+    pub fn run(&mut self) -> Result<RunStatus, VmFault> {
+        self.run_stats.instructions = 0;
         while self.running {
-            self.determine_function();
             if (self.pc as usize) >= self.instructions.len() {
                 self.running = false;
+                break;
+            }
+            if let Some(status) = self.step()? {
+                return Ok(status);
             }
         }
+        Ok(RunStatus::Halted)
+    }
+
+    /// Executes the next instruction and counts it against the fuel. Returns why nothing
+    /// was executed if the fuel is used up or the deadline has passed.
+    pub fn step(&mut self) -> Result<Option<RunStatus>, VmFault> {
+        if self.fuel == Some(0) {
+            return Ok(Some(RunStatus::OutOfFuel));
+        }
+        if let Some(deadline) = self.limits.deadline
+            && self.run_stats.instructions.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && Instant::now() >= deadline
+        {
+            return Ok(Some(RunStatus::DeadlineExceeded));
+        }
+        self.determine_function()?;
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel -= 1;
+        }
+        self.run_stats.instructions += 1;
+        self.run_stats.total_instructions += 1;
+        Ok(None)
+    }
+
+    /// Grants `amount` more instructions to a VM running on a budget, or starts one.
+    pub fn add_fuel(&mut self, amount: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(amount));
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Bumped whenever opcodes are added, removed or change meaning, so that
+/// bytecode assembled for another instruction set is rejected at load time.
+pub const INSTRUCTION_SET_VERSION: u16 = 7;
+
+#[derive(Debug, Copy, Clone, Default)]
 #[repr(u8)]
 pub enum InstructionType {
+    #[default]
     INST_NOOP = 0,
     INST_PUSH,
     INST_POP,
@@ -1066,11 +1620,45 @@ pub enum InstructionType {
     INST_STD_SYSCALL,
     INST_DLL_LOAD,
     INST_DLL_CALL,
+    INST_HOST_CALL,
+    INST_ENTER,
+    INST_LOAD_ARG,
+    INST_STORE_ARG,
+    INST_LOAD_LOCAL,
+    INST_STORE_LOCAL,
+    INST_FREE,
+    INST_FREE_RAW,
+    INST_LT,
+    INST_GT,
+    INST_LE,
+    INST_GE,
+    INST_EQ,
+    INST_JMP,
+    INST_JMPLT,
+    INST_JMPGT,
 }
 
-impl Default for InstructionType {
-    fn default() -> Self {
-        Self::INST_NOOP
+
+impl InstructionType {
+    /// The QASM spelling of the instruction, e.g. `INST_PUSH_STR` -> `PUSH_STR`.
+    pub fn mnemonic(&self) -> String {
+        let name = format!("{:?}", self);
+        name.split_at(5).1.to_string()
+    }
+
+    /// Whether the first operand is an instruction index (a label in QASM).
+    pub fn takes_code_address(&self) -> bool {
+        matches!(
+            self,
+            Self::INST_CALL
+                | Self::INST_JMP
+                | Self::INST_JMPZ
+                | Self::INST_JMPEQ
+                | Self::INST_JMPNEQ
+                | Self::INST_JMPNZ
+                | Self::INST_JMPLT
+                | Self::INST_JMPGT
+        )
     }
 }
 
@@ -1079,7 +1667,7 @@ impl TryFrom<u8> for InstructionType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            x if x <= 35 => Ok(unsafe { std::mem::transmute(x) }),
+            x if x <= Self::INST_JMPGT as u8 => Ok(unsafe { std::mem::transmute::<u8, InstructionType>(x) }),
             _ => Err(()),
         }
     }
@@ -1095,23 +1683,12 @@ impl Instruction {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buffer: Vec<u8> = vec![];
         buffer.push(self.tt as u8);
-        match &self.values {
-            Some(value) => {
-                buffer.push(value.iter().len() as u8);
-            }
-            None => {
-                buffer.push(0);
-            }
-        }
+        let count = self.values.as_ref().map_or(0, |values| values.len());
+        let count = u16::try_from(count).expect("the assembler rejects instructions with more than u16::MAX operands");
+        buffer.extend_from_slice(&count.to_be_bytes());
         if let Some(values) = &self.values {
             for value in values {
-                println!("{:?}", value);
-                match value {
-                    Word::U16(_) => buffer.push(0),
-                    Word::F16(_) => buffer.push(1),
-                    Word::Char(_) => buffer.push(2),
-                    Word::I16(_) => buffer.push(3),
-                }
+                buffer.push(value.tag());
                 buffer.extend_from_slice(&value.to_be_bytes());
             }
         }
@@ -1183,8 +1760,8 @@ pub fn DEFINE_JMPNZ(x: i16) -> Instruction {
 }
 
 pub fn DEFINE_PUSH_STR(x: &str) -> Instruction {
-    let mut values = vec![Word::from(x.len() as u16)];
-    values.extend(x.chars().map(|c| Word::from(c)));
+    let mut values = vec![Word::from(x.chars().count() as u16)];
+    values.extend(x.chars().map(Word::from));
     Instruction {
         tt: InstructionType::INST_PUSH_STR,
         values: Some(values),
@@ -1295,6 +1872,12 @@ pub fn DEFINE_RET() -> Instruction {
     }
 }
 
+/// `CALL main` followed by a `RET` with no frame to return to, so the machine halts once
+/// `main` returns instead of falling into the code after the prologue.
+pub fn DEFINE_ENTRY_PROLOGUE(main: u16) -> [Instruction; ENTRY_PROLOGUE_LEN] {
+    [DEFINE_CALL(main), DEFINE_RET()]
+}
+
 pub fn DEFINE_PUT() -> Instruction {
     Instruction {
         tt: InstructionType::INST_PUT,
@@ -1316,11 +1899,90 @@ pub fn DEFINE_DLL_LOAD() -> Instruction {
     }
 }
 
+pub fn DEFINE_HOST_CALL(x: &str) -> Instruction {
+    let mut values = vec![Word::from(x.chars().count() as u16)];
+    values.extend(x.chars().map(Word::from));
+    Instruction {
+        tt: InstructionType::INST_HOST_CALL,
+        values: Some(values),
+    }
+}
+
 pub fn DEFINE_DLL_CALL(x: &str) -> Instruction {
-    let mut values = vec![Word::from(x.len() as u16)];
-    values.extend(x.chars().map(|c| Word::from(c)));
+    let mut values = vec![Word::from(x.chars().count() as u16)];
+    values.extend(x.chars().map(Word::from));
     Instruction {
         tt: InstructionType::INST_DLL_CALL,
         values: Some(values),
     }
 }
+
+pub fn DEFINE_ENTER(args: u16, locals: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_ENTER,
+        values: Some(vec![Word::from(args), Word::from(locals)]),
+    }
+}
+
+pub fn DEFINE_LOAD_ARG(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_LOAD_ARG,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_STORE_ARG(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_STORE_ARG,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_LOAD_LOCAL(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_LOAD_LOCAL,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_STORE_LOCAL(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_STORE_LOCAL,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_FREE() -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_FREE,
+        values: None,
+    }
+}
+
+pub fn DEFINE_FREE_RAW() -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_FREE_RAW,
+        values: None,
+    }
+}
+
+pub fn DEFINE_JMP(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_JMP,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_JMPLT(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_JMPLT,
+        values: Some(vec![Word::from(x)]),
+    }
+}
+
+pub fn DEFINE_JMPGT(x: u16) -> Instruction {
+    Instruction {
+        tt: InstructionType::INST_JMPGT,
+        values: Some(vec![Word::from(x)]),
+    }
+}
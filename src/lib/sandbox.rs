@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use super::machine_type::{QuarkVM, VmError};
+
+type ArgumentCheck = dyn Fn(&[usize; 6]) -> bool;
+
+/// Which syscall numbers `SYSCALL` may make at all.
+#[derive(Debug, Clone, Default)]
+pub enum SyscallRule {
+    #[default]
+    AllowAll,
+    /// No raw syscalls: every `SYSCALL` faults.
+    DenyAll,
+    Allow(HashSet<u16>),
+    Deny(HashSet<u16>),
+}
+
+/// A condition on the arguments of one syscall number, e.g. that `write` only goes to
+/// stdout or stderr.
+#[derive(Clone)]
+pub struct ArgumentPredicate {
+    pub number: u16,
+    /// Shown in the fault when the check fails.
+    pub description: String,
+    pub check: Rc<ArgumentCheck>,
+}
+
+impl fmt::Debug for ArgumentPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArgumentPredicate")
+            .field("number", &self.number)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+/// What raw `SYSCALL`s a program may make, checked before the syscall is issued. A call
+/// the policy refuses is a `SyscallDenied` fault and is recorded in `QuarkVM::syscall_log`.
+///
+/// `STD_SYSCALL`, `DLL_CALL` and `HOST_CALL` are not affected.
+#[derive(Debug, Clone, Default)]
+pub struct SyscallPolicy {
+    pub rule: SyscallRule,
+    pub predicates: Vec<ArgumentPredicate>,
+    /// Record allowed syscalls in the log too, not only refused ones.
+    pub log_allowed: bool,
+}
+
+impl SyscallPolicy {
+    /// A policy that refuses every raw syscall.
+    pub fn deny_all() -> Self {
+        Self { rule: SyscallRule::DenyAll, ..Self::default() }
+    }
+
+    /// A policy that only permits the given syscall numbers.
+    pub fn allow(numbers: impl IntoIterator<Item = u16>) -> Self {
+        Self { rule: SyscallRule::Allow(numbers.into_iter().collect()), ..Self::default() }
+    }
+
+    /// A policy that permits everything except the given syscall numbers.
+    pub fn deny(numbers: impl IntoIterator<Item = u16>) -> Self {
+        Self { rule: SyscallRule::Deny(numbers.into_iter().collect()), ..Self::default() }
+    }
+
+    /// Requires `check` to hold for the arguments of every call of syscall `number`.
+    pub fn require(&mut self, number: u16, description: &str, check: impl Fn(&[usize; 6]) -> bool + 'static) {
+        self.predicates.push(ArgumentPredicate {
+            number,
+            description: description.to_string(),
+            check: Rc::new(check),
+        });
+    }
+
+    /// Requires the file descriptor in argument `index` of syscall `number` to be one of `fds`,
+    /// e.g. `restrict_fd(1, 0, &[1, 2])` keeps x86_64 `write` to stdout and stderr.
+    pub fn restrict_fd(&mut self, number: u16, index: usize, fds: &[usize]) {
+        let fds: HashSet<usize> = fds.iter().copied().collect();
+        let mut listed: Vec<usize> = fds.iter().copied().collect();
+        listed.sort_unstable();
+        let description = format!("argument {} must be one of the file descriptors {:?}", index, listed);
+        self.require(number, &description, move |args| fds.contains(&args[index]));
+    }
+
+    /// Why the policy refuses the call, or `None` if it is allowed.
+    pub fn check(&self, number: u16, args: &[usize; 6]) -> Option<String> {
+        let listed = match &self.rule {
+            SyscallRule::AllowAll => true,
+            SyscallRule::DenyAll => return Some("raw syscalls are disabled".to_string()),
+            SyscallRule::Allow(numbers) => numbers.contains(&number),
+            SyscallRule::Deny(numbers) => !numbers.contains(&number),
+        };
+        if !listed {
+            return Some("not permitted by the syscall policy".to_string());
+        }
+        self.predicates
+            .iter()
+            .find(|predicate| predicate.number == number && !(predicate.check)(args))
+            .map(|predicate| predicate.description.clone())
+    }
+}
+
+/// One raw syscall the program attempted.
+#[derive(Debug, Clone)]
+pub struct SyscallRecord {
+    pub pc: u16,
+    pub number: u16,
+    pub args: [usize; 6],
+    /// Why the policy refused the call; `None` if it was made.
+    pub denied: Option<String>,
+}
+
+impl fmt::Display for SyscallRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc {}: syscall {}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x})", self.pc, self.number,
+            self.args[0], self.args[1], self.args[2], self.args[3], self.args[4], self.args[5])?;
+        match &self.denied {
+            Some(reason) => write!(f, " denied: {}", reason),
+            None => write!(f, " allowed"),
+        }
+    }
+}
+
+impl QuarkVM {
+    /// Applies `syscall_policy` to a raw syscall about to be made and logs the outcome.
+    pub fn check_syscall(&mut self, number: u16, args: &[usize; 6]) -> Result<(), VmError> {
+        let denied = self.syscall_policy.check(number, args);
+        if denied.is_some() || self.syscall_policy.log_allowed {
+            self.syscall_log.push(SyscallRecord { pc: self.pc, number, args: *args, denied: denied.clone() });
+        }
+        match denied {
+            Some(reason) => Err(VmError::SyscallDenied { number, reason }),
+            None => Ok(()),
+        }
+    }
+}
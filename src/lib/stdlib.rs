@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+use super::machine_type::{block_size, Handle, PointerType, QuarkVM, StackValues, VmError};
+
+/// `STD_SYSCALL` ids. Arguments are listed in the order they are popped.
+pub mod calls {
+    /// `exit([code])`: stops the program with exit status `code` (0 if omitted).
+    pub const EXIT: u16 = 0;
+    /// `open(path[, mode]) -> file`: `mode` is an `fopen` style string (`"r"`, `"w"`,
+    /// `"a"`, `"r+"`, `"w+"`, `"a+"`), read-only if omitted.
+    pub const OPEN: u16 = 1;
+    /// `read(offset, buffer, file, count) -> read`: reads up to `count` bytes into
+    /// `buffer` starting `offset` elements in.
+    pub const READ: u16 = 2;
+    /// `close(file)`
+    pub const CLOSE: u16 = 3;
+    /// `write(offset, buffer, file, count) -> written`: writes `count` elements of
+    /// `buffer` starting `offset` elements in.
+    pub const WRITE: u16 = 4;
+    /// `seek(file, offset, whence) -> position`: `whence` is 0 for the start of the file,
+    /// 1 for the current position and 2 for the end.
+    pub const SEEK: u16 = 5;
+    /// `stat(path) -> size, is_dir`: `is_dir` ends up on top.
+    pub const STAT: u16 = 6;
+    /// `unlink(path)`
+    pub const UNLINK: u16 = 7;
+    /// `mkdir(path)`
+    pub const MKDIR: u16 = 8;
+    /// `readdir(path) -> names, count`: `names` is a heap block of `count` string pointers,
+    /// sorted, and `count` ends up on top.
+    pub const READDIR: u16 = 9;
+    /// `print(string)`: writes a string to stdout as text, without a newline.
+    pub const PRINT: u16 = 10;
+    /// `eprint(string)`: the same for stderr.
+    pub const EPRINT: u16 = 11;
+    /// `read_line() -> line, ok`: reads a line from stdin without its line ending. `ok`,
+    /// on top, is false at the end of input.
+    pub const READ_LINE: u16 = 12;
+    /// `read_bytes(count) -> string, read`: reads up to `count` bytes from stdin.
+    pub const READ_BYTES: u16 = 13;
+    /// `print_number(value[, base])`: writes an integer to stdout in base 2, 8, 10
+    /// (the default) or 16. Floats, chars and booleans are written as text.
+    pub const PRINT_NUMBER: u16 = 14;
+}
+
+/// File handles of the standard streams, usable with `read` and `write`.
+pub const STDIN: u16 = 0;
+pub const STDOUT: u16 = 1;
+pub const STDERR: u16 = 2;
+/// The first handle `open` gives out; the ones below it are the standard streams.
+const FIRST_FILE_HANDLE: u16 = 3;
+
+fn io_error(what: &str, error: std::io::Error) -> VmError {
+    VmError::Io(format!("{}: {}", what, error))
+}
+
+/// A file size or position as the `U32` pushed for it, for files below 4 GiB.
+fn file_size(what: &str, value: u64) -> Result<u32, VmError> {
+    u32::try_from(value).map_err(|_| VmError::Io(format!("{}: {} does not fit in a u32", what, value)))
+}
+
+fn next_arg(args: &mut VecDeque<StackValues>) -> Result<StackValues, VmError> {
+    args.pop_front().ok_or(VmError::MissingOperand)
+}
+
+fn integer_arg(args: &mut VecDeque<StackValues>) -> Result<i64, VmError> {
+    match next_arg(args)? {
+        StackValues::U16(v) => Ok(v.into()),
+        StackValues::I16(v) => Ok(v.into()),
+        StackValues::U32(v) => Ok(v.into()),
+        StackValues::I32(v) => Ok(v.into()),
+        other => Err(VmError::TypeMismatch { expected: "integer", found: other }),
+    }
+}
+
+fn count_arg(args: &mut VecDeque<StackValues>) -> Result<usize, VmError> {
+    let value = integer_arg(args)?;
+    usize::try_from(value).map_err(|_| VmError::TypeMismatch { expected: "count", found: StackValues::I32(value as i32) })
+}
+
+fn pointer_arg(args: &mut VecDeque<StackValues>) -> Result<Handle, VmError> {
+    match next_arg(args)? {
+        StackValues::Pointer(ptr) => Ok(ptr),
+        other => Err(VmError::TypeMismatch { expected: "pointer", found: other }),
+    }
+}
+
+fn file_arg(args: &mut VecDeque<StackValues>) -> Result<u16, VmError> {
+    match next_arg(args)? {
+        StackValues::U16(file) => Ok(file),
+        StackValues::I16(file) if file >= 0 => Ok(file as u16),
+        other => Err(VmError::TypeMismatch { expected: "file handle", found: other }),
+    }
+}
+
+/// Formats a value for `print_number`.
+fn format_number(value: StackValues, base: i64) -> Result<String, VmError> {
+    let integer: i64 = match value {
+        StackValues::U16(v) => v.into(),
+        StackValues::I16(v) => v.into(),
+        StackValues::U32(v) => v.into(),
+        StackValues::I32(v) => v.into(),
+        StackValues::F16(v) => return Ok(v.to_string()),
+        StackValues::Char(c) => return Ok(c.to_string()),
+        StackValues::Bool(b) => return Ok(b.to_string()),
+        other => return Err(VmError::TypeMismatch { expected: "number", found: other }),
+    };
+    let sign = if integer < 0 { "-" } else { "" };
+    let magnitude = integer.unsigned_abs();
+    Ok(match base {
+        2 => format!("{}{:b}", sign, magnitude),
+        8 => format!("{}{:o}", sign, magnitude),
+        10 => integer.to_string(),
+        16 => format!("{}{:x}", sign, magnitude),
+        _ => return Err(VmError::TypeMismatch { expected: "base 2, 8, 10 or 16", found: StackValues::I32(base as i32) }),
+    })
+}
+
+fn open_options(mode: &str) -> Option<OpenOptions> {
+    let mut options = OpenOptions::new();
+    match mode {
+        "r" => options.read(true),
+        "w" => options.write(true).create(true).truncate(true),
+        "a" => options.append(true).create(true),
+        "r+" => options.read(true).write(true),
+        "w+" => options.read(true).write(true).create(true).truncate(true),
+        "a+" => options.read(true).append(true).create(true),
+        _ => return None,
+    };
+    Some(options)
+}
+
+impl QuarkVM {
+    /// Runs standard call `id` with the arguments `STD_SYSCALL` popped, first popped first.
+    pub fn std_syscall_match(&mut self, id: u16, mut args: VecDeque<StackValues>) -> Result<(), VmError> {
+        match id {
+            calls::EXIT => {
+                let code = if args.is_empty() { 0 } else { integer_arg(&mut args)? };
+                self.exit_code = Some(code as i32);
+                self.running = false;
+            }
+            calls::OPEN => {
+                let path = self.string_arg(&mut args)?;
+                let mode = if args.is_empty() { "r".to_string() } else { self.string_arg(&mut args)? };
+                let options = open_options(&mode).ok_or_else(|| VmError::Io(format!("unknown open mode `{}`", mode)))?;
+                let file = options.open(&path).map_err(|e| io_error(&path, e))?;
+                let handle = (FIRST_FILE_HANDLE..=u16::MAX)
+                    .find(|handle| !self.fd_table.contains_key(handle))
+                    .ok_or_else(|| VmError::Io("too many open files".to_string()))?;
+                self.fd_table.insert(handle, file);
+                self.push_stack(StackValues::U16(handle))?;
+            }
+            calls::READ => {
+                let offset = count_arg(&mut args)?;
+                let buffer = pointer_arg(&mut args)?;
+                let handle = file_arg(&mut args)?;
+                let count = count_arg(&mut args)?;
+                let start = self.buffer_range(buffer, offset, count)?;
+                let mut bytes = vec![0; count];
+                let read = match handle {
+                    STDIN => io::stdin().read(&mut bytes),
+                    _ => self.file(handle)?.read(&mut bytes),
+                }
+                .map_err(|e| io_error("read", e))?;
+                self.fill_buffer(buffer.region, start, &bytes[..read]);
+                self.push_stack(StackValues::U16(read as u16))?;
+            }
+            calls::CLOSE => {
+                let handle = file_arg(&mut args)?;
+                self.fd_table.remove(&handle).ok_or(VmError::Io(format!("file handle {} is not open", handle)))?;
+            }
+            calls::WRITE => {
+                let offset = count_arg(&mut args)?;
+                let buffer = pointer_arg(&mut args)?;
+                let handle = file_arg(&mut args)?;
+                let count = count_arg(&mut args)?;
+                let start = self.buffer_range(buffer, offset, count)?;
+                let bytes = self.buffer_bytes(buffer.region, start, count)?;
+                self.write_to(handle, &bytes)?;
+                self.push_stack(StackValues::U16(count as u16))?;
+            }
+            calls::SEEK => {
+                let handle = file_arg(&mut args)?;
+                let offset = integer_arg(&mut args)?;
+                let position = match integer_arg(&mut args)? {
+                    0 => SeekFrom::Start(
+                        u64::try_from(offset)
+                            .map_err(|_| VmError::Io(format!("seek: negative offset {} from the start", offset)))?,
+                    ),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    whence => return Err(VmError::Io(format!("unknown seek origin {}", whence))),
+                };
+                let position = self.file(handle)?.seek(position).map_err(|e| io_error("seek", e))?;
+                self.push_stack(StackValues::U32(file_size("seek", position)?))?;
+            }
+            calls::STAT => {
+                let path = self.string_arg(&mut args)?;
+                let metadata = fs::metadata(&path).map_err(|e| io_error(&path, e))?;
+                self.push_stack(StackValues::U32(file_size(&path, metadata.len())?))?;
+                self.push_stack(StackValues::Bool(metadata.is_dir()))?;
+            }
+            calls::UNLINK => {
+                let path = self.string_arg(&mut args)?;
+                fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+            }
+            calls::MKDIR => {
+                let path = self.string_arg(&mut args)?;
+                fs::create_dir(&path).map_err(|e| io_error(&path, e))?;
+            }
+            calls::READDIR => {
+                let path = self.string_arg(&mut args)?;
+                let mut names = Vec::new();
+                for entry in fs::read_dir(&path).map_err(|e| io_error(&path, e))? {
+                    let entry = entry.map_err(|e| io_error(&path, e))?;
+                    names.push(entry.file_name().to_string_lossy().into_owned());
+                }
+                names.sort();
+                let count = block_size(names.len())?;
+                let list = self.allocate(count, PointerType::StackValuesPointer)?;
+                for (index, name) in names.iter().enumerate() {
+                    let name = self.allocate_str(name)?;
+                    self.heap[list.offset + index] = StackValues::Pointer(name);
+                }
+                self.push_stack(StackValues::Pointer(list))?;
+                self.push_stack(StackValues::U16(count))?;
+            }
+            calls::PRINT | calls::EPRINT => {
+                let text = self.string_arg(&mut args)?;
+                self.write_to(if id == calls::PRINT { STDOUT } else { STDERR }, text.as_bytes())?;
+            }
+            calls::READ_LINE => {
+                let mut line = String::new();
+                let read = io::stdin().lock().read_line(&mut line).map_err(|e| io_error("stdin", e))?;
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                let ptr = self.allocate_str(line)?;
+                self.push_stack(StackValues::Pointer(ptr))?;
+                self.push_stack(StackValues::Bool(read > 0))?;
+            }
+            calls::READ_BYTES => {
+                let count = count_arg(&mut args)?;
+                // The bytes and the zero after them have to fit in one allocation.
+                block_size(count.saturating_add(1))?;
+                let mut bytes = Vec::with_capacity(count);
+                io::stdin().lock().take(count as u64).read_to_end(&mut bytes).map_err(|e| io_error("stdin", e))?;
+                let ptr = self.allocate(block_size(bytes.len() + 1)?, PointerType::StackValuesPointer)?;
+                self.fill_buffer(PointerType::StackValuesPointer, ptr.offset, &bytes);
+                self.heap[ptr.offset + bytes.len()] = StackValues::Char('\0');
+                self.push_stack(StackValues::Pointer(ptr))?;
+                self.push_stack(StackValues::U16(block_size(bytes.len())?))?;
+            }
+            calls::PRINT_NUMBER => {
+                let value = next_arg(&mut args)?;
+                let base = if args.is_empty() { 10 } else { integer_arg(&mut args)? };
+                let text = format_number(value, base)?;
+                self.write_to(STDOUT, text.as_bytes())?;
+            }
+            _ => return Err(VmError::UnknownSyscall(id)),
+        }
+        Ok(())
+    }
+
+    /// Writes to an open file or to stdout or stderr. The standard streams are flushed so
+    /// a prompt shows up before the program reads its answer.
+    fn write_to(&mut self, handle: u16, bytes: &[u8]) -> Result<(), VmError> {
+        let result = match handle {
+            STDOUT => io::stdout().write_all(bytes).and_then(|_| io::stdout().flush()),
+            STDERR => io::stderr().write_all(bytes),
+            _ => self.file(handle)?.write_all(bytes),
+        };
+        result.map_err(|e| io_error("write", e))
+    }
+
+    fn string_arg(&self, args: &mut VecDeque<StackValues>) -> Result<String, VmError> {
+        let ptr = pointer_arg(args)?;
+        self.get_str_from_ptr(ptr)
+    }
+
+    fn file(&mut self, handle: u16) -> Result<&mut File, VmError> {
+        self.fd_table
+            .get_mut(&handle)
+            .ok_or(VmError::Io(format!("file handle {} is not open", handle)))
+    }
+
+    /// Checks that `count` elements starting `offset` elements past `ptr` lie inside its
+    /// allocation and returns the index of the first one in `ptr`'s region.
+    fn buffer_range(&self, ptr: Handle, offset: usize, count: usize) -> Result<usize, VmError> {
+        let (block, size) = self.find_allocation(ptr).ok_or(VmError::InvalidPointer(ptr))?;
+        let start = ptr.offset + offset;
+        if start + count > block.offset + size as usize {
+            return Err(VmError::InvalidPointer(ptr.wrapping_add(offset + count)));
+        }
+        Ok(start)
+    }
+
+    /// Stores bytes into raw memory as they are, or into heap cells as one `Char` each, so
+    /// what was read compares like any other string.
+    fn fill_buffer(&mut self, region: PointerType, start: usize, bytes: &[u8]) {
+        match region {
+            PointerType::RawPointer => self.memory[start..start + bytes.len()].copy_from_slice(bytes),
+            PointerType::StackValuesPointer => {
+                for (cell, byte) in self.heap[start..start + bytes.len()].iter_mut().zip(bytes) {
+                    *cell = StackValues::Char((*byte).into());
+                }
+            }
+        }
+    }
+
+    /// Reads bytes from raw memory as they are, or from heap cells holding characters,
+    /// which are written as UTF-8.
+    fn buffer_bytes(&self, region: PointerType, start: usize, count: usize) -> Result<Vec<u8>, VmError> {
+        match region {
+            PointerType::RawPointer => Ok(self.memory[start..start + count].to_vec()),
+            PointerType::StackValuesPointer => {
+                let mut text = String::new();
+                for cell in self.heap[start..start + count].iter() {
+                    let c = match *cell {
+                        StackValues::Char(c) => Some(c),
+                        StackValues::U16(v) => char::from_u32(v.into()),
+                        _ => None,
+                    };
+                    text.push(c.ok_or(VmError::TypeMismatch { expected: "char", found: *cell })?);
+                }
+                Ok(text.into_bytes())
+            }
+        }
+    }
+}
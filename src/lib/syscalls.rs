@@ -0,0 +1,121 @@
+use std::fmt;
+
+/// A CPU architecture whose Linux syscall numbers are known, so QASM can name syscalls
+/// (`SYSCALL write`) instead of hardcoding one architecture's numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
+
+/// A syscall as `SYSCALL name` compiles it.
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub number: u16,
+    /// How many arguments `SYSCALL` pops for it.
+    pub args: u8,
+}
+
+/// Name, argument count, x86_64 number, and the number on aarch64 and riscv64, which share
+/// the kernel's generic table. `None` where an architecture lacks the syscall.
+const SYSCALLS: &[(&str, u8, Option<u16>, Option<u16>)] = &[
+    ("read", 3, Some(0), Some(63)),
+    ("write", 3, Some(1), Some(64)),
+    ("open", 3, Some(2), None),
+    ("close", 1, Some(3), Some(57)),
+    ("fstat", 2, Some(5), Some(80)),
+    ("lseek", 3, Some(8), Some(62)),
+    ("mmap", 6, Some(9), Some(222)),
+    ("mprotect", 3, Some(10), Some(226)),
+    ("munmap", 2, Some(11), Some(215)),
+    ("brk", 1, Some(12), Some(214)),
+    ("ioctl", 3, Some(16), Some(29)),
+    ("dup", 1, Some(32), Some(23)),
+    ("nanosleep", 2, Some(35), Some(101)),
+    ("getpid", 0, Some(39), Some(172)),
+    ("socket", 3, Some(41), Some(198)),
+    ("connect", 3, Some(42), Some(203)),
+    ("clone", 5, Some(56), Some(220)),
+    ("fork", 0, Some(57), None),
+    ("execve", 3, Some(59), Some(221)),
+    ("exit", 1, Some(60), Some(93)),
+    ("wait4", 4, Some(61), Some(260)),
+    ("kill", 2, Some(62), Some(129)),
+    ("uname", 1, Some(63), Some(160)),
+    ("fsync", 1, Some(74), Some(82)),
+    ("getcwd", 2, Some(79), Some(17)),
+    ("chdir", 1, Some(80), Some(49)),
+    ("getuid", 0, Some(102), Some(174)),
+    ("gettid", 0, Some(186), Some(178)),
+    ("clock_gettime", 2, Some(228), Some(113)),
+    ("exit_group", 1, Some(231), Some(94)),
+    ("openat", 4, Some(257), Some(56)),
+    ("mkdirat", 3, Some(258), Some(34)),
+    ("unlinkat", 3, Some(263), Some(35)),
+    ("dup3", 3, Some(292), Some(24)),
+    ("pipe2", 2, Some(293), Some(59)),
+    ("getrandom", 3, Some(318), Some(278)),
+];
+
+impl Arch {
+    pub const ALL: [Arch; 3] = [Arch::X86_64, Arch::Aarch64, Arch::Riscv64];
+
+    /// The architecture this crate was built for, if its syscall numbers are known.
+    pub fn host() -> Option<Arch> {
+        if cfg!(target_arch = "x86_64") {
+            Some(Arch::X86_64)
+        } else if cfg!(target_arch = "aarch64") {
+            Some(Arch::Aarch64)
+        } else if cfg!(target_arch = "riscv64") {
+            Some(Arch::Riscv64)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+        }
+    }
+
+    /// Accepts the names used by `--target`, including the `amd64` and `arm64` aliases.
+    pub fn from_name(name: &str) -> Option<Arch> {
+        match name {
+            "x86_64" | "amd64" => Some(Arch::X86_64),
+            "aarch64" | "arm64" => Some(Arch::Aarch64),
+            "riscv64" => Some(Arch::Riscv64),
+            _ => None,
+        }
+    }
+
+    fn number(self, entry: &(&str, u8, Option<u16>, Option<u16>)) -> Option<u16> {
+        match self {
+            Arch::X86_64 => entry.2,
+            Arch::Aarch64 | Arch::Riscv64 => entry.3,
+        }
+    }
+
+    /// Looks up a syscall by name.
+    pub fn syscall(self, name: &str) -> Option<Syscall> {
+        let entry = SYSCALLS.iter().find(|entry| entry.0 == name)?;
+        Some(Syscall { number: self.number(entry)?, args: entry.1 })
+    }
+
+    /// The name of syscall `number`, if it is in the table.
+    pub fn syscall_name(self, number: u16) -> Option<&'static str> {
+        SYSCALLS
+            .iter()
+            .find(|entry| self.number(entry) == Some(number))
+            .map(|entry| entry.0)
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use proton::lib::bytecode::{DataItem, DebugEntry, Program, Symbol, SymbolKind};
+use proton::lib::machine_type::{CONSTANT_POOL_SIZE, DEFINE_ENTRY_PROLOGUE, ENTRY_PROLOGUE_LEN, Word};
+
+#[derive(Debug)]
+pub enum LinkError {
+    NotAnObject(String),
+    DuplicateSymbol { name: String, first: String, second: String },
+    UndefinedSymbol { name: String, object: String },
+    /// A code label used as a slot or the other way round.
+    KindMismatch { name: String, object: String },
+    /// A relocation pointing at an operand that doesn't exist or isn't a `U16`.
+    BadRelocation { object: String, instruction: u16 },
+    TooManySlots(usize),
+    TooManyInstructions(usize),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAnObject(object) => write!(f, "{} is not an object file; assemble it with `assembler -c`", object),
+            Self::DuplicateSymbol { name, first, second } => {
+                write!(f, "duplicate symbol `{}`, defined in {} and {}", name, first, second)
+            }
+            Self::UndefinedSymbol { name, object } => write!(f, "undefined symbol `{}`, referenced from {}", name, object),
+            Self::KindMismatch { name, object } => {
+                write!(f, "{} uses `{}` as the wrong kind of symbol (a label where a slot is expected or vice versa)", object, name)
+            }
+            Self::BadRelocation { object, instruction } => {
+                write!(f, "{}: relocation for instruction {} does not match its operands", object, instruction)
+            }
+            Self::TooManySlots(count) => write!(f, "the objects use {} constant pool slots, more than the {} available", count, CONSTANT_POOL_SIZE),
+            Self::TooManyInstructions(count) => write!(f, "the linked program has {} instructions, more than a u16 can address", count),
+        }
+    }
+}
+
+/// An object file as given on the command line.
+pub struct Object {
+    pub name: String,
+    pub program: Program,
+}
+
+/// Where an object's code and constant pool slots end up in the linked program.
+struct Placement {
+    code: u16,
+    slots: u16,
+}
+
+/// Combines object files into one executable program.
+///
+/// Objects are laid out in the order given. If one of them exports `main`, `CALL main; RET`
+/// is placed in front, as the assembler does for a single file.
+pub fn link(objects: &[Object]) -> Result<Program, Vec<LinkError>> {
+    let mut errors = Vec::new();
+    for object in objects.iter().filter(|object| object.program.link.is_none()) {
+        errors.push(LinkError::NotAnObject(object.name.clone()));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let has_main = objects
+        .iter()
+        .flat_map(|object| object.program.link.iter().flat_map(|link| link.exports.iter()))
+        .any(|export| export.name == "main" && export.kind == SymbolKind::Code);
+    let mut placements = Vec::with_capacity(objects.len());
+    let (mut code, mut slots) = (if has_main { ENTRY_PROLOGUE_LEN } else { 0 }, 0usize);
+    for object in objects {
+        placements.push(Placement { code: code as u16, slots: slots as u16 });
+        code += object.program.instructions.len();
+        slots += object.program.link.as_ref().map_or(0, |link| link.slots as usize);
+    }
+    if code > u16::MAX as usize {
+        return Err(vec![LinkError::TooManyInstructions(code)]);
+    }
+    if slots > CONSTANT_POOL_SIZE {
+        return Err(vec![LinkError::TooManySlots(slots)]);
+    }
+
+    // Every exported symbol with its final value and the object defining it.
+    let mut globals: HashMap<&str, (SymbolKind, u16, &str)> = HashMap::new();
+    for (object, placement) in objects.iter().zip(placements.iter()) {
+        for export in object.program.link.iter().flat_map(|link| link.exports.iter()) {
+            let base = match export.kind {
+                SymbolKind::Code => placement.code,
+                SymbolKind::Slot => placement.slots,
+            };
+            if let Some((_, _, first)) = globals.get(export.name.as_str()) {
+                errors.push(LinkError::DuplicateSymbol {
+                    name: export.name.clone(),
+                    first: first.to_string(),
+                    second: object.name.clone(),
+                });
+                continue;
+            }
+            globals.insert(&export.name, (export.kind, base + export.value, &object.name));
+        }
+    }
+
+    let mut program = Program::default();
+    if let Some((_, main, _)) = globals.get("main") {
+        program.instructions.extend(DEFINE_ENTRY_PROLOGUE(*main));
+    }
+    for (object, placement) in objects.iter().zip(placements.iter()) {
+        let mut instructions = object.program.instructions.clone();
+        for relocation in object.program.link.iter().flat_map(|link| link.relocations.iter()) {
+            let value = match &relocation.symbol {
+                None => match relocation.kind {
+                    SymbolKind::Code => placement.code,
+                    SymbolKind::Slot => placement.slots,
+                },
+                Some(name) => match globals.get(name.as_str()) {
+                    Some((kind, value, _)) if *kind == relocation.kind => *value,
+                    Some(_) => {
+                        errors.push(LinkError::KindMismatch { name: name.clone(), object: object.name.clone() });
+                        continue;
+                    }
+                    None => {
+                        errors.push(LinkError::UndefinedSymbol { name: name.clone(), object: object.name.clone() });
+                        continue;
+                    }
+                },
+            };
+            let operand = instructions
+                .get_mut(relocation.instruction as usize)
+                .and_then(|instruction| instruction.values.as_mut())
+                .and_then(|values| values.get_mut(relocation.operand as usize));
+            match operand {
+                // Local operands hold an index relative to the object; imports hold zero.
+                Some(Word::U16(operand)) => *operand = operand.wrapping_add(value),
+                _ => errors.push(LinkError::BadRelocation { object: object.name.clone(), instruction: relocation.instruction }),
+            }
+        }
+        program.instructions.extend(instructions);
+
+        for item in object.program.data.iter() {
+            // An item past the slots its object declares would escape the check above.
+            let slot = item.slot as usize + placement.slots as usize;
+            if slot >= CONSTANT_POOL_SIZE {
+                errors.push(LinkError::TooManySlots(slot + 1));
+                continue;
+            }
+            program.data.push(DataItem { slot: slot as u16, ..item.clone() });
+        }
+        program.symbols.extend(object.program.symbols.iter().map(|symbol| Symbol {
+            name: symbol.name.clone(),
+            index: symbol.index + placement.code,
+        }));
+        let files = program.debug_info.files.len() as u16;
+        program.debug_info.entries.extend(object.program.debug_info.entries.iter().map(|entry| DebugEntry {
+            instruction: entry.instruction + placement.code,
+            file: entry.file + files,
+            ..entry.clone()
+        }));
+        program.debug_info.files.extend(object.program.debug_info.files.iter().cloned());
+    }
+    program.symbols.sort_by_key(|symbol| symbol.index);
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proton::lib::bytecode::{DataInit, DataItem, Export, LinkInfo, Program, Relocation, SymbolKind};
+    use proton::lib::machine_type::{DEFINE_CALL, DEFINE_JMP, DEFINE_LOAD, DEFINE_PUSH, DEFINE_RET, DEFINE_STORE, Instruction, Word};
+    use super::{Object, link};
+
+    fn relocation(instruction: u16, kind: SymbolKind, symbol: Option<&str>) -> Relocation {
+        Relocation { instruction, operand: 0, kind, symbol: symbol.map(str::to_string) }
+    }
+
+    fn export(name: &str, kind: SymbolKind, value: u16) -> Export {
+        Export { name: name.to_string(), kind, value }
+    }
+
+    /// The instruction as the disassembler would write it without labels, e.g. `CALL 6`.
+    fn text(instruction: &Instruction) -> String {
+        match instruction.values.as_deref() {
+            Some([Word::U16(value)]) => format!("{} {}", instruction.tt.mnemonic(), value),
+            None => instruction.tt.mnemonic(),
+            _ => panic!("unexpected operands on {:?}", instruction),
+        }
+    }
+
+    #[test]
+    fn calls_and_slots_are_relocated_across_objects() {
+        // main: STORE local; CALL inc; LOAD counter; RET
+        let first = Program {
+            link: Some(LinkInfo {
+                slots: 1,
+                exports: vec![export("main", SymbolKind::Code, 0)],
+                relocations: vec![
+                    relocation(0, SymbolKind::Slot, None),
+                    relocation(1, SymbolKind::Code, Some("inc")),
+                    relocation(2, SymbolKind::Slot, Some("counter")),
+                ],
+            }),
+            ..Program::new(vec![DEFINE_STORE(0), DEFINE_CALL(0), DEFINE_LOAD(Some(0)), DEFINE_RET()])
+        };
+        // inc: PUSH 1; JMP done; done: LOAD counter; RET
+        let second = Program {
+            data: vec![DataItem { name: "counter".to_string(), slot: 0, init: DataInit::Zeroed(1) }],
+            link: Some(LinkInfo {
+                slots: 1,
+                exports: vec![export("inc", SymbolKind::Code, 0), export("counter", SymbolKind::Slot, 0)],
+                relocations: vec![relocation(1, SymbolKind::Code, None), relocation(2, SymbolKind::Slot, None)],
+            }),
+            ..Program::new(vec![DEFINE_PUSH(1), DEFINE_JMP(2), DEFINE_LOAD(Some(0)), DEFINE_RET()])
+        };
+        let objects = [
+            Object { name: "first.o".to_string(), program: first },
+            Object { name: "second.o".to_string(), program: second },
+        ];
+        let program = link(&objects).expect("links");
+
+        // The entry prologue takes two instructions, so `first` starts at 2 and `second` at 6.
+        assert_eq!(text(&program.instructions[0]), "CALL 2");
+        assert_eq!(text(&program.instructions[1]), "RET");
+        assert_eq!(text(&program.instructions[2]), "STORE 0");
+        assert_eq!(text(&program.instructions[3]), "CALL 6");
+        assert_eq!(text(&program.instructions[4]), "LOAD 1");
+        assert_eq!(text(&program.instructions[7]), "JMP 8");
+        assert_eq!(text(&program.instructions[8]), "LOAD 1");
+        assert_eq!(program.data[0].slot, 1);
+    }
+}
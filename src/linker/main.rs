@@ -0,0 +1,47 @@
+#![allow(clippy::module_inception)]
+
+use std::env;
+use std::process;
+
+use proton::lib::bytecode::ByteCodeCompiler;
+
+use crate::linker::{Object, link};
+mod linker;
+
+const USAGE: &str = "Usage: linker <output_file> <object_file>...";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    let output = &args[1];
+
+    let mut objects = Vec::new();
+    for name in args[2..].iter() {
+        match ByteCodeCompiler::new(name).load_file() {
+            Ok(program) => objects.push(Object { name: name.clone(), program }),
+            Err(e) => {
+                eprintln!("Failed to load object file {}: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    match link(&objects) {
+        Ok(program) => {
+            if let Err(e) = ByteCodeCompiler::new(output).store_file(&program) {
+                eprintln!("QUARMVM: Error while writing the bytecode file {}: {}", output, e);
+                process::exit(1);
+            }
+        }
+        Err(errors) => {
+            for error in errors.iter() {
+                eprintln!("error: {}", error);
+            }
+            eprintln!("QUARMVM: {} error(s), no bytecode written", errors.len());
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use proton::lib::machine_type::{QuarkVM, RunStatus, StackValues, VmFault};
+
+const HELP: &str = "\
+commands:
+  break <index|label>     set a breakpoint (alias: b)
+  delete <index|label>    remove a breakpoint (alias: d)
+  breakpoints             list breakpoints
+  step                    execute one instruction (alias: s)
+  next                    step over CALL instructions (alias: n)
+  finish                  run until the current function returns (alias: out)
+  continue                run until a breakpoint or the end (alias: c)
+  where                   show the current instruction and its neighbours (alias: w)
+  stack                   show every stack slot
+  set <slot> <value>      overwrite a stack slot, value is `5`, `-5` or `<type>:<value>`
+                          with type u16, i16, u32, i32, f16, char or bool
+  pool <index> [count]    show constant pool entries
+  heap <start> [count]    show heap cells
+  mem <start> [count]     show raw memory bytes
+  allocs                  show live allocations
+  gc                      collect unreachable heap blocks now
+  calls                   show the call stack
+  frame                   show the current frame's arguments and locals
+  quit                    stop debugging (alias: q)";
+
+/// Why execution stopped after a `continue`, `next` or `finish`.
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Halted,
+    /// `--fuel` or `--time-limit` ran out before the next instruction.
+    Limit(RunStatus),
+    Fault(VmFault),
+}
+
+pub struct Debugger<'a> {
+    vm: &'a mut QuarkVM,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut QuarkVM) -> Self {
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, out: &mut W) -> io::Result<()> {
+        writeln!(out, "QUARKVM debugger, {} instructions. Type `help` for commands.", self.vm.instructions.len())?;
+        self.show_location(out)?;
+        loop {
+            write!(out, "(qdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let Some((command, args)) = parts.split_first() else {
+                continue;
+            };
+            match *command {
+                "help" | "h" | "?" => writeln!(out, "{}", HELP)?,
+                "break" | "b" => match args.first().and_then(|a| self.resolve_location(a)) {
+                    Some(index) => {
+                        self.breakpoints.insert(index);
+                        writeln!(out, "breakpoint at {}", self.describe_location(index))?;
+                    }
+                    None => writeln!(out, "usage: break <index|label>")?,
+                },
+                "delete" | "d" => match args.first().and_then(|a| self.resolve_location(a)) {
+                    Some(index) if self.breakpoints.remove(&index) => {
+                        writeln!(out, "removed breakpoint at {}", self.describe_location(index))?
+                    }
+                    Some(index) => writeln!(out, "no breakpoint at {}", index)?,
+                    None => writeln!(out, "usage: delete <index|label>")?,
+                },
+                "breakpoints" => {
+                    for index in self.breakpoints.iter() {
+                        writeln!(out, "  {}", self.describe_location(*index))?;
+                    }
+                }
+                "step" | "s" => {
+                    let stop = self.step();
+                    self.report(stop, out)?;
+                }
+                "next" | "n" => {
+                    let depth = self.vm.call_stack.len();
+                    let stop = self.run_while(|vm| vm.call_stack.len() > depth);
+                    self.report(stop, out)?;
+                }
+                "finish" | "out" => {
+                    let depth = self.vm.call_stack.len();
+                    let stop = if depth == 0 {
+                        self.run_while(|_| true)
+                    } else {
+                        self.run_while(|vm| vm.call_stack.len() >= depth)
+                    };
+                    self.report(stop, out)?;
+                }
+                "continue" | "c" => {
+                    let stop = self.run_while(|_| true);
+                    self.report(stop, out)?;
+                }
+                "where" | "w" => self.show_location(out)?,
+                "stack" => self.show_stack(out)?,
+                "set" => self.set_stack_slot(args, out)?,
+                "pool" => self.show_pool(args, out)?,
+                "heap" => self.show_heap(args, out)?,
+                "mem" => self.show_memory(args, out)?,
+                "allocs" => self.show_allocations(out)?,
+                "gc" => {
+                    let freed = self.vm.collect_garbage();
+                    writeln!(
+                        out,
+                        "freed {} blocks, {} heap cells live",
+                        freed,
+                        self.vm.live_heap_cells()
+                    )?;
+                }
+                "calls" => {
+                    for (depth, frame) in self.vm.call_stack.iter().enumerate().rev() {
+                        writeln!(
+                            out,
+                            "  #{} returns to {}, {} args, {} locals",
+                            depth,
+                            self.describe_location(frame.return_pc),
+                            frame.args,
+                            frame.locals
+                        )?;
+                    }
+                }
+                "frame" => self.show_frame(out)?,
+                "quit" | "q" => return Ok(()),
+                other => writeln!(out, "unknown command `{}`, type `help`", other)?,
+            }
+        }
+    }
+
+    fn is_halted(&self) -> bool {
+        !self.vm.running || self.vm.pc as usize >= self.vm.instructions.len()
+    }
+
+    fn step(&mut self) -> Stop {
+        if self.is_halted() {
+            return Stop::Halted;
+        }
+        match self.vm.step() {
+            Ok(Some(status)) => return Stop::Limit(status),
+            Ok(None) => {}
+            Err(fault) => return Stop::Fault(fault),
+        }
+        if self.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::Stepped
+        }
+    }
+
+    /// Steps once, then keeps stepping while `keep_going` holds, stopping early on breakpoints.
+    fn run_while(&mut self, keep_going: impl Fn(&QuarkVM) -> bool) -> Stop {
+        let mut stop = self.step();
+        while let Stop::Stepped = stop {
+            if self.breakpoints.contains(&self.vm.pc) {
+                return Stop::Breakpoint;
+            }
+            if !keep_going(self.vm) {
+                break;
+            }
+            stop = self.step();
+        }
+        stop
+    }
+
+    fn report<W: Write>(&self, stop: Stop, out: &mut W) -> io::Result<()> {
+        match stop {
+            Stop::Stepped => self.show_location(out),
+            Stop::Breakpoint => {
+                writeln!(out, "hit breakpoint")?;
+                self.show_location(out)
+            }
+            Stop::Halted => writeln!(out, "program finished"),
+            Stop::Limit(RunStatus::OutOfFuel) => {
+                writeln!(out, "out of fuel after {} instructions", self.vm.run_stats.instructions)
+            }
+            Stop::Limit(_) => writeln!(out, "time limit exceeded after {} instructions", self.vm.run_stats.instructions),
+            Stop::Fault(fault) => writeln!(out, "{}", fault),
+        }
+    }
+
+    fn resolve_location(&self, arg: &str) -> Option<u16> {
+        arg.parse::<u16>().ok().or_else(|| {
+            self.vm
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == arg)
+                .map(|symbol| symbol.index)
+        })
+    }
+
+    /// `index (label+offset)`, using the closest label at or before `index`.
+    fn describe_location(&self, index: u16) -> String {
+        let label = self
+            .vm
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.index <= index)
+            .max_by_key(|symbol| symbol.index);
+        match label {
+            Some(symbol) if symbol.index == index => format!("{} ({})", index, symbol.name),
+            Some(symbol) => format!("{} ({}+{})", index, symbol.name, index - symbol.index),
+            None => index.to_string(),
+        }
+    }
+
+    fn source_location(&self, index: u16) -> Option<String> {
+        let entry = self
+            .vm
+            .debug_info
+            .entries
+            .iter()
+            .find(|entry| entry.instruction == index)?;
+        let file = self
+            .vm
+            .debug_info
+            .files
+            .get(entry.file as usize)
+            .map(String::as_str)
+            .unwrap_or("?");
+        Some(format!("{}:{}:{}", file, entry.line, entry.column))
+    }
+
+    fn show_location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.is_halted() {
+            return writeln!(out, "program finished");
+        }
+        let pc = self.vm.pc as usize;
+        let first = pc.saturating_sub(2);
+        let last = (pc + 3).min(self.vm.instructions.len());
+        for index in first..last {
+            let instruction = &self.vm.instructions[index];
+            let marker = if index == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&(index as u16)) { "*" } else { " " };
+            let operands = match &instruction.values {
+                Some(values) => format!("{:?}", values),
+                None => String::new(),
+            };
+            writeln!(
+                out,
+                "{}{} {:<20} {} {}",
+                marker,
+                breakpoint,
+                self.describe_location(index as u16),
+                instruction.tt.mnemonic(),
+                operands
+            )?;
+        }
+        if let Some(source) = self.source_location(self.vm.pc) {
+            writeln!(out, "   at {}", source)?;
+        }
+        Ok(())
+    }
+
+    fn show_stack<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.vm.sp < 0 {
+            return writeln!(out, "  <empty>");
+        }
+        for slot in (0..=self.vm.sp as usize).rev() {
+            let top = if slot == self.vm.sp as usize { " <- top" } else { "" };
+            writeln!(out, "  [{}] {:?}{}", slot, self.vm.stack[slot], top)?;
+        }
+        Ok(())
+    }
+
+    fn parse_value(text: &str) -> Option<StackValues> {
+        match text.split_once(':') {
+            Some(("u16", v)) => v.parse().ok().map(StackValues::U16),
+            Some(("i16", v)) => v.parse().ok().map(StackValues::I16),
+            Some(("u32", v)) => v.parse().ok().map(StackValues::U32),
+            Some(("i32", v)) => v.parse().ok().map(StackValues::I32),
+            Some(("f16", v)) => v.parse().ok().map(StackValues::F16),
+            Some(("char", v)) => v.parse().ok().map(StackValues::Char),
+            Some(("bool", v)) => v.parse().ok().map(StackValues::Bool),
+            Some(_) => None,
+            None if text.starts_with('-') => text.parse().ok().map(StackValues::I16),
+            None => text.parse().ok().map(StackValues::U16),
+        }
+    }
+
+    fn set_stack_slot<W: Write>(&mut self, args: &[&str], out: &mut W) -> io::Result<()> {
+        let (Some(slot), Some(value)) = (
+            args.first().and_then(|a| a.parse::<usize>().ok()),
+            args.get(1).and_then(|a| Self::parse_value(a)),
+        ) else {
+            return writeln!(out, "usage: set <slot> <value>");
+        };
+        if self.vm.sp < 0 || slot > self.vm.sp as usize {
+            return writeln!(out, "slot {} is above the top of the stack", slot);
+        }
+        self.vm.stack[slot] = value;
+        writeln!(out, "  [{}] {:?}", slot, value)
+    }
+
+    fn range_args(args: &[&str], default_count: usize) -> Option<(usize, usize)> {
+        let start = args.first()?.parse().ok()?;
+        let count = match args.get(1) {
+            Some(count) => count.parse().ok()?,
+            None => default_count,
+        };
+        Some((start, count))
+    }
+
+    fn show_pool<W: Write>(&self, args: &[&str], out: &mut W) -> io::Result<()> {
+        let Some((start, count)) = Self::range_args(args, 1) else {
+            return writeln!(out, "usage: pool <index> [count]");
+        };
+        for (index, value) in self.vm.constant_pools.iter().enumerate().skip(start).take(count) {
+            writeln!(out, "  pool[{}] {:?}", index, value)?;
+        }
+        Ok(())
+    }
+
+    fn show_heap<W: Write>(&self, args: &[&str], out: &mut W) -> io::Result<()> {
+        let Some((start, count)) = Self::range_args(args, 8) else {
+            return writeln!(out, "usage: heap <start> [count]");
+        };
+        for (index, value) in self.vm.heap.iter().enumerate().skip(start).take(count) {
+            writeln!(out, "  heap[{}] {:?}", index, value)?;
+        }
+        Ok(())
+    }
+
+    fn show_memory<W: Write>(&self, args: &[&str], out: &mut W) -> io::Result<()> {
+        let Some((start, count)) = Self::range_args(args, 16) else {
+            return writeln!(out, "usage: mem <start> [count]");
+        };
+        let bytes: Vec<String> = self
+            .vm
+            .memory
+            .iter()
+            .skip(start)
+            .take(count)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(out, "  mem[{}..] {}", start, bytes.join(" "))
+    }
+
+    fn show_frame<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let Some(frame) = self.vm.call_stack.last() else {
+            return writeln!(out, "  <no frame>");
+        };
+        let slots = &self.vm.frame_slots[frame.fp..];
+        for (index, value) in slots.iter().take(frame.args as usize).enumerate() {
+            writeln!(out, "  arg[{}] {:?}", index, value)?;
+        }
+        for (index, value) in slots.iter().skip(frame.args as usize).enumerate() {
+            writeln!(out, "  local[{}] {:?}", index, value)?;
+        }
+        Ok(())
+    }
+
+    fn show_allocations<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut allocations: Vec<_> = self.vm.allocated_memory.iter().collect();
+        allocations.sort_by_key(|(handle, _)| **handle);
+        for (handle, (size, pointer_type)) in allocations {
+            writeln!(out, "  {:?} @ {} size {}", pointer_type, handle.offset, size)?;
+        }
+        Ok(())
+    }
+}
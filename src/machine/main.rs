@@ -1,21 +1,183 @@
 use std::env;
+use std::io;
 use std::process;
+use std::time::{Duration, Instant};
 
 use proton::lib::bytecode::ByteCodeCompiler;
-use proton::lib::machine_type::QuarkVM;
+use proton::lib::machine_type::{QuarkVM, RunStatus};
+use proton::lib::sandbox::SyscallPolicy;
+use proton::lib::syscalls::Arch;
 
+use crate::debugger::Debugger;
+mod debugger;
+
+const USAGE: &str = "Usage: machine [--debug] [--gc] [--gc-threshold <cells>] [--gc-stats]\n               [--stack-size <values>] [--call-depth <frames>]\n               [--fuel <instructions>] [--time-limit <ms>] [--stats]\n               [--no-syscalls | --allow-syscalls <list> | --deny-syscalls <list>] [--syscall-log]\n               <input_file>";
+
+struct Options {
+    debug: bool,
+    gc: bool,
+    gc_threshold: Option<usize>,
+    gc_stats: bool,
+    stack_size: Option<usize>,
+    call_depth: Option<usize>,
+    fuel: Option<u64>,
+    time_limit: Option<u64>,
+    stats: bool,
+    syscall_policy: Option<SyscallPolicy>,
+    syscall_log: bool,
+    input_file: String,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        debug: false,
+        gc: false,
+        gc_threshold: None,
+        gc_stats: false,
+        stack_size: None,
+        call_depth: None,
+        fuel: None,
+        time_limit: None,
+        stats: false,
+        syscall_policy: None,
+        syscall_log: false,
+        input_file: String::new(),
+    };
+    let mut input_file = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => options.debug = true,
+            "--gc" => options.gc = true,
+            "--gc-threshold" => options.gc_threshold = Some(args.next()?.parse().ok()?),
+            "--gc-stats" => options.gc_stats = true,
+            "--stack-size" => options.stack_size = Some(args.next()?.parse().ok()?),
+            "--call-depth" => options.call_depth = Some(args.next()?.parse().ok()?),
+            "--fuel" => options.fuel = Some(args.next()?.parse().ok()?),
+            "--time-limit" => options.time_limit = Some(args.next()?.parse().ok()?),
+            "--stats" => options.stats = true,
+            "--no-syscalls" => options.syscall_policy = Some(SyscallPolicy::deny_all()),
+            "--allow-syscalls" => options.syscall_policy = Some(SyscallPolicy::allow(parse_numbers(args.next()?)?)),
+            "--deny-syscalls" => options.syscall_policy = Some(SyscallPolicy::deny(parse_numbers(args.next()?)?)),
+            "--syscall-log" => options.syscall_log = true,
+            flag if flag.starts_with("--") => return None,
+            file if input_file.is_none() => input_file = Some(file.to_string()),
+            _ => return None,
+        }
+    }
+    options.input_file = input_file?;
+    Some(options)
+}
+
+/// Parses a comma separated list of syscalls such as `0,1,60` or `read,write,exit`.
+/// Names are looked up for the architecture the machine runs on.
+fn parse_numbers(list: &str) -> Option<Vec<u16>> {
+    list.split(',')
+        .map(|entry| {
+            let entry = entry.trim();
+            entry
+                .parse()
+                .ok()
+                .or_else(|| Some(Arch::host()?.syscall(entry)?.number))
+        })
+        .collect()
+}
+
+fn print_gc_stats(vm: &QuarkVM) {
+    let stats = &vm.gc_stats;
+    eprintln!(
+        "GC: {} collections, {} blocks ({} cells) reclaimed, {} of {} heap cells live",
+        stats.collections,
+        stats.blocks_freed,
+        stats.cells_freed,
+        vm.live_heap_cells(),
+        vm.heap.len()
+    );
+}
+
+fn print_syscall_log(vm: &QuarkVM) {
+    for record in vm.syscall_log.iter() {
+        eprintln!("SYSCALL: {}", record);
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: machine <input_file>");
+    let Some(options) = parse_args(&args) else {
+        eprintln!("{}", USAGE);
         process::exit(1);
-    }
-
-    let input_file = &args[1];
+    };
+    let input_file = &options.input_file;
 
     let mut quark_machine = QuarkVM::new(ByteCodeCompiler::new(input_file));
-    quark_machine.load_file();
-    quark_machine.run();
+    quark_machine.gc.enabled = options.gc || options.gc_threshold.is_some();
+    if let Some(threshold) = options.gc_threshold {
+        quark_machine.gc.threshold = threshold;
+    }
+    if let Some(stack_size) = options.stack_size {
+        quark_machine.limits.stack_size = stack_size;
+    }
+    if let Some(call_depth) = options.call_depth {
+        quark_machine.limits.call_depth = call_depth;
+    }
+    if let Err(e) = quark_machine.load_file() {
+        eprintln!("Failed to load bytecode file {}: {}", input_file, e);
+        process::exit(1);
+    }
+    quark_machine.fuel = options.fuel;
+    if let Some(ms) = options.time_limit {
+        quark_machine.limits.deadline = Some(Instant::now() + Duration::from_millis(ms));
+    }
+    if let Some(policy) = options.syscall_policy {
+        quark_machine.syscall_policy = policy;
+    }
+    quark_machine.syscall_policy.log_allowed = options.syscall_log;
+
+    // The debugger reports how the program stopped itself; only `run` leaves a result here.
+    let result = if options.debug {
+        let mut debugger = Debugger::new(&mut quark_machine);
+        if let Err(e) = debugger.repl(io::stdin().lock(), &mut io::stdout()) {
+            eprintln!("Debugger I/O error: {}", e);
+            process::exit(1);
+        }
+        None
+    } else {
+        Some(quark_machine.run())
+    };
+    if options.gc_stats {
+        print_gc_stats(&quark_machine);
+    }
+    if options.syscall_log {
+        print_syscall_log(&quark_machine);
+    }
+    if options.stats {
+        eprintln!("Executed {} instructions", quark_machine.run_stats.instructions);
+    }
+    let Some(result) = result else {
+        if let Some(code) = quark_machine.exit_code {
+            process::exit(code);
+        }
+        return;
+    };
+    let executed = quark_machine.run_stats.instructions;
+    match result {
+        Ok(RunStatus::Halted) => {
+            if let Some(code) = quark_machine.exit_code {
+                process::exit(code);
+            }
+        }
+        Ok(RunStatus::OutOfFuel) => {
+            eprintln!("QUARKVM: out of fuel after {} instructions, stopped at pc {}", executed, quark_machine.pc);
+            process::exit(1);
+        }
+        Ok(RunStatus::DeadlineExceeded) => {
+            eprintln!("QUARKVM: time limit exceeded after {} instructions, stopped at pc {}", executed, quark_machine.pc);
+            process::exit(1);
+        }
+        Err(fault) => {
+            eprintln!("{}", fault);
+            process::exit(1);
+        }
+    }
 }